@@ -0,0 +1,18 @@
+-- users.status is read as a UserStatus enum, so it may no longer be NULL or free-form
+UPDATE users SET status = 'active' WHERE status IS NULL;
+ALTER TABLE users ALTER COLUMN status SET NOT NULL;
+ALTER TABLE users ADD CONSTRAINT users_status_check
+  CHECK (status IN ('active', 'pending_verification', 'suspended', 'banned', 'deactivated'));
+
+-- Every status change made by an admin, with the reason given
+CREATE TABLE IF NOT EXISTS user_status_changes (
+  id BIGSERIAL PRIMARY KEY,
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  old_status TEXT NOT NULL,
+  new_status TEXT NOT NULL,
+  reason TEXT NOT NULL,
+  changed_by BIGINT REFERENCES users(id) ON DELETE SET NULL,
+  changed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_status_changes_user_id ON user_status_changes(user_id);
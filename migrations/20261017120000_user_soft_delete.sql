@@ -0,0 +1,2 @@
+-- Set when a user deletes their own account. The row is kept, but its personal data is anonymised.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP WITH TIME ZONE;
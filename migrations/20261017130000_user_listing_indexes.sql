@@ -0,0 +1,4 @@
+-- Keyset pagination and filters of the admin user listing
+CREATE INDEX IF NOT EXISTS idx_users_created_at_id ON users (created_at, id);
+CREATE INDEX IF NOT EXISTS idx_users_status ON users (status);
+CREATE INDEX IF NOT EXISTS idx_users_last_login ON users (last_login);
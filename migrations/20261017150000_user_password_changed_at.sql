@@ -0,0 +1,2 @@
+-- Access tokens issued before this time are rejected, so changing or resetting the password ends existing sessions
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_changed_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,5 @@
+-- Consecutive failed logins per account, used for backoff delays and temporary lockouts.
+-- Counts older than `lockout.max_lockout_seconds` start over at the next failure.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS failed_login_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_failed_login_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS locked_until TIMESTAMP WITH TIME ZONE;
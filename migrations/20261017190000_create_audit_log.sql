@@ -0,0 +1,17 @@
+-- Who did what: logins, registrations, password, status and permission changes.
+-- Entries outlive the users they mention, so the user columns aren't foreign keys.
+CREATE TABLE IF NOT EXISTS audit_log (
+  id BIGSERIAL PRIMARY KEY,
+  occurred_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+  action TEXT NOT NULL,
+  actor_id BIGINT,
+  target_id BIGINT,
+  ip TEXT,
+  user_agent TEXT,
+  request_id TEXT,
+  details JSONB NOT NULL DEFAULT 'null'::jsonb
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_log_occurred_at ON audit_log(occurred_at);
+CREATE INDEX IF NOT EXISTS idx_audit_log_actor_id ON audit_log(actor_id, id);
+CREATE INDEX IF NOT EXISTS idx_audit_log_action ON audit_log(action, id);
@@ -0,0 +1,29 @@
+-- Accounts at external OpenID Connect providers, each linked to one user.
+-- The issuer and subject together identify the external account.
+CREATE TABLE IF NOT EXISTS user_identities (
+  id BIGSERIAL PRIMARY KEY,
+  user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  provider TEXT NOT NULL,
+  issuer TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  email TEXT,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+  UNIQUE (issuer, subject)
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_identities_user_id ON user_identities(user_id);
+
+-- Authorization requests waiting for the user to come back from the provider. Only the
+-- SHA-256 hash of the `state` parameter is stored.
+CREATE TABLE IF NOT EXISTS oidc_login_states (
+  state_hash TEXT PRIMARY KEY,
+  provider TEXT NOT NULL,
+  code_verifier TEXT NOT NULL,
+  nonce TEXT NOT NULL,
+  -- Set when a signed-in user links a provider instead of logging in with it
+  link_user_id BIGINT REFERENCES users(id) ON DELETE CASCADE,
+  expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Accounts created through an external provider have no password
+ALTER TABLE users ALTER COLUMN password DROP NOT NULL;
@@ -0,0 +1,2 @@
+-- Admin who made the request while impersonating the actor, if any
+ALTER TABLE audit_log ADD COLUMN IF NOT EXISTS impersonator_id BIGINT;
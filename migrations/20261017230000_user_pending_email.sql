@@ -0,0 +1,2 @@
+-- A changed email address is kept here until the link sent to it has been opened
+ALTER TABLE users ADD COLUMN IF NOT EXISTS pending_email TEXT;
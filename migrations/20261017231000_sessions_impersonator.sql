@@ -0,0 +1,3 @@
+-- Sessions started by an admin impersonating the user. Revoking the user's sessions also
+-- ends them, but they are not listed to the user as their own logins.
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS impersonator_id BIGINT REFERENCES users(id) ON DELETE CASCADE;
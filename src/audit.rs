@@ -0,0 +1,245 @@
+use actix_web::dev::Payload;
+use actix_web::http::header::USER_AGENT;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use futures::future::{ready, Ready};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::auth::Credential;
+use crate::db;
+use crate::error::AppError;
+use crate::middleware::request_id::RequestId;
+
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+// Security relevant events recorded in `audit_log`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Register,
+    LoginSuccess,
+    LoginFailure,
+    TokenRefresh,
+    PasswordChange,
+    PasswordReset,
+    EmailChange,
+    StatusChange,
+    PermissionChange,
+    AccountUnlock,
+    IdentityLink,
+    SessionRevoke,
+    Impersonate,
+    AccountDelete,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Register => "register",
+            AuditAction::LoginSuccess => "login_success",
+            AuditAction::LoginFailure => "login_failure",
+            AuditAction::TokenRefresh => "token_refresh",
+            AuditAction::PasswordChange => "password_change",
+            AuditAction::PasswordReset => "password_reset",
+            AuditAction::EmailChange => "email_change",
+            AuditAction::StatusChange => "status_change",
+            AuditAction::PermissionChange => "permission_change",
+            AuditAction::AccountUnlock => "account_unlock",
+            AuditAction::IdentityLink => "identity_link",
+            AuditAction::SessionRevoke => "session_revoke",
+            AuditAction::Impersonate => "impersonate",
+            AuditAction::AccountDelete => "account_delete",
+        }
+    }
+}
+
+// What happened: the action, the user who did it, the user it was done to and any
+// action specific details. Failed logins of unknown users have neither actor nor target.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub action: AuditAction,
+    pub actor_id: Option<i64>,
+    pub target_id: Option<i64>,
+    pub details: Value,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction) -> Self {
+        AuditEvent {
+            action,
+            actor_id: None,
+            target_id: None,
+            details: Value::Null,
+        }
+    }
+
+    pub fn actor(mut self, user_id: i64) -> Self {
+        self.actor_id = Some(user_id);
+        self
+    }
+
+    pub fn target(mut self, user_id: i64) -> Self {
+        self.target_id = Some(user_id);
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+// Where a request came from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditContext {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    // Admin acting as the user through an impersonation token
+    pub impersonator_id: Option<i64>,
+}
+
+impl AuditContext {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        AuditContext {
+            ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|agent| agent.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+            request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+            impersonator_id: req.extensions().get::<Credential>().and_then(Credential::impersonator),
+        }
+    }
+}
+
+// A stored audit log entry
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditLogEntry {
+    pub id: i64,
+    pub occurred_at: DateTime<Utc>,
+    pub action: String,
+    pub actor_id: Option<i64>,
+    pub target_id: Option<i64>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    pub impersonator_id: Option<i64>,
+    pub details: Value,
+}
+
+// Writes audit events to the database, registered as app data
+pub struct AuditLogger {
+    pool: Pool,
+}
+
+impl AuditLogger {
+    pub fn new(pool: Pool) -> Self {
+        AuditLogger { pool }
+    }
+
+    // Failures are logged but never fail the request being audited. The insert runs in its
+    // own task: handlers record events while holding a database connection, and waiting for
+    // a second one here could use up the pool and stall every request.
+    pub fn record(&self, context: &AuditContext, event: AuditEvent) {
+        let pool = self.pool.clone();
+        let context = context.clone();
+        tokio::spawn(async move {
+            let client = match pool.get().await {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("Failed to get database connection for audit event {}: {}", event.action.as_str(), e);
+                    return;
+                }
+            };
+            if let Err(e) = db::insert_audit_entry(&client, &context, &event).await {
+                error!("Failed to record audit event {}: {}", event.action.as_str(), e);
+            }
+        });
+    }
+}
+
+// Extractor for handlers that record audit events: the logger together with the
+// context of the current request
+pub struct Audit {
+    logger: web::Data<AuditLogger>,
+    context: AuditContext,
+}
+
+impl Audit {
+    pub fn context(&self) -> &AuditContext {
+        &self.context
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        self.logger.record(&self.context, event);
+    }
+}
+
+impl FromRequest for Audit {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let logger = req.app_data::<web::Data<AuditLogger>>().cloned().ok_or_else(|| {
+            error!("Audit logger is not registered as app data");
+            AppError::InternalServerError
+        });
+        ready(logger.map(|logger| Audit {
+            logger,
+            context: AuditContext::from_request(req),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    #[test]
+    fn test_action_names_match_serde() {
+        for action in [
+            AuditAction::Register,
+            AuditAction::LoginFailure,
+            AuditAction::PermissionChange,
+            AuditAction::AccountDelete,
+        ] {
+            assert_eq!(serde_json::to_value(action).unwrap(), json!(action.as_str()));
+        }
+    }
+
+    #[test]
+    fn test_event_builder() {
+        let event = AuditEvent::new(AuditAction::StatusChange)
+            .actor(1)
+            .target(2)
+            .details(json!({"status": "banned"}));
+        assert_eq!(event.actor_id, Some(1));
+        assert_eq!(event.target_id, Some(2));
+        assert_eq!(event.details["status"], "banned");
+    }
+
+    #[test]
+    fn test_context_from_request() {
+        let req = TestRequest::default()
+            .peer_addr("10.1.2.3:5000".parse().unwrap())
+            .insert_header((USER_AGENT, "curl/8.0"))
+            .to_http_request();
+        req.extensions_mut().insert(RequestId("req-1".to_string()));
+
+        assert_eq!(
+            AuditContext::from_request(&req),
+            AuditContext {
+                ip: Some("10.1.2.3".to_string()),
+                user_agent: Some("curl/8.0".to_string()),
+                request_id: Some("req-1".to_string()),
+                impersonator_id: None,
+            }
+        );
+    }
+}
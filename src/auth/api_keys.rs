@@ -0,0 +1,121 @@
+use chrono::{DateTime, Utc};
+use rand::Rng;
+
+use super::{refresh, Permission};
+
+// Keys look like `ak_<prefix>_<secret>`. The prefix is stored in clear to find the key and to show
+// it in listings; the secret only exists as a SHA-256 hash, like refresh tokens.
+const KEY_MARKER: &str = "ak_";
+const PREFIX_LENGTH: usize = 8;
+const PREFIX_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+pub struct GeneratedApiKey {
+    // Full key, shown to the user once
+    pub key: String,
+    pub prefix: String,
+    pub secret_hash: String,
+}
+
+pub fn generate_api_key() -> GeneratedApiKey {
+    let mut rng = rand::thread_rng();
+    let prefix: String = (0..PREFIX_LENGTH)
+        .map(|_| PREFIX_ALPHABET[rng.gen_range(0..PREFIX_ALPHABET.len())] as char)
+        .collect();
+    let secret = refresh::generate_refresh_token();
+    GeneratedApiKey {
+        key: format!("{}{}_{}", KEY_MARKER, prefix, secret),
+        secret_hash: hash_api_key_secret(&secret),
+        prefix,
+    }
+}
+
+// Splits a key into its prefix and secret, or returns `None` if it isn't shaped like one
+pub fn parse_api_key(key: &str) -> Option<(&str, &str)> {
+    let (prefix, secret) = key.strip_prefix(KEY_MARKER)?.split_once('_')?;
+    if prefix.len() != PREFIX_LENGTH || !prefix.bytes().all(|b| PREFIX_ALPHABET.contains(&b)) || secret.is_empty() {
+        return None;
+    }
+    Some((prefix, secret))
+}
+
+pub fn hash_api_key_secret(secret: &str) -> String {
+    refresh::hash_refresh_token(secret)
+}
+
+// A stored API key, without its secret
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Permission>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn is_usable(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+// The API key a request was authenticated with. Its scopes limit the owner's permissions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyIdentity {
+    pub key_id: i64,
+    pub user_id: i64,
+    pub scopes: Vec<Permission>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_generated_key_parses_back() {
+        let generated = generate_api_key();
+        let (prefix, secret) = parse_api_key(&generated.key).expect("Generated key should parse");
+        assert_eq!(prefix, generated.prefix);
+        assert_eq!(hash_api_key_secret(secret), generated.secret_hash);
+        assert_ne!(generate_api_key().prefix, generated.prefix);
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_keys() {
+        assert_eq!(parse_api_key("ak_abcd1234_s3cret"), Some(("abcd1234", "s3cret")));
+        // Secrets may themselves contain underscores
+        assert_eq!(parse_api_key("ak_abcd1234_a_b"), Some(("abcd1234", "a_b")));
+        assert_eq!(parse_api_key("abcd1234_s3cret"), None);
+        assert_eq!(parse_api_key("ak_short_s3cret"), None);
+        assert_eq!(parse_api_key("ak_ABCD1234_s3cret"), None);
+        assert_eq!(parse_api_key("ak_abcd1234_"), None);
+    }
+
+    #[test]
+    fn test_key_usability() {
+        let now = Utc::now();
+        let mut key = ApiKey {
+            id: 1,
+            user_id: 1,
+            name: "ci".to_string(),
+            prefix: "abcd1234".to_string(),
+            scopes: vec![],
+            created_at: now,
+            expires_at: None,
+            last_used_at: None,
+            revoked_at: None,
+        };
+        assert!(key.is_usable(now));
+
+        key.expires_at = Some(now - Duration::seconds(1));
+        assert!(!key.is_usable(now));
+
+        key.expires_at = Some(now + Duration::days(1));
+        key.revoked_at = Some(now);
+        assert!(!key.is_usable(now));
+    }
+}
@@ -0,0 +1,454 @@
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use chrono::Utc;
+use deadpool_postgres::{Client, Pool};
+use futures::future::{self, Either};
+use futures::Future;
+use log::{error, warn};
+use std::pin::Pin;
+use uuid::Uuid;
+
+use crate::auth::api_keys::{self, ApiKeyIdentity};
+use crate::auth::sessions::SessionCache;
+use crate::auth::{decode_token, Claims, JwtKeys, Permission, User};
+use crate::db;
+use crate::error::AppError;
+use crate::middleware::rate_limiter::RateLimiter;
+
+// How a request proved who it comes from
+#[derive(Debug, Clone)]
+pub enum Credential {
+    // A JWT access token from a login
+    Token(Claims),
+    // A personal API key; only the owner's permissions that are also in its scopes apply
+    ApiKey(ApiKeyIdentity),
+}
+
+impl Credential {
+    pub fn user_id(&self) -> Result<i64, AppError> {
+        match self {
+            Credential::Token(claims) => claims.sub.parse().map_err(|_| AppError::Unauthorized),
+            Credential::ApiKey(key) => Ok(key.user_id),
+        }
+    }
+
+    // Id of the admin acting as the user, for requests made with an impersonation token
+    pub fn impersonator(&self) -> Option<i64> {
+        match self {
+            Credential::Token(claims) => claims.impersonator(),
+            Credential::ApiKey(_) => None,
+        }
+    }
+}
+
+// The authenticated caller of a request, loaded from the credential in the Authorization header
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user: User,
+    pub credential: Credential,
+}
+
+impl AuthenticatedUser {
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        let scoped_in = match &self.credential {
+            Credential::Token(_) => true,
+            Credential::ApiKey(key) => key.scopes.contains(&permission),
+        };
+        scoped_in && self.user.permission_set().contains(permission)
+    }
+
+    // Fails with `AppError::Forbidden` unless the caller holds the permission
+    pub fn require(&self, permission: Permission) -> Result<(), AppError> {
+        if self.has_permission(permission) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+
+    pub fn impersonator(&self) -> Option<i64> {
+        self.credential.impersonator()
+    }
+
+    // Fails with `AppError::Forbidden` for API keys and impersonation tokens. Used by account
+    // management routes (password, two-factor setup, API keys) that need the user themselves logged in.
+    pub fn require_session(&self) -> Result<(), AppError> {
+        match &self.credential {
+            Credential::Token(claims) if claims.act.is_none() => Ok(()),
+            _ => Err(AppError::Forbidden),
+        }
+    }
+}
+
+// Extracts the credential of an `Authorization: <scheme> <credential>` header
+fn authorization<'a>(req: &'a HttpRequest, expected_scheme: &str) -> Option<&'a str> {
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, value) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case(expected_scheme) {
+        return None;
+    }
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+// Extracts the token from an `Authorization: Bearer <token>` header
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    authorization(req, "bearer")
+}
+
+// Extracts the key from an `Authorization: ApiKey <key>` header
+pub fn api_key(req: &HttpRequest) -> Option<&str> {
+    authorization(req, "apikey")
+}
+
+// Returns the validated claims of a bearer token. Claims already checked by the
+// `RequireAuth` middleware are reused instead of decoding the token twice.
+pub fn request_claims(req: &HttpRequest) -> Result<Claims, AppError> {
+    if let Some(Credential::Token(claims)) = req.extensions().get::<Credential>() {
+        return Ok(claims.clone());
+    }
+
+    let keys = req.app_data::<web::Data<JwtKeys>>().ok_or_else(|| {
+        error!("JWT keys are not registered as app data");
+        AppError::InternalServerError
+    })?;
+    let token = bearer_token(req).ok_or(AppError::Unauthorized)?;
+    decode_token(keys, token).map_err(|e| {
+        warn!("Rejected bearer token: {}", e);
+        AppError::Unauthorized
+    })
+}
+
+fn database_pool(pool: Option<web::Data<Pool>>) -> Result<web::Data<Pool>, AppError> {
+    pool.ok_or_else(|| {
+        error!("Database pool is not registered as app data");
+        AppError::InternalServerError
+    })
+}
+
+// Checks an API key against the database, takes the request from the key's rate limit bucket
+// and records that the key was used
+async fn verify_api_key(
+    pool: Option<web::Data<Pool>>,
+    limiter: Option<web::Data<RateLimiter>>,
+    key: String,
+    method: String,
+    path: String,
+) -> Result<ApiKeyIdentity, AppError> {
+    let (prefix, secret) = api_keys::parse_api_key(&key).ok_or(AppError::Unauthorized)?;
+
+    let pool = database_pool(pool)?;
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let (stored, secret_hash) = db::find_api_key_by_prefix(&client, prefix)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    if secret_hash != api_keys::hash_api_key_secret(secret) || !stored.is_usable(Utc::now()) {
+        warn!("Rejected API key {}", prefix);
+        return Err(AppError::Unauthorized);
+    }
+
+    let limiter = limiter.ok_or_else(|| {
+        error!("Rate limiter is not registered as app data");
+        AppError::InternalServerError
+    })?;
+    limiter.check_api_key(stored.id, &method, &path)?;
+
+    db::touch_api_key(&client, stored.id).await?;
+    Ok(ApiKeyIdentity {
+        key_id: stored.id,
+        user_id: stored.user_id,
+        scopes: stored.scopes,
+    })
+}
+
+// Rejects tokens of revoked sessions. Known sessions are answered from the cache; the
+// database is only asked once the cached state has expired.
+async fn verify_session(
+    pool: Option<web::Data<Pool>>,
+    sessions: Option<web::Data<SessionCache>>,
+    claims: &Claims,
+) -> Result<(), AppError> {
+    // Tokens issued before sessions were tracked expire on their own
+    let Some(sid) = claims.sid.as_deref() else {
+        return Ok(());
+    };
+    let session_id = Uuid::parse_str(sid).map_err(|_| AppError::Unauthorized)?;
+    let sessions = sessions.ok_or_else(|| {
+        error!("Session cache is not registered as app data");
+        AppError::InternalServerError
+    })?;
+
+    let now = Utc::now();
+    let revoked = match sessions.is_revoked(session_id, now) {
+        Some(revoked) => revoked,
+        None => {
+            let pool = database_pool(pool)?;
+            let client = pool.get().await.map_err(|e| {
+                error!("Failed to get database connection: {}", e);
+                AppError::DatabaseError(e.to_string())
+            })?;
+            let revoked = db::is_session_revoked(&client, session_id).await?;
+            sessions.insert(session_id, revoked, now);
+            revoked
+        }
+    };
+    if revoked {
+        warn!("Rejected token of revoked session {}", session_id);
+        return Err(AppError::Unauthorized);
+    }
+    Ok(())
+}
+
+// Verifies the credential of a request: a bearer token or an API key. The returned future does
+// not borrow the request, so middleware can await it before passing the request on.
+pub fn request_credential(req: &HttpRequest) -> impl Future<Output = Result<Credential, AppError>> {
+    if let Some(credential) = req.extensions().get::<Credential>() {
+        return Either::Left(future::ready(Ok(credential.clone())));
+    }
+    let pool = req.app_data::<web::Data<Pool>>().cloned();
+    match api_key(req) {
+        Some(key) => {
+            let key = key.to_string();
+            let limiter = req.app_data::<web::Data<RateLimiter>>().cloned();
+            let (method, path) = (req.method().to_string(), req.path().to_string());
+            Either::Right(Either::Left(async move {
+                verify_api_key(pool, limiter, key, method, path).await.map(Credential::ApiKey)
+            }))
+        }
+        None => {
+            let claims = request_claims(req);
+            let sessions = req.app_data::<web::Data<SessionCache>>().cloned();
+            Either::Right(Either::Right(async move {
+                let claims = claims?;
+                verify_session(pool, sessions, &claims).await?;
+                Ok(Credential::Token(claims))
+            }))
+        }
+    }
+}
+
+// Impersonation tokens stop working once the admin who got them is no longer active or
+// has lost the permission to impersonate
+async fn verify_impersonator(client: &Client, claims: &Claims) -> Result<(), AppError> {
+    let actor_id = claims.impersonator().ok_or(AppError::Unauthorized)?;
+    let actor = db::find_user_by_id(client, actor_id)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    if !actor.status.is_active() || !actor.permission_set().contains(Permission::UsersImpersonate) {
+        warn!("Rejected impersonation token of user {} acting as user {}", actor_id, claims.sub);
+        return Err(AppError::Unauthorized);
+    }
+    Ok(())
+}
+
+// Loads the user a request was authenticated as, and checks that the account is active, the
+// token is newer than the last password change and an impersonating admin may still do so.
+// Callers already checked by `RequireAuth` or `RequirePermission` are reused. The returned
+// future does not borrow the request, so middleware can await it before passing the request on.
+pub fn load_authenticated_user(req: &HttpRequest) -> impl Future<Output = Result<AuthenticatedUser, AppError>> {
+    if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+        return Either::Left(future::ready(Ok(user.clone())));
+    }
+    let credential = request_credential(req);
+    let pool = req.app_data::<web::Data<Pool>>().cloned();
+
+    Either::Right(async move {
+        let credential = credential.await?;
+        let user_id = credential.user_id()?;
+
+        let pool = database_pool(pool)?;
+        let client = pool.get().await.map_err(|e| {
+            error!("Failed to get database connection: {}", e);
+            AppError::DatabaseError(e.to_string())
+        })?;
+
+        // A valid token for a user that no longer exists is treated like any other bad token
+        let user = db::find_user_by_id(&client, user_id)
+            .await?
+            .ok_or(AppError::Unauthorized)?;
+        user.ensure_active()?;
+        if let Credential::Token(claims) = &credential {
+            if user.token_predates_password_change(claims) {
+                return Err(AppError::Unauthorized);
+            }
+            if claims.act.is_some() {
+                verify_impersonator(&client, claims).await?;
+            }
+        }
+
+        Ok(AuthenticatedUser { user, credential })
+    })
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AppError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        Box::pin(load_authenticated_user(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::keys::tests::test_keys;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_bearer_token_parsing() {
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer abc.def.ghi"))
+            .to_http_request();
+        assert_eq!(bearer_token(&req), Some("abc.def.ghi"));
+
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "bearer abc.def.ghi"))
+            .to_http_request();
+        assert_eq!(bearer_token(&req), Some("abc.def.ghi"));
+    }
+
+    #[test]
+    fn test_bearer_token_missing_or_wrong_scheme() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(bearer_token(&req), None);
+
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Basic dXNlcjpwYXNz"))
+            .to_http_request();
+        assert_eq!(bearer_token(&req), None);
+
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer "))
+            .to_http_request();
+        assert_eq!(bearer_token(&req), None);
+    }
+
+    #[test]
+    fn test_api_key_header_parsing() {
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "ApiKey ak_abcd1234_secret"))
+            .to_http_request();
+        assert_eq!(api_key(&req), Some("ak_abcd1234_secret"));
+        assert_eq!(bearer_token(&req), None);
+
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer abc.def.ghi"))
+            .to_http_request();
+        assert_eq!(api_key(&req), None);
+    }
+
+    #[actix_web::test]
+    async fn test_malformed_api_key_is_rejected_without_database() {
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "ApiKey not-a-key"))
+            .to_http_request();
+        assert!(matches!(request_credential(&req).await, Err(AppError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_api_key_scopes_limit_permissions() {
+        let user = User {
+            id: 1,
+            email: "admin@example.com".to_string(),
+            username: "admin".to_string(),
+            created_at: Utc::now(),
+            avatar: None,
+            tokens: None,
+            status: crate::auth::UserStatus::Active,
+            permissions: Some(serde_json::json!({ "roles": ["admin"] })),
+            last_login: None,
+            password_changed_at: None,
+            failed_login_attempts: 0,
+            locked_until: None,
+        };
+        let caller = AuthenticatedUser {
+            user,
+            credential: Credential::ApiKey(ApiKeyIdentity {
+                key_id: 1,
+                user_id: 1,
+                scopes: vec![Permission::StatsRead],
+            }),
+        };
+        assert!(caller.has_permission(Permission::StatsRead));
+        assert!(!caller.has_permission(Permission::UsersWrite));
+        assert!(matches!(caller.require_session(), Err(AppError::Forbidden)));
+    }
+
+    #[test]
+    fn test_impersonation_tokens_cannot_manage_the_account() {
+        let keys = test_keys();
+        let token = crate::auth::generate_impersonation_token(&keys, "1", 2, uuid::Uuid::new_v4()).unwrap();
+        let caller = AuthenticatedUser {
+            user: User {
+                id: 1,
+                email: "alice@example.com".to_string(),
+                username: "alice".to_string(),
+                created_at: Utc::now(),
+                avatar: None,
+                tokens: None,
+                status: crate::auth::UserStatus::Active,
+                permissions: None,
+                last_login: None,
+                password_changed_at: None,
+                failed_login_attempts: 0,
+                locked_until: None,
+            },
+            credential: Credential::Token(decode_token(&keys, &token).unwrap()),
+        };
+        assert_eq!(caller.impersonator(), Some(2));
+        assert!(matches!(caller.require_session(), Err(AppError::Forbidden)));
+    }
+
+    #[test]
+    fn test_request_claims_rejects_invalid_token() {
+        let req = TestRequest::default()
+            .app_data(web::Data::new(test_keys()))
+            .insert_header((AUTHORIZATION, "Bearer not-a-jwt"))
+            .to_http_request();
+        assert!(matches!(request_claims(&req), Err(AppError::Unauthorized)));
+    }
+
+    #[test]
+    fn test_request_claims_accepts_valid_token() {
+        let keys = test_keys();
+        let token = crate::auth::generate_token(&keys, "7").unwrap();
+        let req = TestRequest::default()
+            .app_data(web::Data::new(keys))
+            .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+            .to_http_request();
+        let claims = request_claims(&req).expect("Expected valid claims");
+        assert_eq!(claims.sub, "7");
+    }
+
+    #[actix_web::test]
+    async fn test_revoked_session_is_rejected_from_cache() {
+        let keys = test_keys();
+        let session_id = Uuid::new_v4();
+        let token = crate::auth::generate_session_token(&keys, "7", session_id).unwrap();
+        let sessions = web::Data::new(SessionCache::new(30, 3600));
+        let request = || {
+            TestRequest::default()
+                .app_data(web::Data::new(test_keys()))
+                .app_data(sessions.clone())
+                .insert_header((AUTHORIZATION, format!("Bearer {}", token)))
+                .to_http_request()
+        };
+
+        // No database pool is registered, so these are answered from the cache alone
+        sessions.insert(session_id, false, Utc::now());
+        assert!(matches!(request_credential(&request()).await, Ok(Credential::Token(_))));
+
+        sessions.revoke(&[session_id], Utc::now());
+        assert!(matches!(request_credential(&request()).await, Err(AppError::Unauthorized)));
+    }
+}
@@ -0,0 +1,539 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters,
+    OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use std::collections::HashSet;
+use std::fs;
+use thiserror::Error;
+
+use crate::config::{AuthConfig, JwtAlgorithm, VerificationKeyConfig};
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("Missing auth setting: {0}")]
+    Missing(&'static str),
+    #[error("Failed to read key file {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Invalid key: {0}")]
+    Invalid(#[from] jsonwebtoken::errors::Error),
+    #[error("Unsupported public key in {0}; expected an RSA or Ed25519 PEM public key")]
+    UnsupportedPublicKey(String),
+    #[error("Key id {0} is used more than once")]
+    DuplicateKid(String),
+}
+
+// A key tokens are verified with, picked by the `kid` header of the token
+#[derive(Clone)]
+pub struct VerificationKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    decoding: DecodingKey,
+    // The public key as published in the JWKS; `None` for HS256 secrets
+    jwk: Option<Jwk>,
+}
+
+// The key ring: one signing key plus the keys of earlier rotations, and the claim
+// settings, built once from `AuthConfig`
+#[derive(Clone)]
+pub struct JwtKeys {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    // The signing key's own verification key comes first
+    verification_keys: Vec<VerificationKey>,
+    pub issuer: String,
+    pub audience: String,
+    pub token_ttl_seconds: u64,
+    pub mfa_pending_ttl_seconds: u64,
+    pub impersonation_ttl_seconds: u64,
+}
+
+fn read_key_file(path: &str) -> Result<Vec<u8>, KeyError> {
+    fs::read(path).map_err(|source| KeyError::Io {
+        path: path.to_string(),
+        source,
+    })
+}
+
+fn read_secret(
+    secret: &Option<String>,
+    secret_file: &Option<String>,
+    setting: &'static str,
+) -> Result<Vec<u8>, KeyError> {
+    match (secret_file, secret) {
+        (Some(path), _) => read_key_file(path),
+        (None, Some(secret)) => Ok(secret.as_bytes().to_vec()),
+        (None, None) => Err(KeyError::Missing(setting)),
+    }
+}
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_INTEGER: u8 = 0x02;
+const DER_BIT_STRING: u8 = 0x03;
+const DER_OBJECT_IDENTIFIER: u8 = 0x06;
+// 1.2.840.113549.1.1.1 and 1.3.101.112
+const RSA_ENCRYPTION_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const ED25519_OID: &[u8] = &[0x2b, 0x65, 0x70];
+
+// Splits the first DER element with the given tag off the input, returning its contents
+// and the remaining input
+fn der_element(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&first, rest) = input.split_first()?;
+    if first != tag {
+        return None;
+    }
+    let (&length, rest) = rest.split_first()?;
+    let (length, rest) = if length < 0x80 {
+        (length as usize, rest)
+    } else {
+        let count = (length & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let (length_bytes, rest) = rest.split_at(count);
+        (length_bytes.iter().fold(0usize, |length, byte| (length << 8) | *byte as usize), rest)
+    };
+    (rest.len() >= length).then(|| rest.split_at(length))
+}
+
+// Modulus and exponent of a PKCS#1 `RSAPublicKey`
+fn rsa_components(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (key, _) = der_element(der, DER_SEQUENCE)?;
+    let (modulus, rest) = der_element(key, DER_INTEGER)?;
+    let (exponent, _) = der_element(rest, DER_INTEGER)?;
+    // DER integers are signed, so a leading zero byte may be there to keep them positive
+    Some((trim_leading_zeros(modulus), trim_leading_zeros(exponent)))
+}
+
+fn trim_leading_zeros(value: &[u8]) -> &[u8] {
+    let start = value.iter().position(|byte| *byte != 0).unwrap_or(value.len());
+    &value[start..]
+}
+
+// Reads a PEM public key, either a `PUBLIC KEY` (SubjectPublicKeyInfo) or an `RSA PUBLIC KEY`
+// (PKCS#1), into the algorithm it is for and its JWK parameters
+fn public_key_parameters(pem: &[u8]) -> Option<(Algorithm, AlgorithmParameters)> {
+    let pem = pem::parse(pem).ok()?;
+    let rsa = |der: &[u8]| {
+        rsa_components(der).map(|(modulus, exponent)| {
+            (
+                Algorithm::RS256,
+                AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(modulus),
+                    e: URL_SAFE_NO_PAD.encode(exponent),
+                }),
+            )
+        })
+    };
+
+    match pem.tag() {
+        "RSA PUBLIC KEY" => rsa(pem.contents()),
+        "PUBLIC KEY" => {
+            let (info, _) = der_element(pem.contents(), DER_SEQUENCE)?;
+            let (algorithm, rest) = der_element(info, DER_SEQUENCE)?;
+            let (oid, _) = der_element(algorithm, DER_OBJECT_IDENTIFIER)?;
+            let (bits, _) = der_element(rest, DER_BIT_STRING)?;
+            // The first byte counts unused bits, which keys never have
+            let key = bits.strip_prefix(&[0])?;
+            match oid {
+                RSA_ENCRYPTION_OID => rsa(key),
+                ED25519_OID if key.len() == 32 => Some((
+                    Algorithm::EdDSA,
+                    AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                        key_type: OctetKeyPairType::OctetKeyPair,
+                        curve: EllipticCurve::Ed25519,
+                        x: URL_SAFE_NO_PAD.encode(key),
+                    }),
+                )),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+impl VerificationKey {
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding
+    }
+
+    fn secret(kid: &str, secret: &[u8]) -> Self {
+        VerificationKey {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS256,
+            decoding: DecodingKey::from_secret(secret),
+            jwk: None,
+        }
+    }
+
+    // Loads a PEM public key. With `expected` set, the key must be for that algorithm;
+    // otherwise the algorithm follows from the key.
+    fn public(kid: &str, expected: Option<JwtAlgorithm>, path: &str) -> Result<Self, KeyError> {
+        let pem = read_key_file(path)?;
+        let (algorithm, parameters) =
+            public_key_parameters(&pem).ok_or_else(|| KeyError::UnsupportedPublicKey(path.to_string()))?;
+        let matches_expected = match expected {
+            None => true,
+            Some(JwtAlgorithm::Rs256) => algorithm == Algorithm::RS256,
+            Some(JwtAlgorithm::EdDsa) => algorithm == Algorithm::EdDSA,
+            Some(JwtAlgorithm::Hs256) => false,
+        };
+        if !matches_expected {
+            return Err(KeyError::UnsupportedPublicKey(path.to_string()));
+        }
+
+        let (decoding, key_algorithm) = if algorithm == Algorithm::RS256 {
+            (DecodingKey::from_rsa_pem(&pem)?, KeyAlgorithm::RS256)
+        } else {
+            (DecodingKey::from_ed_pem(&pem)?, KeyAlgorithm::EdDSA)
+        };
+        Ok(VerificationKey {
+            kid: kid.to_string(),
+            algorithm,
+            decoding,
+            jwk: Some(Jwk {
+                common: CommonParameters {
+                    public_key_use: Some(PublicKeyUse::Signature),
+                    key_algorithm: Some(key_algorithm),
+                    key_id: Some(kid.to_string()),
+                    ..Default::default()
+                },
+                algorithm: parameters,
+            }),
+        })
+    }
+
+    fn from_config(config: &VerificationKeyConfig) -> Result<Self, KeyError> {
+        match config.algorithm {
+            JwtAlgorithm::Hs256 => Ok(VerificationKey::secret(
+                &config.kid,
+                &read_secret(&config.secret, &config.secret_file, "auth.verification_keys.secret or secret_file")?,
+            )),
+            algorithm => VerificationKey::public(
+                &config.kid,
+                Some(algorithm),
+                config
+                    .public_key_file
+                    .as_deref()
+                    .ok_or(KeyError::Missing("auth.verification_keys.public_key_file"))?,
+            ),
+        }
+    }
+}
+
+// Public keys from a directory of `<kid>.pem` files, in file name order
+fn load_key_dir(dir: &str) -> Result<Vec<VerificationKey>, KeyError> {
+    let io_error = |source| KeyError::Io {
+        path: dir.to_string(),
+        source,
+    };
+    let mut paths: Vec<_> = fs::read_dir(dir)
+        .map_err(io_error)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()
+        .map_err(io_error)?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "pem"));
+    paths.sort();
+
+    paths
+        .iter()
+        .map(|path| {
+            let kid = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+            VerificationKey::public(kid, None, &path.to_string_lossy())
+        })
+        .collect()
+}
+
+impl JwtKeys {
+    pub fn from_config(config: &AuthConfig) -> Result<Self, KeyError> {
+        let (algorithm, encoding, signing_key) = match config.algorithm {
+            JwtAlgorithm::Hs256 => {
+                let secret = read_secret(&config.secret, &config.secret_file, "auth.secret or auth.secret_file")?;
+                (
+                    Algorithm::HS256,
+                    EncodingKey::from_secret(&secret),
+                    VerificationKey::secret(&config.kid, &secret),
+                )
+            }
+            JwtAlgorithm::Rs256 | JwtAlgorithm::EdDsa => {
+                let private_pem = read_key_file(
+                    config.private_key_file.as_deref().ok_or(KeyError::Missing("auth.private_key_file"))?,
+                )?;
+                let public_key = VerificationKey::public(
+                    &config.kid,
+                    Some(config.algorithm),
+                    config.public_key_file.as_deref().ok_or(KeyError::Missing("auth.public_key_file"))?,
+                )?;
+                if config.algorithm == JwtAlgorithm::Rs256 {
+                    (Algorithm::RS256, EncodingKey::from_rsa_pem(&private_pem)?, public_key)
+                } else {
+                    (Algorithm::EdDSA, EncodingKey::from_ed_pem(&private_pem)?, public_key)
+                }
+            }
+        };
+
+        let mut verification_keys = vec![signing_key];
+        for key in &config.verification_keys {
+            verification_keys.push(VerificationKey::from_config(key)?);
+        }
+        if let Some(dir) = &config.verification_keys_dir {
+            verification_keys.extend(load_key_dir(dir)?);
+        }
+
+        let mut kids = HashSet::new();
+        for key in &verification_keys {
+            if !kids.insert(key.kid.as_str()) {
+                return Err(KeyError::DuplicateKid(key.kid.clone()));
+            }
+        }
+
+        Ok(JwtKeys {
+            kid: config.kid.clone(),
+            algorithm,
+            encoding,
+            verification_keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            token_ttl_seconds: config.token_ttl_seconds,
+            mfa_pending_ttl_seconds: config.mfa_pending_ttl_seconds,
+            impersonation_ttl_seconds: config.impersonation_ttl_seconds,
+        })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    // Header for new tokens, naming the signing key
+    pub fn header(&self) -> Header {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        header
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding
+    }
+
+    // The key named by a token's `kid`. Tokens without one were issued before key ids
+    // were introduced and are checked against the signing key.
+    pub fn verification_key(&self, kid: Option<&str>) -> Option<&VerificationKey> {
+        match kid {
+            Some(kid) => self.verification_keys.iter().find(|key| key.kid == kid),
+            None => self.verification_keys.first(),
+        }
+    }
+
+    // The public keys in the ring, for other services to verify our tokens with.
+    // HS256 secrets are never included.
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self.verification_keys.iter().filter_map(|key| key.jwk.clone()).collect(),
+        }
+    }
+
+    // Validation rules for incoming tokens: signature algorithm of the key, exp, nbf, iss and aud
+    pub fn validation(&self, key: &VerificationKey) -> Validation {
+        let mut validation = Validation::new(key.algorithm);
+        validation.validate_nbf = true;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud", "sub"]);
+        validation
+    }
+
+    // Audience of the short-lived token handed out between the password and the second factor.
+    // It differs from the access token audience, so the two can never be used in place of each other.
+    pub fn mfa_pending_audience(&self) -> String {
+        format!("{}:mfa_pending", self.audience)
+    }
+
+    pub fn mfa_pending_validation(&self, key: &VerificationKey) -> Validation {
+        let mut validation = self.validation(key);
+        validation.set_audience(&[self.mfa_pending_audience()]);
+        validation
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/jwt/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    // HS256 configuration shared by the auth unit tests
+    pub(crate) fn test_auth_config() -> AuthConfig {
+        AuthConfig {
+            algorithm: JwtAlgorithm::Hs256,
+            secret: Some("unit_test_secret".to_string()),
+            secret_file: None,
+            private_key_file: None,
+            public_key_file: None,
+            kid: "test".to_string(),
+            verification_keys: Vec::new(),
+            verification_keys_dir: None,
+            issuer: "test_issuer".to_string(),
+            audience: "test_audience".to_string(),
+            token_ttl_seconds: 3600,
+            refresh_token_ttl_seconds: 86400,
+            mfa_pending_ttl_seconds: 300,
+            impersonation_ttl_seconds: 600,
+            session_cache_seconds: 30,
+            totp_issuer: "Test".to_string(),
+        }
+    }
+
+    pub(crate) fn test_keys() -> JwtKeys {
+        JwtKeys::from_config(&test_auth_config()).expect("Failed to build test keys")
+    }
+
+    #[test]
+    fn test_hs256_requires_secret() {
+        let mut config = test_auth_config();
+        config.secret = None;
+        assert!(matches!(JwtKeys::from_config(&config), Err(KeyError::Missing(_))));
+    }
+
+    #[test]
+    fn test_hs256_secret_file() {
+        let mut config = test_auth_config();
+        config.secret = None;
+        config.secret_file = Some(fixture("rs256_public.pem")); // Any readable file works as a secret
+        let keys = JwtKeys::from_config(&config).expect("Secret file should be accepted");
+        assert_eq!(keys.algorithm(), Algorithm::HS256);
+    }
+
+    #[test]
+    fn test_rs256_keys_from_pem_files() {
+        let mut config = test_auth_config();
+        config.algorithm = JwtAlgorithm::Rs256;
+        config.private_key_file = Some(fixture("rs256_private.pem"));
+        config.public_key_file = Some(fixture("rs256_public.pem"));
+        let keys = JwtKeys::from_config(&config).expect("RS256 keys should load");
+        assert_eq!(keys.algorithm(), Algorithm::RS256);
+    }
+
+    #[test]
+    fn test_eddsa_keys_from_pem_files() {
+        let mut config = test_auth_config();
+        config.algorithm = JwtAlgorithm::EdDsa;
+        config.private_key_file = Some(fixture("ed25519_private.pem"));
+        config.public_key_file = Some(fixture("ed25519_public.pem"));
+        let keys = JwtKeys::from_config(&config).expect("EdDSA keys should load");
+        assert_eq!(keys.algorithm(), Algorithm::EdDSA);
+    }
+
+    #[test]
+    fn test_missing_key_file() {
+        let mut config = test_auth_config();
+        config.algorithm = JwtAlgorithm::Rs256;
+        config.private_key_file = Some(fixture("does_not_exist.pem"));
+        config.public_key_file = Some(fixture("rs256_public.pem"));
+        assert!(matches!(JwtKeys::from_config(&config), Err(KeyError::Io { .. })));
+    }
+
+    #[test]
+    fn test_verification_key_lookup_by_kid() {
+        let mut config = test_auth_config();
+        config.verification_keys.push(VerificationKeyConfig {
+            kid: "old".to_string(),
+            algorithm: JwtAlgorithm::Hs256,
+            secret: Some("old_secret".to_string()),
+            secret_file: None,
+            public_key_file: None,
+        });
+        let keys = JwtKeys::from_config(&config).unwrap();
+
+        assert_eq!(keys.verification_key(Some("old")).unwrap().kid, "old");
+        assert_eq!(keys.verification_key(Some("test")).unwrap().kid, "test");
+        // Tokens without a kid are checked against the signing key
+        assert_eq!(keys.verification_key(None).unwrap().kid, "test");
+        assert!(keys.verification_key(Some("unknown")).is_none());
+        // Secrets are never published
+        assert!(keys.jwks().keys.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_kid_is_rejected() {
+        let mut config = test_auth_config();
+        config.verification_keys.push(VerificationKeyConfig {
+            kid: "test".to_string(),
+            algorithm: JwtAlgorithm::Hs256,
+            secret: Some("old_secret".to_string()),
+            secret_file: None,
+            public_key_file: None,
+        });
+        assert!(matches!(JwtKeys::from_config(&config), Err(KeyError::DuplicateKid(kid)) if kid == "test"));
+    }
+
+    #[test]
+    fn test_jwks_publishes_public_keys() {
+        let mut config = test_auth_config();
+        config.algorithm = JwtAlgorithm::Rs256;
+        config.kid = "rsa-2026".to_string();
+        config.private_key_file = Some(fixture("rs256_private.pem"));
+        config.public_key_file = Some(fixture("rs256_public.pem"));
+        config.verification_keys.push(VerificationKeyConfig {
+            kid: "ed-2025".to_string(),
+            algorithm: JwtAlgorithm::EdDsa,
+            secret: None,
+            secret_file: None,
+            public_key_file: Some(fixture("ed25519_public.pem")),
+        });
+        let jwks = serde_json::to_value(JwtKeys::from_config(&config).unwrap().jwks()).unwrap();
+
+        let rsa = &jwks["keys"][0];
+        assert_eq!(rsa["kid"], "rsa-2026");
+        assert_eq!(rsa["kty"], "RSA");
+        assert_eq!(rsa["alg"], "RS256");
+        assert_eq!(rsa["use"], "sig");
+        assert_eq!(rsa["e"], "AQAB");
+
+        let ed = &jwks["keys"][1];
+        assert_eq!(ed["kid"], "ed-2025");
+        assert_eq!(ed["kty"], "OKP");
+        assert_eq!(ed["crv"], "Ed25519");
+        assert_eq!(ed["x"], "9Cmeiq-DxKqwDWmr45DNmXWh1NrOKe0KThhi0bciAaE");
+    }
+
+    #[test]
+    fn test_wrong_algorithm_for_public_key() {
+        let mut config = test_auth_config();
+        config.algorithm = JwtAlgorithm::EdDsa;
+        config.private_key_file = Some(fixture("ed25519_private.pem"));
+        config.public_key_file = Some(fixture("rs256_public.pem"));
+        assert!(matches!(JwtKeys::from_config(&config), Err(KeyError::UnsupportedPublicKey(_))));
+    }
+
+    #[test]
+    fn test_verification_keys_dir() {
+        let dir = std::env::temp_dir().join(format!("jwt-keys-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        fs::copy(fixture("rs256_public.pem"), dir.join("rsa-old.pem")).unwrap();
+        fs::copy(fixture("ed25519_public.pem"), dir.join("ed-old.pem")).unwrap();
+        fs::write(dir.join("README"), "not a key").unwrap();
+
+        let mut config = test_auth_config();
+        config.verification_keys_dir = Some(dir.to_string_lossy().into_owned());
+        let keys = JwtKeys::from_config(&config);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let keys = keys.unwrap();
+        assert_eq!(keys.verification_key(Some("rsa-old")).unwrap().algorithm, Algorithm::RS256);
+        assert_eq!(keys.verification_key(Some("ed-old")).unwrap().algorithm, Algorithm::EdDSA);
+        assert_eq!(keys.jwks().keys.len(), 2);
+    }
+}
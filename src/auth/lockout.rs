@@ -0,0 +1,299 @@
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::config::LockoutConfig;
+
+// Delay before answering a login after `failures` consecutive failures: nothing for the
+// first attempt, then `backoff_base_ms` doubling with every failure up to `backoff_max_ms`
+pub fn backoff_delay(config: &LockoutConfig, failures: u32) -> std::time::Duration {
+    if failures == 0 {
+        return std::time::Duration::ZERO;
+    }
+    let factor = 1u64.checked_shl(failures - 1).unwrap_or(u64::MAX);
+    let delay = config.backoff_base_ms.saturating_mul(factor).min(config.backoff_max_ms);
+    std::time::Duration::from_millis(delay)
+}
+
+// How long to lock after the `failures`-th consecutive failure. Every `threshold` failures
+// lock again, each time twice as long as before, up to `max_lockout_seconds`.
+pub fn lockout_duration(config: &LockoutConfig, threshold: u32, failures: u32) -> Option<Duration> {
+    if threshold == 0 || failures == 0 || !failures.is_multiple_of(threshold) {
+        return None;
+    }
+    let round = failures / threshold - 1;
+    let factor = 1u64.checked_shl(round).unwrap_or(u64::MAX);
+    let seconds = config.lockout_seconds.saturating_mul(factor).min(config.max_lockout_seconds);
+    Some(Duration::seconds(seconds as i64))
+}
+
+// At most this many IPs or login names are tracked at once. Once full, new ones aren't counted
+// until old ones are forgotten, so made-up login names can't use up memory.
+const MAX_TRACKED_KEYS: usize = 100_000;
+// Stale records are dropped at most this often, rather than on every failure
+const PRUNE_INTERVAL_SECONDS: i64 = 60;
+// Longer login names are cut off; no username or email address is this long
+const MAX_LOGIN_KEY_LENGTH: usize = 254;
+
+struct Failures {
+    count: u32,
+    last_failure: DateTime<Utc>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct FailureMap {
+    records: HashMap<String, Failures>,
+    last_pruned: Option<DateTime<Utc>>,
+}
+
+// Login names differing only in case or surrounding spaces share a record
+fn login_key(login: &str) -> String {
+    login.trim().chars().take(MAX_LOGIN_KEY_LENGTH).collect::<String>().to_lowercase()
+}
+
+struct ChallengeFailures {
+    count: u32,
+    // When the `mfa_token` expires; the count is useless after that
+    expires_at: DateTime<Utc>,
+}
+
+// Failed logins per client IP, which catch guessing across many accounts. Failed logins
+// per account are kept in the database (`users.failed_login_attempts`) instead; those for
+// login names without an account are kept here, so they get the same backoff. Wrong
+// second factors are also counted per MFA challenge, the `jti` of the `mfa_token`.
+pub struct LoginThrottle {
+    config: LockoutConfig,
+    max_tracked_keys: usize,
+    ips: Mutex<FailureMap>,
+    unknown_logins: Mutex<FailureMap>,
+    challenges: Mutex<HashMap<String, ChallengeFailures>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: LockoutConfig) -> Self {
+        LoginThrottle {
+            config,
+            max_tracked_keys: MAX_TRACKED_KEYS,
+            ips: Mutex::new(FailureMap::default()),
+            unknown_logins: Mutex::new(FailureMap::default()),
+            challenges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &LockoutConfig {
+        &self.config
+    }
+
+    fn is_stale(&self, record: &Failures, now: DateTime<Utc>) -> bool {
+        record.locked_until.is_none_or(|until| until <= now)
+            && now - record.last_failure > Duration::seconds(self.config.max_lockout_seconds as i64)
+    }
+
+    // Recent failures of the IP, and until when it is locked if it currently is
+    pub fn status(&self, ip: &str, now: DateTime<Utc>) -> (u32, Option<DateTime<Utc>>) {
+        let ips = self.ips.lock().unwrap();
+        match ips.records.get(ip) {
+            Some(record) if !self.is_stale(record, now) => {
+                (record.count, record.locked_until.filter(|until| *until > now))
+            }
+            _ => (0, None),
+        }
+    }
+
+    // Counts a failed login and returns the end of the lockout it triggered, if any
+    pub fn record_failure(&self, ip: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.count_failure(&self.ips, ip, self.config.ip_max_failed_attempts, now)
+    }
+
+    // Recent failures for a login name that matches no account
+    pub fn unknown_login_failures(&self, login: &str, now: DateTime<Utc>) -> u32 {
+        let logins = self.unknown_logins.lock().unwrap();
+        logins.records.get(&login_key(login)).filter(|record| !self.is_stale(record, now)).map_or(0, |record| record.count)
+    }
+
+    // Only slows down later attempts; there is no account to lock
+    pub fn record_unknown_login_failure(&self, login: &str, now: DateTime<Utc>) {
+        self.count_failure(&self.unknown_logins, &login_key(login), 0, now);
+    }
+
+    fn count_failure(
+        &self,
+        failures: &Mutex<FailureMap>,
+        key: &str,
+        threshold: u32,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let mut failures = failures.lock().unwrap();
+        // Forget keys that stopped failing long ago so the map doesn't grow forever
+        if failures.last_pruned.is_none_or(|pruned| now - pruned >= Duration::seconds(PRUNE_INTERVAL_SECONDS)) {
+            failures.records.retain(|_, record| !self.is_stale(record, now));
+            failures.last_pruned = Some(now);
+        }
+        if failures.records.len() >= self.max_tracked_keys && !failures.records.contains_key(key) {
+            warn!("Not counting a failed login: already tracking {} keys", failures.records.len());
+            return None;
+        }
+
+        let record = failures.records.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        record.count += 1;
+        record.last_failure = now;
+
+        let lockout = lockout_duration(&self.config, threshold, record.count)?;
+        let until = now + lockout;
+        record.locked_until = Some(until);
+        Some(until)
+    }
+
+    // Whether the challenge has used up its attempts and must not be accepted anymore
+    pub fn challenge_exhausted(&self, jti: &str, now: DateTime<Utc>) -> bool {
+        let challenges = self.challenges.lock().unwrap();
+        challenges
+            .get(jti)
+            .is_some_and(|record| record.expires_at > now && record.count >= self.config.mfa_attempts_per_challenge)
+    }
+
+    // Counts a wrong second factor for the challenge and returns whether it is now used up
+    pub fn record_challenge_failure(&self, jti: &str, expires_at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges.retain(|_, record| record.expires_at > now);
+
+        let record = challenges
+            .entry(jti.to_string())
+            .or_insert(ChallengeFailures { count: 0, expires_at });
+        record.count += 1;
+        record.count >= self.config.mfa_attempts_per_challenge
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> LockoutConfig {
+        LockoutConfig {
+            max_failed_attempts: 3,
+            ip_max_failed_attempts: 2,
+            lockout_seconds: 60,
+            max_lockout_seconds: 300,
+            backoff_base_ms: 100,
+            backoff_max_ms: 1000,
+            mfa_attempts_per_challenge: 3,
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay_doubles_up_to_the_maximum() {
+        let config = config();
+        let delays: Vec<u128> = (0..7).map(|n| backoff_delay(&config, n).as_millis()).collect();
+        assert_eq!(delays, vec![0, 100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff_delay(&config, 200).as_millis(), 1000);
+    }
+
+    #[test]
+    fn test_lockout_duration_grows_with_each_round() {
+        let config = config();
+        assert_eq!(lockout_duration(&config, 3, 2), None);
+        assert_eq!(lockout_duration(&config, 3, 3), Some(Duration::seconds(60)));
+        assert_eq!(lockout_duration(&config, 3, 4), None);
+        assert_eq!(lockout_duration(&config, 3, 6), Some(Duration::seconds(120)));
+        assert_eq!(lockout_duration(&config, 3, 9), Some(Duration::seconds(240)));
+        assert_eq!(lockout_duration(&config, 3, 12), Some(Duration::seconds(300)));
+        assert_eq!(lockout_duration(&config, 3, 3000), Some(Duration::seconds(300)));
+    }
+
+    #[test]
+    fn test_login_throttle_locks_ip_and_forgets_old_failures() {
+        let throttle = LoginThrottle::new(config());
+        let now = Utc::now();
+
+        assert_eq!(throttle.record_failure("10.0.0.1", now), None);
+        let until = throttle.record_failure("10.0.0.1", now).expect("Second failure should lock");
+        assert_eq!(until, now + Duration::seconds(60));
+        assert_eq!(throttle.status("10.0.0.1", now), (2, Some(until)));
+        assert_eq!(throttle.status("10.0.0.2", now), (0, None));
+
+        // Once the lockout is over the count stays until the failures are old enough
+        let later = now + Duration::seconds(61);
+        assert_eq!(throttle.status("10.0.0.1", later), (2, None));
+        let much_later = now + Duration::seconds(301);
+        assert_eq!(throttle.status("10.0.0.1", much_later), (0, None));
+    }
+
+    #[test]
+    fn test_challenge_is_exhausted_after_its_attempts() {
+        let throttle = LoginThrottle::new(config());
+        let now = Utc::now();
+        let expires_at = now + Duration::seconds(300);
+
+        assert!(!throttle.record_challenge_failure("jti-1", expires_at, now));
+        assert!(!throttle.record_challenge_failure("jti-1", expires_at, now));
+        assert!(!throttle.challenge_exhausted("jti-1", now));
+        assert!(throttle.record_challenge_failure("jti-1", expires_at, now));
+        assert!(throttle.challenge_exhausted("jti-1", now));
+        assert!(!throttle.challenge_exhausted("jti-2", now));
+
+        // Expired challenges are refused by their token anyway, so they are forgotten
+        let later = expires_at + Duration::seconds(1);
+        assert!(!throttle.challenge_exhausted("jti-1", later));
+        throttle.record_challenge_failure("jti-2", later + Duration::seconds(300), later);
+        assert_eq!(throttle.challenges.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_unknown_logins_are_counted_like_accounts() {
+        let throttle = LoginThrottle::new(config());
+        let now = Utc::now();
+
+        throttle.record_unknown_login_failure("nobody", now);
+        throttle.record_unknown_login_failure("nobody", now);
+        assert_eq!(throttle.unknown_login_failures("nobody", now), 2);
+        assert_eq!(throttle.unknown_login_failures("someone", now), 0);
+        // They don't count against the IP; that is recorded separately
+        assert_eq!(throttle.status("nobody", now), (0, None));
+
+        let much_later = now + Duration::seconds(301);
+        assert_eq!(throttle.unknown_login_failures("nobody", much_later), 0);
+    }
+
+    #[test]
+    fn test_unknown_login_names_are_normalised() {
+        let throttle = LoginThrottle::new(config());
+        let now = Utc::now();
+
+        throttle.record_unknown_login_failure("  Nobody ", now);
+        throttle.record_unknown_login_failure("NOBODY", now);
+        assert_eq!(throttle.unknown_login_failures("nobody", now), 2);
+
+        // Names past the length limit share the record of their first characters
+        let long = "x".repeat(MAX_LOGIN_KEY_LENGTH);
+        throttle.record_unknown_login_failure(&format!("{}a", long), now);
+        throttle.record_unknown_login_failure(&format!("{}b", long), now);
+        assert_eq!(throttle.unknown_login_failures(&long, now), 2);
+    }
+
+    #[test]
+    fn test_tracked_keys_are_capped() {
+        let mut throttle = LoginThrottle::new(config());
+        throttle.max_tracked_keys = 2;
+        let now = Utc::now();
+
+        throttle.record_unknown_login_failure("first", now);
+        throttle.record_unknown_login_failure("second", now);
+        throttle.record_unknown_login_failure("third", now);
+        assert_eq!(throttle.unknown_login_failures("third", now), 0);
+        // Names already tracked keep counting
+        throttle.record_unknown_login_failure("first", now);
+        assert_eq!(throttle.unknown_login_failures("first", now), 2);
+
+        // Stale records are only dropped once the prune interval has passed, which makes room
+        let much_later = now + Duration::seconds(301);
+        throttle.record_unknown_login_failure("third", much_later);
+        assert_eq!(throttle.unknown_login_failures("third", much_later), 1);
+        assert_eq!(throttle.unknown_logins.lock().unwrap().records.len(), 1);
+    }
+}
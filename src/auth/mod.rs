@@ -1,11 +1,33 @@
-use bcrypt::{hash, verify, DEFAULT_COST};
-use jsonwebtoken::{encode, EncodingKey, Header};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, decode_header, encode, Validation};
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 use chrono::{DateTime, Utc};
 use serde_json::Value;
+use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+pub mod api_keys;
+pub mod extractor;
+pub mod keys;
+pub mod lockout;
+pub mod oidc;
+pub mod one_time;
+pub mod password;
+pub mod password_policy;
+pub mod permissions;
+pub mod refresh;
+pub mod sessions;
+pub mod status;
+pub mod totp;
+
+pub use extractor::{AuthenticatedUser, Credential};
+pub use keys::JwtKeys;
+pub use password::PasswordHashers;
+pub use password_policy::PasswordPolicy;
+pub use permissions::{Permission, PermissionSet, Role};
+pub use status::UserStatus;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: i64,
     pub email: String,
@@ -13,80 +35,213 @@ pub struct User {
     pub created_at: DateTime<Utc>,
     pub avatar: Option<String>,
     pub tokens: Option<Value>,
-    pub status: String,
+    pub status: UserStatus,
     pub permissions: Option<Value>,
     pub last_login: Option<DateTime<Utc>>,
+    pub password_changed_at: Option<DateTime<Utc>>,
+    // Consecutive failed logins, reset by a successful login or an admin unlock
+    pub failed_login_attempts: i32,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl User {
+    pub fn permission_set(&self) -> PermissionSet {
+        PermissionSet::from_value(self.permissions.as_ref())
+    }
+
+    // Fails with `AppError::AccountInactive` explaining why, unless the account is active
+    pub fn ensure_active(&self) -> Result<(), crate::error::AppError> {
+        match self.status.inactive_reason() {
+            None => Ok(()),
+            Some(reason) => Err(crate::error::AppError::AccountInactive(reason.to_string())),
+        }
+    }
+
+    pub fn locked_until(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.locked_until.filter(|until| *until > now)
+    }
+
+    // Tokens issued before the password was last changed or reset are no longer accepted.
+    // `iat` only has second precision, so a token from the same second still counts.
+    pub fn token_predates_password_change(&self, claims: &Claims) -> bool {
+        self.password_changed_at
+            .is_some_and(|changed_at| (claims.iat as i64) < changed_at.timestamp())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
-    sub: String,
-    exp: usize,
+    pub sub: String,
+    pub exp: usize,
+    pub iat: usize,
+    pub nbf: usize,
+    pub iss: String,
+    pub aud: String,
+    pub jti: String,
+    // Login session the token belongs to; tokens issued before sessions were tracked have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    // Set on impersonation tokens: the admin acting as `sub`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
 }
 
-pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
-    hash(password, DEFAULT_COST)
+impl Claims {
+    // Id of the admin impersonating the user, for impersonation tokens
+    pub fn impersonator(&self) -> Option<i64> {
+        self.act.as_ref().and_then(|actor| actor.sub.parse().ok())
+    }
 }
 
-pub fn verify_password(password: &str, hash: &str) -> Result<bool, bcrypt::BcryptError> {
-    verify(password, hash)
+// The `act` claim of RFC 8693: who is actually making requests with the token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: String,
 }
 
-pub fn generate_token(user_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    let expiration = SystemTime::now()
+fn sign_token(
+    keys: &JwtKeys,
+    user_id: &str,
+    audience: String,
+    ttl_seconds: u64,
+    session_id: Option<Uuid>,
+    actor: Option<Actor>,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
-        .as_secs() + 24 * 3600; // 24 hours from now
+        .as_secs() as usize;
 
     let claims = Claims {
         sub: user_id.to_string(),
-        exp: expiration as usize,
+        exp: now + ttl_seconds as usize,
+        iat: now,
+        nbf: now,
+        iss: keys.issuer.clone(),
+        aud: audience,
+        jti: Uuid::new_v4().to_string(),
+        sid: session_id.map(|id| id.to_string()),
+        act: actor,
     };
 
-    let header = Header::default();
-    let key = EncodingKey::from_secret("your_secret_key".as_bytes());
+    encode(&keys.header(), &claims, keys.encoding_key())
+}
 
-    encode(&header, &claims, &key)
+pub fn generate_token(keys: &JwtKeys, user_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    sign_token(keys, user_id, keys.audience.clone(), keys.token_ttl_seconds, None, None)
+}
+
+// Access token of a login session; it stops being accepted once the session is revoked
+pub fn generate_session_token(keys: &JwtKeys, user_id: &str, session_id: Uuid) -> Result<String, jsonwebtoken::errors::Error> {
+    sign_token(keys, user_id, keys.audience.clone(), keys.token_ttl_seconds, Some(session_id), None)
+}
+
+// Short-lived access token for `user_id` carrying the admin `actor_id` in its `act` claim.
+// Its session can be revoked like any other, which ends the impersonation.
+pub fn generate_impersonation_token(
+    keys: &JwtKeys,
+    user_id: &str,
+    actor_id: i64,
+    session_id: Uuid,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let actor = Actor {
+        sub: actor_id.to_string(),
+    };
+    sign_token(keys, user_id, keys.audience.clone(), keys.impersonation_ttl_seconds, Some(session_id), Some(actor))
+}
+
+// Token proving the password step of a two-factor login; only `/api/login/mfa` accepts it
+pub fn generate_mfa_pending_token(keys: &JwtKeys, user_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    sign_token(keys, user_id, keys.mfa_pending_audience(), keys.mfa_pending_ttl_seconds, None, None)
+}
+
+// Verifies a token with the key its `kid` header names and the validation rules `validation` gives for that key
+fn verify_token(
+    keys: &JwtKeys,
+    token: &str,
+    validation: impl Fn(&keys::VerificationKey) -> Validation,
+) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let header = decode_header(token)?;
+    let key = keys.verification_key(header.kid.as_deref()).ok_or(ErrorKind::InvalidSignature)?;
+    decode::<Claims>(token, key.decoding_key(), &validation(key)).map(|data| data.claims)
+}
+
+pub fn decode_mfa_pending_token(keys: &JwtKeys, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+    verify_token(keys, token, |key| keys.mfa_pending_validation(key))
+}
+
+// Checks the token signature, expiry, issuer and audience and returns its claims
+pub fn decode_token(keys: &JwtKeys, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+    verify_token(keys, token, |key| keys.validation(key))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use jsonwebtoken::{decode, DecodingKey, Validation};
+    use super::keys::tests::{test_auth_config, test_keys};
+    use jsonwebtoken::{EncodingKey, Header};
 
-    #[test]
-    fn test_hash_password_success() {
-        let password = "test_password";
-        let hashed_result = hash_password(password);
-        assert!(hashed_result.is_ok());
-        let hashed_password = hashed_result.unwrap();
-        assert!(!hashed_password.is_empty());
-        assert_ne!(password, hashed_password); // Hash should not be the same as password
+    fn now() -> usize {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as usize
+    }
+
+    fn claims_for(keys: &JwtKeys, sub: &str) -> Claims {
+        Claims {
+            sub: sub.to_string(),
+            exp: now() + 3600,
+            iat: now(),
+            nbf: now(),
+            iss: keys.issuer.clone(),
+            aud: keys.audience.clone(),
+            jti: "test-jti".to_string(),
+            sid: None,
+            act: None,
+        }
     }
 
     #[test]
-    fn test_verify_password_correct() {
-        let password = "test_password123";
-        let hashed_password = hash_password(password).expect("Failed to hash password");
-        let verification_result = verify_password(password, &hashed_password);
-        assert!(verification_result.is_ok());
-        assert!(verification_result.unwrap());
+    fn test_mfa_pending_token_is_not_an_access_token() {
+        let keys = test_keys();
+        let pending = generate_mfa_pending_token(&keys, "7").unwrap();
+        assert_eq!(decode_mfa_pending_token(&keys, &pending).unwrap().sub, "7");
+        assert!(decode_token(&keys, &pending).is_err());
+
+        let access = generate_token(&keys, "7").unwrap();
+        assert!(decode_mfa_pending_token(&keys, &access).is_err());
     }
 
     #[test]
-    fn test_verify_password_incorrect() {
-        let password = "test_password456";
-        let wrong_password = "wrong_password789";
-        let hashed_password = hash_password(password).expect("Failed to hash password");
-        let verification_result = verify_password(wrong_password, &hashed_password);
-        assert!(verification_result.is_ok());
-        assert!(!verification_result.unwrap());
+    fn test_token_predates_password_change() {
+        let keys = test_keys();
+        let claims = claims_for(&keys, "1");
+        let mut user = User {
+            id: 1,
+            email: "alice@example.com".to_string(),
+            username: "alice".to_string(),
+            created_at: Utc::now(),
+            avatar: None,
+            tokens: None,
+            status: UserStatus::Active,
+            permissions: None,
+            last_login: None,
+            password_changed_at: None,
+            failed_login_attempts: 0,
+            locked_until: None,
+        };
+        assert!(!user.token_predates_password_change(&claims));
+
+        user.password_changed_at = Some(Utc::now() + chrono::Duration::seconds(5));
+        assert!(user.token_predates_password_change(&claims));
+
+        // Same second as the token: still accepted
+        user.password_changed_at = DateTime::from_timestamp(claims.iat as i64, 999_000_000);
+        assert!(!user.token_predates_password_change(&claims));
     }
 
     #[test]
     fn test_generate_token_success() {
         let user_id = "user123";
-        let token_result = generate_token(user_id);
+        let token_result = generate_token(&test_keys(), user_id);
         assert!(token_result.is_ok());
         let token = token_result.unwrap();
         assert!(!token.is_empty());
@@ -95,40 +250,157 @@ mod tests {
     #[test]
     fn test_generate_token_payload_and_decode() {
         let user_id = "test_user_sub";
-        let token_result = generate_token(user_id);
+        let keys = test_keys();
+        let token_result = generate_token(&keys, user_id);
         assert!(token_result.is_ok());
         let token = token_result.unwrap();
 
+        assert_eq!(decode_header(&token).unwrap().kid.as_deref(), Some(keys.kid()));
+
         // Decode the token to verify its contents
-        let decoding_key = DecodingKey::from_secret("your_secret_key".as_bytes());
-        let mut validation = Validation::default();
-        // In a real scenario, you might want to validate 'exp', but for this test,
-        // we're primarily concerned with the 'sub' claim.
-        // If the token is generated with default leeway, this should pass.
-        // Disabling time validation for simplicity if it causes flaky tests due to exact timing.
-        validation.validate_exp = true;
-        // If tests are flaky due to timing, consider adding leeway or disabling exp validation for this specific test
-        // validation.leeway = 5; // Allow 5 seconds leeway for expiration
-
-        let decoded_token = decode::<Claims>(&token, &decoding_key, &validation);
-        assert!(decoded_token.is_ok());
-        let claims = decoded_token.unwrap().claims;
+        let claims = decode_token(&keys, &token).expect("Failed to decode token");
         assert_eq!(claims.sub, user_id);
+        assert_eq!(claims.iss, "test_issuer");
+        assert_eq!(claims.aud, "test_audience");
+        assert!(!claims.jti.is_empty());
+        assert!(claims.nbf <= claims.iat);
+
+        // Check that expiration matches the configured lifetime (roughly)
+        let current_timestamp = now();
+        assert!(claims.exp > current_timestamp);
+        assert!(claims.exp <= current_timestamp + 3600);
+    }
 
-        // Check that expiration is in the future (roughly)
-        let current_timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        assert!(claims.exp > current_timestamp as usize);
+    #[test]
+    fn test_generate_token_unique_jti() {
+        let keys = test_keys();
+        let first = decode_token(&keys, &generate_token(&keys, "1").unwrap()).unwrap();
+        let second = decode_token(&keys, &generate_token(&keys, "1").unwrap()).unwrap();
+        assert_ne!(first.jti, second.jti);
+    }
+
+    #[test]
+    fn test_session_token_carries_session_id() {
+        let keys = test_keys();
+        let session_id = Uuid::new_v4();
+        let claims = decode_token(&keys, &generate_session_token(&keys, "1", session_id).unwrap()).unwrap();
+        assert_eq!(claims.sid, Some(session_id.to_string()));
+        assert_eq!(decode_token(&keys, &generate_token(&keys, "1").unwrap()).unwrap().sid, None);
+    }
+
+    #[test]
+    fn test_impersonation_token_names_the_actor() {
+        let keys = test_keys();
+        let session_id = Uuid::new_v4();
+        let claims = decode_token(&keys, &generate_impersonation_token(&keys, "5", 1, session_id).unwrap()).unwrap();
+        assert_eq!(claims.sub, "5");
+        assert_eq!(claims.impersonator(), Some(1));
+        assert_eq!(claims.sid, Some(session_id.to_string()));
+        assert!(claims.exp <= now() + keys.impersonation_ttl_seconds as usize);
+        assert_eq!(decode_token(&keys, &generate_token(&keys, "5").unwrap()).unwrap().impersonator(), None);
+    }
+
+    #[test]
+    fn test_decode_token_round_trip() {
+        let keys = test_keys();
+        let token = generate_token(&keys, "42").expect("Failed to generate token");
+        let claims = decode_token(&keys, &token).expect("Failed to decode token");
+        assert_eq!(claims.sub, "42");
     }
 
     #[test]
-    fn test_verify_password_with_invalid_hash_format() {
-        let password = "test_password";
-        let invalid_hash = "not_a_real_hash";
-        let verification_result = verify_password(password, invalid_hash);
-        // bcrypt verify function returns an error for malformed hashes
-        assert!(verification_result.is_err());
-    }
-}
\ No newline at end of file
+    fn test_decode_token_rejects_expired_token() {
+        let keys = test_keys();
+        let mut claims = claims_for(&keys, "42");
+        claims.exp = 1; // Long in the past
+        let token = encode(&Header::default(), &claims, keys.encoding_key()).unwrap();
+        assert!(decode_token(&keys, &token).is_err());
+    }
+
+    #[test]
+    fn test_decode_token_rejects_not_yet_valid_token() {
+        let keys = test_keys();
+        let mut claims = claims_for(&keys, "42");
+        claims.nbf = now() + 600;
+        let token = encode(&Header::default(), &claims, keys.encoding_key()).unwrap();
+        assert!(decode_token(&keys, &token).is_err());
+    }
+
+    #[test]
+    fn test_decode_token_rejects_wrong_issuer_or_audience() {
+        let keys = test_keys();
+
+        let mut claims = claims_for(&keys, "42");
+        claims.iss = "someone_else".to_string();
+        let token = encode(&Header::default(), &claims, keys.encoding_key()).unwrap();
+        assert!(decode_token(&keys, &token).is_err());
+
+        let mut claims = claims_for(&keys, "42");
+        claims.aud = "another_service".to_string();
+        let token = encode(&Header::default(), &claims, keys.encoding_key()).unwrap();
+        assert!(decode_token(&keys, &token).is_err());
+    }
+
+    #[test]
+    fn test_tokens_with_asymmetric_algorithms() {
+        let fixture = |name: &str| Some(format!("{}/tests/fixtures/jwt/{}", env!("CARGO_MANIFEST_DIR"), name));
+        for (algorithm, private, public) in [
+            (crate::config::JwtAlgorithm::Rs256, "rs256_private.pem", "rs256_public.pem"),
+            (crate::config::JwtAlgorithm::EdDsa, "ed25519_private.pem", "ed25519_public.pem"),
+        ] {
+            let mut config = test_auth_config();
+            config.algorithm = algorithm;
+            config.private_key_file = fixture(private);
+            config.public_key_file = fixture(public);
+            let keys = JwtKeys::from_config(&config).unwrap();
+
+            let token = generate_token(&keys, "5").unwrap();
+            let claims = decode_token(&keys, &token).expect("Token should verify with the public key");
+            assert_eq!(claims.sub, "5");
+        }
+    }
+
+    #[test]
+    fn test_decode_token_rejects_wrong_signature() {
+        let keys = test_keys();
+        let claims = claims_for(&keys, "42");
+        let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"some_other_secret")).unwrap();
+        assert!(decode_token(&keys, &token).is_err());
+    }
+
+    #[test]
+    fn test_tokens_of_rotated_keys_stay_valid() {
+        let old_keys = test_keys();
+        let old_token = generate_token(&old_keys, "9").unwrap();
+
+        let mut config = test_auth_config();
+        config.kid = "next".to_string();
+        config.secret = Some("next_secret".to_string());
+        config.verification_keys.push(crate::config::VerificationKeyConfig {
+            kid: "test".to_string(),
+            algorithm: crate::config::JwtAlgorithm::Hs256,
+            secret: Some("unit_test_secret".to_string()),
+            secret_file: None,
+            public_key_file: None,
+        });
+        let keys = JwtKeys::from_config(&config).unwrap();
+
+        assert_eq!(decode_token(&keys, &old_token).unwrap().sub, "9");
+        let new_token = generate_token(&keys, "9").unwrap();
+        assert_eq!(decode_header(&new_token).unwrap().kid.as_deref(), Some("next"));
+        assert!(decode_token(&keys, &new_token).is_ok());
+        // The old ring doesn't know the new key
+        assert!(decode_token(&old_keys, &new_token).is_err());
+    }
+
+    #[test]
+    fn test_decode_token_rejects_unknown_kid() {
+        let keys = test_keys();
+        let header = Header {
+            kid: Some("retired".to_string()),
+            ..Default::default()
+        };
+        let token = encode(&header, &claims_for(&keys, "42"), keys.encoding_key()).unwrap();
+        assert!(decode_token(&keys, &token).is_err());
+    }
+}
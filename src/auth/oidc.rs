@@ -0,0 +1,322 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+use thiserror::Error;
+
+use super::refresh;
+use crate::config::{OidcConfig, OidcProviderConfig};
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum OidcError {
+    #[error("Request to the provider failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Invalid provider metadata: {0}")]
+    Metadata(String),
+    #[error("Invalid ID token: {0}")]
+    InvalidIdToken(#[from] jsonwebtoken::errors::Error),
+    #[error("ID token signed with an unknown key")]
+    UnknownKey,
+    #[error("ID token signed with unsupported algorithm {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+    #[error("ID token nonce doesn't match the authorization request")]
+    NonceMismatch,
+}
+
+// The parts of the discovery document the authorization code flow needs
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// Claims of a validated ID token. `iss`, `aud` and `exp` have been checked already.
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+}
+
+// Random value for the `state` and `nonce` parameters and the PKCE code verifier
+pub fn generate_random_value() -> String {
+    refresh::generate_refresh_token()
+}
+
+// PKCE `S256` code challenge for a code verifier (RFC 7636)
+pub fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn trim_issuer(issuer: &str) -> &str {
+    issuer.trim_end_matches('/')
+}
+
+// One configured provider, with its discovery document and signing keys cached after first use
+pub struct OidcProvider {
+    config: OidcProviderConfig,
+    http: reqwest::Client,
+    metadata: RwLock<Option<ProviderMetadata>>,
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+impl OidcProvider {
+    pub fn new(config: OidcProviderConfig) -> Self {
+        OidcProvider {
+            config,
+            http: reqwest::Client::builder()
+                .timeout(HTTP_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            metadata: RwLock::new(None),
+            jwks: RwLock::new(None),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub async fn metadata(&self) -> Result<ProviderMetadata, OidcError> {
+        if let Some(metadata) = self.metadata.read().unwrap().clone() {
+            return Ok(metadata);
+        }
+
+        let url = format!("{}/.well-known/openid-configuration", trim_issuer(&self.config.issuer));
+        let metadata: ProviderMetadata = self.http.get(&url).send().await?.error_for_status()?.json().await?;
+        // A discovery document for another issuer would let that issuer's tokens in
+        if trim_issuer(&metadata.issuer) != trim_issuer(&self.config.issuer) {
+            return Err(OidcError::Metadata(format!(
+                "issuer {} doesn't match the configured issuer {}",
+                metadata.issuer, self.config.issuer
+            )));
+        }
+
+        *self.metadata.write().unwrap() = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    // URL of the provider's login page for a new authorization request
+    pub async fn authorization_url(&self, state: &str, nonce: &str, code_verifier: &str) -> Result<String, OidcError> {
+        let metadata = self.metadata().await?;
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.config.redirect_uri.as_str()),
+                ("scope", self.config.scopes.join(" ").as_str()),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", code_challenge(code_verifier).as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| OidcError::Metadata(format!("invalid authorization_endpoint: {}", e)))?;
+        Ok(url.into())
+    }
+
+    // Exchanges an authorization code for an ID token and validates it against the
+    // nonce of the authorization request
+    pub async fn authenticate(&self, code: &str, code_verifier: &str, nonce: &str) -> Result<IdTokenClaims, OidcError> {
+        let metadata = self.metadata().await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_uri.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+        let response: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let claims = self.validate_id_token(&metadata, &response.id_token).await?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(OidcError::NonceMismatch);
+        }
+        Ok(claims)
+    }
+
+    async fn validate_id_token(&self, metadata: &ProviderMetadata, id_token: &str) -> Result<IdTokenClaims, OidcError> {
+        let header = decode_header(id_token)?;
+        // Only signatures made with the provider's published keys count; shared secrets
+        // would let anyone holding the client secret forge tokens
+        if !matches!(
+            header.alg,
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::ES256 | Algorithm::ES384 | Algorithm::EdDSA
+        ) {
+            return Err(OidcError::UnsupportedAlgorithm(header.alg));
+        }
+
+        let key = self.decoding_key(metadata, header.kid.as_deref()).await?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        Ok(decode::<IdTokenClaims>(id_token, &key, &validation)?.claims)
+    }
+
+    // The provider key with the given id. Keys are fetched again once when the id is
+    // unknown, since the provider may have rotated them.
+    async fn decoding_key(&self, metadata: &ProviderMetadata, kid: Option<&str>) -> Result<DecodingKey, OidcError> {
+        let find = |jwks: &JwkSet| match kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            // Without a key id only a provider with a single key is unambiguous
+            None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+            None => None,
+        };
+
+        let cached = self.jwks.read().unwrap().as_ref().and_then(find);
+        let jwk = match cached {
+            Some(jwk) => jwk,
+            None => {
+                let jwks: JwkSet = self.http.get(&metadata.jwks_uri).send().await?.error_for_status()?.json().await?;
+                let jwk = find(&jwks);
+                *self.jwks.write().unwrap() = Some(jwks);
+                jwk.ok_or(OidcError::UnknownKey)?
+            }
+        };
+        Ok(DecodingKey::from_jwk(&jwk)?)
+    }
+}
+
+// All configured providers by name, registered as app data
+pub struct OidcProviders {
+    providers: HashMap<String, OidcProvider>,
+}
+
+impl OidcProviders {
+    pub fn from_config(config: &OidcConfig) -> Self {
+        OidcProviders {
+            providers: config
+                .providers
+                .iter()
+                .map(|provider| (provider.name.clone(), OidcProvider::new(provider.clone())))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OidcProvider> {
+        self.providers.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockOidcIssuer;
+    use serde_json::json;
+
+    fn provider_for(issuer: &MockOidcIssuer) -> OidcProvider {
+        OidcProvider::new(OidcProviderConfig {
+            name: "mock".to_string(),
+            issuer: issuer.issuer.clone(),
+            client_id: issuer.client_id.clone(),
+            client_secret: None,
+            redirect_uri: "http://localhost:3000/oidc/callback".to_string(),
+            scopes: vec!["openid".to_string(), "email".to_string()],
+        })
+    }
+
+    #[test]
+    fn test_code_challenge_matches_rfc_7636_example() {
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_authorization_code_flow_with_mock_issuer() {
+        let issuer = MockOidcIssuer::start("test-client").await;
+        let provider = provider_for(&issuer);
+
+        let (state, nonce, verifier) = (generate_random_value(), generate_random_value(), generate_random_value());
+        let url = provider.authorization_url(&state, &nonce, &verifier).await.unwrap();
+        assert!(url.starts_with(&format!("{}/authorize?", issuer.issuer)));
+        assert!(url.contains("code_challenge_method=S256"));
+
+        let (code, returned_state) = issuer.authorize(&url, json!({"sub": "alice-1", "email": "alice@example.com", "email_verified": true}));
+        assert_eq!(returned_state, state);
+
+        let claims = provider.authenticate(&code, &verifier, &nonce).await.unwrap();
+        assert_eq!(claims.sub, "alice-1");
+        assert_eq!(claims.iss, issuer.issuer);
+        assert_eq!(claims.email.as_deref(), Some("alice@example.com"));
+        assert!(claims.email_verified);
+
+        // Codes are single-use
+        assert!(matches!(provider.authenticate(&code, &verifier, &nonce).await, Err(OidcError::Http(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_wrong_code_verifier_is_rejected_by_issuer() {
+        let issuer = MockOidcIssuer::start("test-client").await;
+        let provider = provider_for(&issuer);
+
+        let url = provider.authorization_url("state", "nonce", &generate_random_value()).await.unwrap();
+        let (code, _) = issuer.authorize(&url, json!({"sub": "alice-1"}));
+        assert!(provider.authenticate(&code, &generate_random_value(), "nonce").await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_nonce_and_audience_are_checked() {
+        let issuer = MockOidcIssuer::start("test-client").await;
+        let provider = provider_for(&issuer);
+        let verifier = generate_random_value();
+
+        let url = provider.authorization_url("state", "nonce", &verifier).await.unwrap();
+        let (code, _) = issuer.authorize(&url, json!({"sub": "alice-1"}));
+        assert!(matches!(
+            provider.authenticate(&code, &verifier, "another nonce").await,
+            Err(OidcError::NonceMismatch)
+        ));
+
+        let (code, _) = issuer.authorize(&url, json!({"sub": "alice-1", "aud": "another-client"}));
+        assert!(matches!(
+            provider.authenticate(&code, &verifier, "nonce").await,
+            Err(OidcError::InvalidIdToken(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_discovery_for_another_issuer_is_rejected() {
+        let issuer = MockOidcIssuer::start("test-client").await;
+        let mut provider = provider_for(&issuer);
+        provider.config.issuer = format!("{}/", issuer.issuer);
+        assert!(provider.metadata().await.is_ok(), "a trailing slash is the same issuer");
+
+        let mut provider = provider_for(&issuer);
+        provider.config.issuer = issuer.issuer.replace("127.0.0.1", "localhost");
+        assert!(matches!(provider.metadata().await, Err(OidcError::Metadata(_))));
+    }
+}
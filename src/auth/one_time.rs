@@ -0,0 +1,30 @@
+use super::refresh;
+
+// What a single-use emailed token is good for, stored in `one_time_tokens.purpose`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenPurpose {
+    EmailVerification,
+    // Confirms a new address for an existing account; sent to the new address
+    EmailChange,
+    PasswordReset,
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::EmailVerification => "email_verification",
+            TokenPurpose::EmailChange => "email_change",
+            TokenPurpose::PasswordReset => "password_reset",
+        }
+    }
+}
+
+// One-time tokens share the random format and SHA-256 storage of refresh tokens:
+// only the hash is kept, so a database leak doesn't expose usable links.
+pub fn generate_one_time_token() -> String {
+    refresh::generate_refresh_token()
+}
+
+pub fn hash_one_time_token(token: &str) -> String {
+    refresh::hash_refresh_token(token)
+}
@@ -0,0 +1,291 @@
+use actix_web::web;
+use argon2::password_hash::{self, PasswordHash, SaltString};
+use argon2::{Algorithm, Argon2, Params, PasswordVerifier, Version};
+use rand::RngCore;
+use std::sync::Arc;
+use thiserror::Error;
+use crate::config::{PasswordAlgorithm, PasswordHashConfig};
+
+#[derive(Debug, Error)]
+#[error("Password hashing failed: {0}")]
+pub struct PasswordHashError(String);
+
+// Algorithm a stored hash was made with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashScheme {
+    Bcrypt,
+    Argon2id,
+}
+
+impl HashScheme {
+    // Identifies a stored hash by its modular crypt (`$2b$...`) or PHC (`$argon2id$...`) prefix
+    pub fn identify(hash: &str) -> Option<HashScheme> {
+        if hash.starts_with("$argon2id$") {
+            Some(HashScheme::Argon2id)
+        } else if ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix)) {
+            Some(HashScheme::Bcrypt)
+        } else {
+            None
+        }
+    }
+}
+
+pub trait PasswordHasher: Send + Sync {
+    fn hash(&self, password: &str) -> Result<String, PasswordHashError>;
+    // Errors only if `hash` is malformed; a wrong password is `Ok(false)`
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordHashError>;
+    // Whether a hash of this hasher's scheme was made with weaker parameters than it uses now
+    fn is_weaker(&self, hash: &str) -> bool;
+}
+
+pub struct BcryptHasher {
+    cost: u32,
+}
+
+impl BcryptHasher {
+    pub fn new(cost: u32) -> Self {
+        BcryptHasher { cost }
+    }
+}
+
+impl PasswordHasher for BcryptHasher {
+    fn hash(&self, password: &str) -> Result<String, PasswordHashError> {
+        bcrypt::hash(password, self.cost).map_err(|e| PasswordHashError(e.to_string()))
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordHashError> {
+        bcrypt::verify(password, hash).map_err(|e| PasswordHashError(e.to_string()))
+    }
+
+    fn is_weaker(&self, hash: &str) -> bool {
+        // `$2b$12$...`: the cost sits between the second and third `$`
+        let cost = hash.get(4..6).and_then(|cost| cost.parse::<u32>().ok());
+        cost.is_none_or(|cost| cost < self.cost)
+    }
+}
+
+pub struct Argon2idHasher {
+    params: Params,
+}
+
+impl Argon2idHasher {
+    pub fn new(memory_kib: u32, time_cost: u32, parallelism: u32) -> Result<Self, PasswordHashError> {
+        let params = Params::new(memory_kib, time_cost, parallelism, None).map_err(|e| PasswordHashError(e.to_string()))?;
+        Ok(Argon2idHasher { params })
+    }
+}
+
+impl PasswordHasher for Argon2idHasher {
+    fn hash(&self, password: &str) -> Result<String, PasswordHashError> {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let salt = SaltString::encode_b64(&salt).map_err(|e| PasswordHashError(e.to_string()))?;
+
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone());
+        argon2::PasswordHasher::hash_password(&argon2, password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| PasswordHashError(e.to_string()))
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordHashError> {
+        let parsed = PasswordHash::new(hash).map_err(|e| PasswordHashError(e.to_string()))?;
+        // The parameters are read from the stored hash, so older hashes keep verifying
+        match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(PasswordHashError(e.to_string())),
+        }
+    }
+
+    fn is_weaker(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return true;
+        };
+        let Ok(params) = Params::try_from(&parsed) else {
+            return true;
+        };
+        parsed.version.is_none_or(|version| version < Version::V0x13 as u32)
+            || params.m_cost() < self.params.m_cost()
+            || params.t_cost() < self.params.t_cost()
+            || params.p_cost() < self.params.p_cost()
+    }
+}
+
+// Hashes new passwords with the configured algorithm and verifies stored hashes of
+// either scheme, so accounts keep working while their hashes are upgraded on login.
+// Both are slow on purpose, so handlers use `hash_password` and `verify_password`, which
+// run them on the blocking thread pool instead of holding up the async workers.
+pub struct PasswordHashers {
+    current: HashScheme,
+    bcrypt: BcryptHasher,
+    argon2id: Argon2idHasher,
+    dummy_hash: String,
+}
+
+impl PasswordHashers {
+    pub fn from_config(config: &PasswordHashConfig) -> Result<Self, PasswordHashError> {
+        let mut hashers = PasswordHashers {
+            current: match config.algorithm {
+                PasswordAlgorithm::Argon2id => HashScheme::Argon2id,
+                PasswordAlgorithm::Bcrypt => HashScheme::Bcrypt,
+            },
+            bcrypt: BcryptHasher::new(config.bcrypt_cost),
+            argon2id: Argon2idHasher::new(config.argon2_memory_kib, config.argon2_time_cost, config.argon2_parallelism)?,
+            dummy_hash: String::new(),
+        };
+        // Made at startup so no request has to wait for it
+        hashers.dummy_hash = hashers.hash("dummy password used for timing equalisation")?;
+        Ok(hashers)
+    }
+
+    fn hasher(&self, scheme: HashScheme) -> &dyn PasswordHasher {
+        match scheme {
+            HashScheme::Bcrypt => &self.bcrypt,
+            HashScheme::Argon2id => &self.argon2id,
+        }
+    }
+
+    fn hash(&self, password: &str) -> Result<String, PasswordHashError> {
+        self.hasher(self.current).hash(password)
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordHashError> {
+        let scheme = HashScheme::identify(hash).ok_or_else(|| PasswordHashError("Unrecognised password hash".to_string()))?;
+        self.hasher(scheme).verify(password, hash)
+    }
+
+    pub async fn hash_password(self: &Arc<Self>, password: &str) -> Result<String, PasswordHashError> {
+        let hashers = Arc::clone(self);
+        let password = password.to_string();
+        web::block(move || hashers.hash(&password))
+            .await
+            .map_err(|e| PasswordHashError(e.to_string()))?
+    }
+
+    pub async fn verify_password(self: &Arc<Self>, password: &str, hash: &str) -> Result<bool, PasswordHashError> {
+        let hashers = Arc::clone(self);
+        let (password, hash) = (password.to_string(), hash.to_string());
+        web::block(move || hashers.verify(&password, &hash))
+            .await
+            .map_err(|e| PasswordHashError(e.to_string()))?
+    }
+
+    // Whether a stored hash should be replaced after a successful login: bcrypt hashes when
+    // Argon2id is configured, and hashes made with weaker parameters than configured.
+    // Argon2id hashes are kept even if bcrypt is configured.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        match HashScheme::identify(hash) {
+            Some(scheme) if scheme == self.current => self.hasher(scheme).is_weaker(hash),
+            Some(HashScheme::Bcrypt) => true,
+            Some(HashScheme::Argon2id) | None => false,
+        }
+    }
+
+    // Hash verified against when a login names an unknown user, so that the
+    // response takes about as long as a wrong password for an existing account.
+    pub fn dummy_hash(&self) -> &str {
+        &self.dummy_hash
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn test_config(algorithm: PasswordAlgorithm) -> PasswordHashConfig {
+        // Cheap parameters; real ones make debug builds of the tests very slow
+        PasswordHashConfig {
+            algorithm,
+            argon2_memory_kib: 1024,
+            argon2_time_cost: 2,
+            argon2_parallelism: 1,
+            bcrypt_cost: 4,
+        }
+    }
+
+    pub(crate) fn test_hashers() -> PasswordHashers {
+        PasswordHashers::from_config(&test_config(PasswordAlgorithm::Argon2id)).unwrap()
+    }
+
+    #[test]
+    fn test_identify_hash_scheme() {
+        assert_eq!(HashScheme::identify("$2b$12$abcdefghijklmnopqrstuv"), Some(HashScheme::Bcrypt));
+        assert_eq!(HashScheme::identify("$2y$10$abcdefghijklmnopqrstuv"), Some(HashScheme::Bcrypt));
+        assert_eq!(HashScheme::identify("$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA"), Some(HashScheme::Argon2id));
+        assert_eq!(HashScheme::identify("$argon2i$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA"), None);
+        assert_eq!(HashScheme::identify("not_a_real_hash"), None);
+    }
+
+    #[test]
+    fn test_hash_and_verify_with_each_algorithm() {
+        for algorithm in [PasswordAlgorithm::Argon2id, PasswordAlgorithm::Bcrypt] {
+            let hashers = PasswordHashers::from_config(&test_config(algorithm)).unwrap();
+            let hash = hashers.hash("test_password123").expect("Failed to hash password");
+            assert_ne!(hash, "test_password123");
+            assert!(hashers.verify("test_password123", &hash).unwrap());
+            assert!(!hashers.verify("wrong_password789", &hash).unwrap());
+            assert!(!hashers.needs_rehash(&hash));
+        }
+    }
+
+    #[test]
+    fn test_argon2id_hashes_use_configured_parameters() {
+        let hash = test_hashers().hash("test_password").unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=1024,t=2,p=1$"), "unexpected hash {}", hash);
+    }
+
+    #[test]
+    fn test_bcrypt_hashes_are_upgraded_to_argon2id() {
+        let bcrypt = PasswordHashers::from_config(&test_config(PasswordAlgorithm::Bcrypt)).unwrap();
+        let old_hash = bcrypt.hash("test_password").unwrap();
+
+        let hashers = test_hashers();
+        assert!(hashers.verify("test_password", &old_hash).unwrap());
+        assert!(hashers.needs_rehash(&old_hash));
+
+        // Argon2id is never downgraded when bcrypt is configured
+        let new_hash = hashers.hash("test_password").unwrap();
+        assert!(!bcrypt.needs_rehash(&new_hash));
+    }
+
+    #[test]
+    fn test_weaker_parameters_need_rehash() {
+        let weak = PasswordHashers::from_config(&PasswordHashConfig {
+            argon2_memory_kib: 512,
+            bcrypt_cost: 4,
+            ..test_config(PasswordAlgorithm::Argon2id)
+        })
+        .unwrap();
+        let weak_hash = weak.hash("test_password").unwrap();
+        assert!(test_hashers().needs_rehash(&weak_hash));
+
+        let bcrypt_cost_5 = BcryptHasher::new(5);
+        assert!(bcrypt_cost_5.is_weaker(&BcryptHasher::new(4).hash("test_password").unwrap()));
+        assert!(!BcryptHasher::new(4).is_weaker(&bcrypt_cost_5.hash("test_password").unwrap()));
+    }
+
+    #[test]
+    fn test_dummy_hash_uses_current_algorithm() {
+        let hashers = test_hashers();
+        let dummy = hashers.dummy_hash();
+        assert_eq!(HashScheme::identify(dummy), Some(HashScheme::Argon2id));
+        // Verifying against it must succeed without error so it costs as much as a real check
+        assert!(!hashers.verify("some_login_attempt", dummy).unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_hash_and_verify_on_the_blocking_pool() {
+        let hashers = Arc::new(test_hashers());
+        let hash = hashers.hash_password("test_password").await.unwrap();
+        assert!(hashers.verify_password("test_password", &hash).await.unwrap());
+        assert!(!hashers.verify_password("wrong_password", &hash).await.unwrap());
+        assert!(hashers.verify_password("test_password", "not_a_real_hash").await.is_err());
+    }
+
+    #[test]
+    fn test_verify_password_with_invalid_hash_format() {
+        let hashers = test_hashers();
+        assert!(hashers.verify("test_password", "not_a_real_hash").is_err());
+        assert!(hashers.verify("test_password", "$argon2id$v=19$m=1024,t=2,p=1$not base64!$hash").is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use crate::config::PasswordPolicyConfig;
+use crate::error::FieldError;
+
+// Parts of an email local part or username shorter than this are too common to reject
+const MIN_PERSONAL_INFO_LENGTH: usize = 3;
+
+pub struct PasswordPolicy {
+    config: PasswordPolicyConfig,
+    breached: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    // Reads the breached password list, if one is configured
+    pub fn from_config(config: &PasswordPolicyConfig) -> io::Result<Self> {
+        let breached = match &config.breached_passwords_file {
+            Some(path) => fs::read_to_string(path)?
+                .lines()
+                .map(str::trim_end)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            None => HashSet::new(),
+        };
+        Ok(PasswordPolicy {
+            config: config.clone(),
+            breached,
+        })
+    }
+
+    // Checks a new password and returns every rule it breaks. `personal_info` holds the
+    // username and email address of the account, as far as they are known.
+    pub fn check(&self, password: &str, personal_info: &[&str]) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+
+        let length = password.chars().count();
+        if length < self.config.min_length {
+            errors.push(FieldError::new(
+                "password",
+                "too_short",
+                format!("Password must be at least {} characters long", self.config.min_length),
+            ));
+        }
+        if password.len() > self.config.max_bytes {
+            errors.push(FieldError::new(
+                "password",
+                "too_long",
+                format!("Password must be at most {} bytes long", self.config.max_bytes),
+            ));
+        }
+
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|present| **present).count() < self.config.min_character_classes {
+            errors.push(FieldError::new(
+                "password",
+                "too_few_character_classes",
+                format!(
+                    "Password must contain at least {} of: lowercase letters, uppercase letters, digits, other characters",
+                    self.config.min_character_classes
+                ),
+            ));
+        }
+
+        let lowercase = password.to_lowercase();
+        let contains_personal_info = personal_info
+            .iter()
+            .map(|info| info.split('@').next().unwrap_or(info).to_lowercase())
+            .any(|part| part.chars().count() >= MIN_PERSONAL_INFO_LENGTH && lowercase.contains(&part));
+        if contains_personal_info {
+            errors.push(FieldError::new(
+                "password",
+                "contains_personal_info",
+                "Password must not contain your username or email address",
+            ));
+        }
+
+        if self.breached.contains(password) {
+            errors.push(FieldError::new(
+                "password",
+                "breached",
+                "Password appears in a list of leaked passwords",
+            ));
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_policy() -> PasswordPolicy {
+        PasswordPolicy {
+            config: PasswordPolicyConfig {
+                min_length: 10,
+                max_bytes: 72,
+                min_character_classes: 3,
+                breached_passwords_file: None,
+            },
+            breached: ["Password123!".to_string()].into_iter().collect(),
+        }
+    }
+
+    fn codes(errors: Vec<FieldError>) -> Vec<String> {
+        errors.into_iter().map(|error| error.code).collect()
+    }
+
+    #[test]
+    fn test_strong_password_passes() {
+        assert!(test_policy().check("correct Horse 7 battery", &["alice", "alice@example.com"]).is_empty());
+    }
+
+    #[test]
+    fn test_length_limits() {
+        let policy = test_policy();
+        assert_eq!(codes(policy.check("", &[])), vec!["too_short", "too_few_character_classes"]);
+        assert_eq!(codes(policy.check("Short1!", &[])), vec!["too_short"]);
+        // Multi-byte characters count once towards the minimum but fully towards the byte limit
+        assert_eq!(codes(policy.check(&"Aé1".repeat(20), &[])), vec!["too_long"]);
+    }
+
+    #[test]
+    fn test_character_classes() {
+        let policy = test_policy();
+        assert_eq!(codes(policy.check("onlylowercaseletters", &[])), vec!["too_few_character_classes"]);
+        assert!(policy.check("lowercase and digits 42", &[]).is_empty());
+    }
+
+    #[test]
+    fn test_rejects_username_and_email_local_part() {
+        let policy = test_policy();
+        assert_eq!(
+            codes(policy.check("My name is Alice_1984", &["alice", "someone@example.com"])),
+            vec!["contains_personal_info"]
+        );
+        assert_eq!(
+            codes(policy.check("Welcome, Someone 1!", &["alice", "someone@example.com"])),
+            vec!["contains_personal_info"]
+        );
+        // Very short identifiers are ignored
+        assert!(policy.check("Aloha Bob 12345", &["al", "b@example.com"]).is_empty());
+    }
+
+    #[test]
+    fn test_rejects_breached_passwords() {
+        assert_eq!(codes(test_policy().check("Password123!", &[])), vec!["breached"]);
+    }
+
+    #[test]
+    fn test_breached_password_file_is_loaded() {
+        let path = std::env::temp_dir().join(format!("breached-{}.txt", uuid::Uuid::new_v4()));
+        fs::write(&path, "Tr0ub4dor&3x\n\nletmein-Please-1\n").unwrap();
+
+        let policy = PasswordPolicy::from_config(&PasswordPolicyConfig {
+            breached_passwords_file: Some(path.to_string_lossy().into_owned()),
+            ..test_policy().config
+        })
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(codes(policy.check("letmein-Please-1", &[])), vec!["breached"]);
+        assert!(policy.check("Tr0ub4dor&3y", &[]).is_empty());
+    }
+}
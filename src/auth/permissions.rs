@@ -0,0 +1,251 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+// Individual capabilities checked by route guards
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    StatsRead,
+    SystemRead,
+    UsersRead,
+    UsersWrite,
+    AuditRead,
+    UsersImpersonate,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 6] = [
+        Permission::StatsRead,
+        Permission::SystemRead,
+        Permission::UsersRead,
+        Permission::UsersWrite,
+        Permission::AuditRead,
+        Permission::UsersImpersonate,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::StatsRead => "stats:read",
+            Permission::SystemRead => "system:read",
+            Permission::UsersRead => "users:read",
+            Permission::UsersWrite => "users:write",
+            Permission::AuditRead => "audit:read",
+            Permission::UsersImpersonate => "users:impersonate",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Permission::ALL
+            .into_iter()
+            .find(|p| p.as_str() == s)
+            .ok_or_else(|| format!("Unknown permission: {}", s))
+    }
+}
+
+// Named bundles of permissions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    User,
+    Readonly,
+}
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::Admin => &Permission::ALL,
+            // Regular accounts can only manage themselves, which needs no extra permission
+            Role::User => &[],
+            Role::Readonly => &[Permission::StatsRead, Permission::SystemRead, Permission::UsersRead],
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "user" => Ok(Role::User),
+            "readonly" => Ok(Role::Readonly),
+            _ => Err(format!("Unknown role: {}", s)),
+        }
+    }
+}
+
+// Shape of the `users.permissions` JSONB column:
+// `{"roles": ["admin"], "permissions": ["stats:read"]}`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StoredPermissions {
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+impl StoredPermissions {
+    // Rejects unknown role or permission names before they are written to the database
+    pub fn validate(&self) -> Result<(), String> {
+        for role in &self.roles {
+            role.parse::<Role>()?;
+        }
+        for permission in &self.permissions {
+            permission.parse::<Permission>()?;
+        }
+        Ok(())
+    }
+
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+// The effective permissions of a user: the union of its roles and any directly granted permissions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermissionSet {
+    roles: HashSet<Role>,
+    permissions: HashSet<Permission>,
+}
+
+impl PermissionSet {
+    // Users without a stored value get the plain `user` role
+    pub fn from_value(value: Option<&Value>) -> Self {
+        let stored = match value {
+            Some(Value::Null) | None => StoredPermissions::default(),
+            Some(value) => serde_json::from_value::<StoredPermissions>(value.clone()).unwrap_or_else(|e| {
+                warn!("Ignoring malformed permissions value {}: {}", value, e);
+                StoredPermissions::default()
+            }),
+        };
+
+        let mut roles: HashSet<Role> = stored
+            .roles
+            .iter()
+            .filter_map(|role| role.parse().map_err(|e| warn!("{}", e)).ok())
+            .collect();
+        if roles.is_empty() {
+            roles.insert(Role::User);
+        }
+
+        let mut permissions: HashSet<Permission> = stored
+            .permissions
+            .iter()
+            .filter_map(|permission| permission.parse().map_err(|e| warn!("{}", e)).ok())
+            .collect();
+        for role in &roles {
+            permissions.extend(role.permissions().iter().copied());
+        }
+
+        PermissionSet { roles, permissions }
+    }
+
+    pub fn has_role(&self, role: Role) -> bool {
+        self.roles.contains(&role)
+    }
+
+    pub fn contains(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    // Whether every permission of this set is also in `other`
+    pub fn is_subset(&self, other: &PermissionSet) -> bool {
+        self.permissions.is_subset(&other.permissions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_permission_round_trip() {
+        for permission in Permission::ALL {
+            assert_eq!(permission.as_str().parse::<Permission>(), Ok(permission));
+        }
+        assert!("stats:write".parse::<Permission>().is_err());
+    }
+
+    #[test]
+    fn test_missing_permissions_default_to_user_role() {
+        let set = PermissionSet::from_value(None);
+        assert!(set.has_role(Role::User));
+        assert!(!set.contains(Permission::StatsRead));
+
+        let set = PermissionSet::from_value(Some(&Value::Null));
+        assert!(set.has_role(Role::User));
+    }
+
+    #[test]
+    fn test_admin_role_grants_everything() {
+        let set = PermissionSet::from_value(Some(&json!({ "roles": ["admin"] })));
+        for permission in Permission::ALL {
+            assert!(set.contains(permission), "admin should have {}", permission);
+        }
+    }
+
+    #[test]
+    fn test_is_subset() {
+        let admin = PermissionSet::from_value(Some(&json!({ "roles": ["admin"] })));
+        let readonly = PermissionSet::from_value(Some(&json!({ "roles": ["readonly"] })));
+        let user = PermissionSet::from_value(None);
+        assert!(user.is_subset(&readonly));
+        assert!(readonly.is_subset(&admin));
+        assert!(!admin.is_subset(&readonly));
+    }
+
+    #[test]
+    fn test_readonly_role_cannot_write() {
+        let set = PermissionSet::from_value(Some(&json!({ "roles": ["readonly"] })));
+        assert!(set.contains(Permission::StatsRead));
+        assert!(set.contains(Permission::UsersRead));
+        assert!(!set.contains(Permission::UsersWrite));
+    }
+
+    #[test]
+    fn test_direct_permissions_are_added_to_roles() {
+        let set = PermissionSet::from_value(Some(&json!({ "roles": ["user"], "permissions": ["stats:read"] })));
+        assert!(set.contains(Permission::StatsRead));
+        assert!(!set.contains(Permission::SystemRead));
+    }
+
+    #[test]
+    fn test_unknown_and_malformed_values_are_ignored() {
+        let set = PermissionSet::from_value(Some(&json!({ "roles": ["superuser"], "permissions": ["everything"] })));
+        assert!(set.has_role(Role::User));
+        assert!(!set.contains(Permission::UsersWrite));
+
+        let set = PermissionSet::from_value(Some(&json!(["admin"])));
+        assert!(!set.has_role(Role::Admin));
+    }
+
+    #[test]
+    fn test_stored_permissions_validate() {
+        let valid = StoredPermissions {
+            roles: vec!["readonly".to_string()],
+            permissions: vec!["users:write".to_string()],
+        };
+        assert!(valid.validate().is_ok());
+
+        let invalid = StoredPermissions {
+            roles: vec!["root".to_string()],
+            permissions: vec![],
+        };
+        assert_eq!(invalid.validate(), Err("Unknown role: root".to_string()));
+    }
+}
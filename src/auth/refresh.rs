@@ -0,0 +1,110 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+// Number of random bytes in an opaque refresh token
+const REFRESH_TOKEN_BYTES: usize = 32;
+
+// A stored refresh token. Only the SHA-256 hash of the token is kept in the database.
+#[derive(Debug, Clone)]
+pub struct RefreshToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub family_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RefreshTokenState {
+    Active,
+    Expired,
+    Revoked,
+    // Already exchanged for a newer token; presenting it again means it leaked
+    Rotated,
+}
+
+impl RefreshToken {
+    pub fn state(&self, now: DateTime<Utc>) -> RefreshTokenState {
+        if self.revoked_at.is_some() {
+            RefreshTokenState::Revoked
+        } else if self.rotated_at.is_some() {
+            RefreshTokenState::Rotated
+        } else if self.expires_at <= now {
+            RefreshTokenState::Expired
+        } else {
+            RefreshTokenState::Active
+        }
+    }
+}
+
+// Generates a new random, URL-safe refresh token
+pub fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; REFRESH_TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Hex encoded SHA-256 of a refresh token, as stored in `refresh_tokens.token_hash`
+pub fn hash_refresh_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn token_expiring_in(seconds: i64) -> RefreshToken {
+        let now = Utc::now();
+        RefreshToken {
+            id: 1,
+            user_id: 1,
+            family_id: Uuid::new_v4(),
+            created_at: now,
+            expires_at: now + Duration::seconds(seconds),
+            rotated_at: None,
+            revoked_at: None,
+        }
+    }
+
+    #[test]
+    fn test_generate_refresh_token_is_random_and_url_safe() {
+        let first = generate_refresh_token();
+        let second = generate_refresh_token();
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 43); // 32 bytes in unpadded base64
+        assert!(first.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    }
+
+    #[test]
+    fn test_hash_refresh_token_is_stable_hex() {
+        let hash = hash_refresh_token("some-token");
+        assert_eq!(hash, hash_refresh_token("some-token"));
+        assert_ne!(hash, hash_refresh_token("other-token"));
+        assert_eq!(hash.len(), 64);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn test_refresh_token_state() {
+        let now = Utc::now();
+        assert_eq!(token_expiring_in(3600).state(now), RefreshTokenState::Active);
+        assert_eq!(token_expiring_in(-1).state(now), RefreshTokenState::Expired);
+
+        let mut rotated = token_expiring_in(3600);
+        rotated.rotated_at = Some(now);
+        assert_eq!(rotated.state(now), RefreshTokenState::Rotated);
+
+        // Revocation wins over rotation, so a revoked family is never reported as reuse twice
+        let mut revoked = token_expiring_in(3600);
+        revoked.rotated_at = Some(now);
+        revoked.revoked_at = Some(now);
+        assert_eq!(revoked.state(now), RefreshTokenState::Revoked);
+    }
+}
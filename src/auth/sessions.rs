@@ -0,0 +1,107 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+// Upper bound on cached sessions; expired entries are dropped once it is reached
+const MAX_CACHED_SESSIONS: usize = 100_000;
+
+// A login session: everything issued from one login, kept alive by refreshing its tokens.
+// Its id is also the family id of its refresh tokens and the `sid` claim of its access tokens.
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+struct CachedSession {
+    revoked: bool,
+    expires_at: DateTime<Utc>,
+}
+
+// Whether sessions are revoked, remembered for a while so authenticating a request doesn't
+// need the database. Revocations made through this cache apply at once; ones made by other
+// server instances once the cached entry expires.
+pub struct SessionCache {
+    ttl: Duration,
+    // Revoked sessions are remembered until their last access token has expired
+    revoked_ttl: Duration,
+    sessions: Mutex<HashMap<Uuid, CachedSession>>,
+}
+
+impl SessionCache {
+    pub fn new(ttl_seconds: u64, token_ttl_seconds: u64) -> Self {
+        SessionCache {
+            ttl: Duration::seconds(ttl_seconds as i64),
+            revoked_ttl: Duration::seconds(token_ttl_seconds.max(ttl_seconds) as i64),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Cached revocation state of a session, unless it is unknown or has expired
+    pub fn is_revoked(&self, session_id: Uuid, now: DateTime<Utc>) -> Option<bool> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(&session_id)
+            .filter(|cached| cached.expires_at > now)
+            .map(|cached| cached.revoked)
+    }
+
+    pub fn insert(&self, session_id: Uuid, revoked: bool, now: DateTime<Utc>) {
+        let ttl = if revoked { self.revoked_ttl } else { self.ttl };
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= MAX_CACHED_SESSIONS {
+            sessions.retain(|_, cached| cached.expires_at > now);
+        }
+        sessions.insert(
+            session_id,
+            CachedSession {
+                revoked,
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    pub fn revoke(&self, session_ids: &[Uuid], now: DateTime<Utc>) {
+        for session_id in session_ids {
+            self.insert(*session_id, true, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_session_is_not_cached() {
+        let cache = SessionCache::new(30, 900);
+        assert_eq!(cache.is_revoked(Uuid::new_v4(), Utc::now()), None);
+    }
+
+    #[test]
+    fn test_active_session_expires_from_cache() {
+        let cache = SessionCache::new(30, 900);
+        let (session_id, now) = (Uuid::new_v4(), Utc::now());
+        cache.insert(session_id, false, now);
+        assert_eq!(cache.is_revoked(session_id, now + Duration::seconds(29)), Some(false));
+        assert_eq!(cache.is_revoked(session_id, now + Duration::seconds(30)), None);
+    }
+
+    #[test]
+    fn test_revocation_outlives_access_tokens() {
+        let cache = SessionCache::new(30, 900);
+        let (session_id, now) = (Uuid::new_v4(), Utc::now());
+        cache.insert(session_id, false, now);
+        cache.revoke(&[session_id], now);
+        assert_eq!(cache.is_revoked(session_id, now), Some(true));
+        assert_eq!(cache.is_revoked(session_id, now + Duration::seconds(899)), Some(true));
+        assert_eq!(cache.is_revoked(session_id, now + Duration::seconds(900)), None);
+    }
+}
@@ -0,0 +1,143 @@
+use postgres_types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use bytes::BytesMut;
+
+// Lifecycle state of an account, stored as text in `users.status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    Active,
+    PendingVerification,
+    Suspended,
+    Banned,
+    Deactivated,
+}
+
+impl UserStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserStatus::Active => "active",
+            UserStatus::PendingVerification => "pending_verification",
+            UserStatus::Suspended => "suspended",
+            UserStatus::Banned => "banned",
+            UserStatus::Deactivated => "deactivated",
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self == UserStatus::Active
+    }
+
+    // Explanation returned to a user whose account can't be used right now
+    pub fn inactive_reason(&self) -> Option<&'static str> {
+        match self {
+            UserStatus::Active => None,
+            UserStatus::PendingVerification => Some("Email address has not been verified yet"),
+            UserStatus::Suspended => Some("Account is suspended"),
+            UserStatus::Banned => Some("Account is banned"),
+            UserStatus::Deactivated => Some("Account is deactivated"),
+        }
+    }
+
+    // Whether an admin may move an account from this status to `next`.
+    // Only email verification takes an account out of `pending_verification`
+    // by itself, so admins can't put accounts back into it.
+    pub fn can_transition_to(&self, next: UserStatus) -> bool {
+        *self != next && next != UserStatus::PendingVerification
+    }
+}
+
+impl fmt::Display for UserStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for UserStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(UserStatus::Active),
+            "pending_verification" => Ok(UserStatus::PendingVerification),
+            "suspended" => Ok(UserStatus::Suspended),
+            "banned" => Ok(UserStatus::Banned),
+            "deactivated" => Ok(UserStatus::Deactivated),
+            _ => Err(format!("Unknown user status: {}", s)),
+        }
+    }
+}
+
+impl<'a> FromSql<'a> for UserStatus {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let value = <&str as FromSql>::from_sql(ty, raw)?;
+        Ok(value.parse()?)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as FromSql>::accepts(ty)
+    }
+}
+
+impl ToSql for UserStatus {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.as_str().to_sql(ty, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [UserStatus; 5] = [
+        UserStatus::Active,
+        UserStatus::PendingVerification,
+        UserStatus::Suspended,
+        UserStatus::Banned,
+        UserStatus::Deactivated,
+    ];
+
+    #[test]
+    fn test_status_string_round_trip() {
+        for status in ALL {
+            assert_eq!(status.as_str().parse::<UserStatus>(), Ok(status));
+            // The serde form matches the database form
+            assert_eq!(serde_json::to_value(status).unwrap(), status.as_str());
+        }
+        assert!("disabled".parse::<UserStatus>().is_err());
+    }
+
+    #[test]
+    fn test_only_active_has_no_inactive_reason() {
+        for status in ALL {
+            assert_eq!(status.inactive_reason().is_none(), status.is_active());
+        }
+    }
+
+    #[test]
+    fn test_status_sql_round_trip() {
+        let mut buf = BytesMut::new();
+        UserStatus::Suspended.to_sql(&Type::TEXT, &mut buf).unwrap();
+        let decoded = UserStatus::from_sql(&Type::TEXT, &buf).unwrap();
+        assert_eq!(decoded, UserStatus::Suspended);
+        assert!(UserStatus::from_sql(&Type::TEXT, b"unknown").is_err());
+    }
+
+    #[test]
+    fn test_transitions() {
+        assert!(UserStatus::Active.can_transition_to(UserStatus::Suspended));
+        assert!(UserStatus::Banned.can_transition_to(UserStatus::Active));
+        assert!(UserStatus::PendingVerification.can_transition_to(UserStatus::Active));
+        assert!(!UserStatus::Active.can_transition_to(UserStatus::Active));
+        assert!(!UserStatus::Suspended.can_transition_to(UserStatus::PendingVerification));
+    }
+}
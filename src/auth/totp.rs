@@ -0,0 +1,220 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::{Rng, RngCore};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::clock::Clock;
+
+// RFC 6238 parameters understood by every common authenticator app
+pub const DIGITS: u32 = 6;
+pub const PERIOD_SECONDS: i64 = 30;
+// Codes from one step before or after the current one are accepted, to allow for clock drift
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+const SECRET_BYTES: usize = 20;
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+// Lowercase letters and digits without the easily confused 0/o and 1/l
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+// RFC 4648 base32 without padding, the form authenticator apps expect
+pub fn encode_base32(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+pub fn decode_base32(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c.to_ascii_uppercase())? as u64;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+// `otpauth://` URI that authenticator apps import, usually shown as a QR code
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC).to_string();
+    let account = utf8_percent_encode(account, NON_ALPHANUMERIC).to_string();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer,
+        account,
+        encode_base32(secret),
+        issuer,
+        DIGITS,
+        PERIOD_SECONDS
+    )
+}
+
+pub fn time_step(at: DateTime<Utc>) -> i64 {
+    at.timestamp().div_euclid(PERIOD_SECONDS)
+}
+
+// HOTP value (RFC 4226) for a counter, which for TOTP is the time step
+pub fn code_at_step(secret: &[u8], step: i64, digits: u32) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&(step as u64).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    format!("{:0width$}", binary % 10u32.pow(digits), width = digits as usize)
+}
+
+// Checks a code against the current time step and its neighbours. Returns the matching step,
+// which the caller stores so the same code can't be used twice. Steps at or before
+// `last_used_step` are rejected for the same reason.
+pub fn verify(secret: &[u8], code: &str, clock: &dyn Clock, last_used_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = time_step(clock.now());
+    (current - ALLOWED_DRIFT_STEPS..=current + ALLOWED_DRIFT_STEPS)
+        .filter(|step| last_used_step.is_none_or(|last| *step > last))
+        .find(|step| code_at_step(secret, *step, DIGITS) == code)
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = (0..RECOVERY_CODE_LENGTH)
+                .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+                .collect();
+            // Shown as `abcde-fghij` for readability
+            format!("{}-{}", &code[..RECOVERY_CODE_LENGTH / 2], &code[RECOVERY_CODE_LENGTH / 2..])
+        })
+        .collect()
+}
+
+// Hex encoded SHA-256 of a recovery code, ignoring case, spaces and dashes
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex_sha256(&normalized)
+}
+
+fn hex_sha256(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+    use chrono::Duration;
+
+    // Secret of the SHA-1 test vectors in RFC 6238 appendix B
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        for (time, expected) in [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+        ] {
+            assert_eq!(code_at_step(RFC_SECRET, time_step(at(time)), 8), expected, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn test_verify_with_fake_clock() {
+        let clock = FakeClock::new(at(1111111109));
+        // Last six digits of the eight digit RFC value
+        let step = verify(RFC_SECRET, "081804", &clock, None).expect("Current code should verify");
+        assert_eq!(step, time_step(at(1111111109)));
+
+        // Still accepted one step later, but not two
+        clock.advance(Duration::seconds(PERIOD_SECONDS));
+        assert_eq!(verify(RFC_SECRET, "081804", &clock, None), Some(step));
+        clock.advance(Duration::seconds(PERIOD_SECONDS));
+        assert_eq!(verify(RFC_SECRET, "081804", &clock, None), None);
+    }
+
+    #[test]
+    fn test_verify_rejects_reused_and_malformed_codes() {
+        let clock = FakeClock::new(at(1111111109));
+        let step = time_step(clock.now());
+        assert_eq!(verify(RFC_SECRET, "081804", &clock, Some(step)), None);
+        assert_eq!(verify(RFC_SECRET, "81804", &clock, None), None);
+        assert_eq!(verify(RFC_SECRET, "08180a", &clock, None), None);
+        assert_eq!(verify(RFC_SECRET, "000000", &clock, None), None);
+    }
+
+    #[test]
+    fn test_base32_round_trip() {
+        // RFC 4648 test vectors, without padding
+        assert_eq!(encode_base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(encode_base32(b"f"), "MY");
+        assert_eq!(decode_base32("MZXW6YTBOI").unwrap(), b"foobar");
+        assert_eq!(decode_base32("mzxw6ytboi======").unwrap(), b"foobar");
+        assert!(decode_base32("not base32!").is_none());
+
+        let secret = generate_secret();
+        assert_eq!(decode_base32(&encode_base32(&secret)).unwrap(), secret);
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        let uri = otpauth_uri("My API", "alice@example.com", b"foobar");
+        assert_eq!(
+            uri,
+            "otpauth://totp/My%20API:alice%40example%2Ecom?secret=MZXW6YTBOI&issuer=My%20API&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(codes.iter().all(|code| code.len() == RECOVERY_CODE_LENGTH + 1 && code.contains('-')));
+
+        let code = &codes[0];
+        assert_eq!(hash_recovery_code(code), hash_recovery_code(&code.to_uppercase().replace('-', " ")));
+        assert_ne!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[1]));
+    }
+}
@@ -0,0 +1,62 @@
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Mutex;
+
+// Source of the current time. Handlers take it as `web::Data<dyn Clock>` so that
+// time-based checks, such as TOTP codes, can be tested with a `FakeClock`.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// A clock that only moves when told to
+#[derive(Debug)]
+pub struct FakeClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl FakeClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        FakeClock { now: Mutex::new(now) }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += by;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fake_clock_only_moves_when_told() {
+        let start = DateTime::from_timestamp(1_000, 0).unwrap();
+        let clock = FakeClock::new(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::seconds(30));
+        assert_eq!(clock.now(), start + Duration::seconds(30));
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
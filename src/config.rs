@@ -9,6 +9,9 @@ pub struct DatabaseConfig {
     pub host: String,
     pub port: u16,
     pub max_connections: u32,
+    // How long a request waits for a free connection, and for a new connection to be set up
+    pub wait_timeout_seconds: u64,
+    pub connect_timeout_seconds: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -29,12 +32,222 @@ pub struct LogConfig {
     pub file: String,
 }
 
+// Secret shipped in example.config.toml; the server refuses to start with it outside tests
+pub const PLACEHOLDER_JWT_SECRET: &str = "your_secret_key";
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    #[serde(rename = "HS256")]
+    Hs256,
+    #[serde(rename = "RS256")]
+    Rs256,
+    #[serde(rename = "EdDSA")]
+    EdDsa,
+}
+
+// A retired signing key: tokens it signed are still accepted until they expire, but no new
+// ones are issued with it
+#[derive(Debug, Deserialize, Clone)]
+pub struct VerificationKeyConfig {
+    pub kid: String,
+    pub algorithm: JwtAlgorithm,
+    // HS256 verifies with `secret`, or with the contents of `secret_file` if set
+    pub secret: Option<String>,
+    pub secret_file: Option<String>,
+    // RS256 and EdDSA verify with a PEM public key
+    pub public_key_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AuthConfig {
+    pub algorithm: JwtAlgorithm,
+    // HS256 signs with `secret`, or with the contents of `secret_file` if set
+    pub secret: Option<String>,
+    pub secret_file: Option<String>,
+    // RS256 and EdDSA sign with a PEM private key and verify with the matching public key
+    pub private_key_file: Option<String>,
+    pub public_key_file: Option<String>,
+    // Key id of the signing key, sent in the `kid` header of every token
+    #[serde(default = "default_kid")]
+    pub kid: String,
+    // Older keys whose tokens are still accepted
+    #[serde(default)]
+    pub verification_keys: Vec<VerificationKeyConfig>,
+    // Directory of further RS256 or EdDSA public keys, one PEM file per key named `<kid>.pem`
+    pub verification_keys_dir: Option<String>,
+    pub issuer: String,
+    pub audience: String,
+    // Lifetime of access tokens; keep short, clients renew them with a refresh token
+    pub token_ttl_seconds: u64,
+    pub refresh_token_ttl_seconds: u64,
+    // Time a user with two-factor authentication has to enter a code after the password step
+    pub mfa_pending_ttl_seconds: u64,
+    // Lifetime of the tokens admins get to act as another user; they can't be refreshed
+    pub impersonation_ttl_seconds: u64,
+    // How long a request may rely on the cached state of a login session instead of reading it
+    // from the database. Sessions revoked on another server instance are still accepted that long.
+    pub session_cache_seconds: u64,
+    // Name authenticator apps show next to TOTP codes
+    pub totp_issuer: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordAlgorithm {
+    Argon2id,
+    Bcrypt,
+}
+
+// How new password hashes are made. Stored hashes made with another algorithm or weaker
+// parameters still verify and are replaced at the user's next successful login.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PasswordHashConfig {
+    pub algorithm: PasswordAlgorithm,
+    pub argon2_memory_kib: u32,
+    pub argon2_time_cost: u32,
+    pub argon2_parallelism: u32,
+    pub bcrypt_cost: u32,
+}
+
+// Rules new passwords must follow, at registration and when a password is changed or reset
+#[derive(Debug, Deserialize, Clone)]
+pub struct PasswordPolicyConfig {
+    // Minimum length in characters
+    pub min_length: usize,
+    // Maximum length in bytes; bcrypt ignores everything after 72 bytes
+    pub max_bytes: usize,
+    // How many of lowercase letters, uppercase letters, digits and other characters must appear
+    pub min_character_classes: usize,
+    // Optional file with one known breached password per line; such passwords are rejected
+    pub breached_passwords_file: Option<String>,
+}
+
+// Brute-force protection for logins. Wrong second factors count as failed logins too.
+#[derive(Debug, Deserialize, Clone)]
+pub struct LockoutConfig {
+    // Failed logins after which an account, or a client IP, is locked
+    pub max_failed_attempts: u32,
+    pub ip_max_failed_attempts: u32,
+    // The first lockout lasts `lockout_seconds`; every further round of failures doubles it,
+    // up to `max_lockout_seconds`. Failures older than that are forgotten.
+    pub lockout_seconds: u64,
+    pub max_lockout_seconds: u64,
+    // Each failure doubles the delay before the next attempt is answered, starting at `backoff_base_ms`
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+    // Wrong codes after which an `mfa_token` is refused, so the password step has to be repeated
+    pub mfa_attempts_per_challenge: u32,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    // Deliver through an SMTP relay
+    Smtp,
+    // Write each email to a file in `file_dir`, for local development
+    File,
+    // Keep emails in memory; only allowed when RUN_ENV=test
+    Memory,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    pub from: String,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    // STARTTLS is required unless this is turned off, e.g. for a local relay
+    #[serde(default = "default_smtp_tls")]
+    pub smtp_tls: bool,
+    pub file_dir: Option<String>,
+    // Base URL of this API as seen by users, used to build the links in emails
+    pub public_base_url: String,
+    pub verification_token_ttl_seconds: u64,
+    pub password_reset_token_ttl_seconds: u64,
+    // Page where users choose a new password; it receives the reset token as the `token` query
+    // parameter and posts it to `/api/password/reset`
+    pub password_reset_url: String,
+    // Verification and password reset emails that may be requested per email address and per client IP each hour
+    pub resend_per_hour: u32,
+}
+
+// An OpenID Connect provider users can sign in with, e.g. a company identity provider
+#[derive(Debug, Deserialize, Clone)]
+pub struct OidcProviderConfig {
+    // Name used in the URLs, e.g. `/api/oidc/{name}/authorize`
+    pub name: String,
+    // Issuer URL; the provider metadata is discovered at `{issuer}/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    // Page the provider sends users back to; it posts the `code` and `state` it receives
+    // to `/api/oidc/{name}/callback`
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OidcConfig {
+    #[serde(default)]
+    pub providers: Vec<OidcProviderConfig>,
+    // Time a user has to finish signing in at the provider
+    #[serde(default = "default_oidc_state_ttl_seconds")]
+    pub state_ttl_seconds: u64,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            providers: Vec::new(),
+            state_ttl_seconds: default_oidc_state_ttl_seconds(),
+        }
+    }
+}
+
+// Access to the Prometheus metrics at `/metrics`
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct MetricsConfig {
+    // Networks (CIDR, or single addresses) that may scrape `/metrics` without authentication;
+    // everyone else gets 403. None by default. Matched against the peer address, so requests
+    // forwarded by a reverse proxy are refused whatever the proxy's address.
+    #[serde(default)]
+    pub allowed_networks: Vec<String>,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "email".to_string(), "profile".to_string()]
+}
+
+fn default_oidc_state_ttl_seconds() -> u64 {
+    600
+}
+
+fn default_kid() -> String {
+    "default".to_string()
+}
+
+fn default_smtp_tls() -> bool {
+    true
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppConfig {
     pub database: DatabaseConfig,
     pub server: ServerConfig,
     pub rate_limit: RateLimitConfig,
     pub log: LogConfig,
+    pub auth: AuthConfig,
+    pub mail: MailConfig,
+    pub lockout: LockoutConfig,
+    pub password_hashing: PasswordHashConfig,
+    pub password_policy: PasswordPolicyConfig,
+    #[serde(default)]
+    pub oidc: OidcConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
 }
 
 impl AppConfig {
@@ -64,6 +277,35 @@ impl AppConfig {
         let config = builder.build()?;
         config.try_deserialize()
     }
+
+    // Checks settings that are only unsafe outside of tests, e.g. the placeholder JWT secret
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.validate_for_env(&std::env::var("RUN_ENV").unwrap_or_default())
+    }
+
+    fn validate_for_env(&self, run_env: &str) -> Result<(), ConfigError> {
+        if run_env == "test" {
+            return Ok(());
+        }
+
+        let auth = &self.auth;
+        if auth.algorithm == JwtAlgorithm::Hs256
+            && auth.secret_file.is_none()
+            && auth.secret.as_deref().is_none_or(|s| s.is_empty() || s == PLACEHOLDER_JWT_SECRET)
+        {
+            return Err(ConfigError::Message(
+                "auth.secret is missing or still set to the example placeholder; set a real secret or auth.secret_file".to_string(),
+            ));
+        }
+
+        // Emails kept in memory never reach anyone, so verification and reset links would be lost
+        if self.mail.transport == MailTransport::Memory {
+            return Err(ConfigError::Message(
+                "mail.transport = \"memory\" is only allowed when RUN_ENV=test".to_string(),
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -98,6 +340,31 @@ mod tests {
         env::remove_var("TEST_APP_SERVER_PORT");
     }
 
+    #[test]
+    fn test_validate_rejects_placeholder_secret_outside_tests() {
+        let mut app_config = AppConfig::new().unwrap();
+        app_config.auth.algorithm = JwtAlgorithm::Hs256;
+        app_config.auth.secret = Some(PLACEHOLDER_JWT_SECRET.to_string());
+        app_config.auth.secret_file = None;
+
+        assert!(app_config.validate_for_env("production").is_err());
+        assert!(app_config.validate_for_env("").is_err());
+        assert!(app_config.validate_for_env("test").is_ok());
+
+        app_config.auth.secret = Some("a_real_secret".to_string());
+        assert!(app_config.validate_for_env("production").is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_memory_mail_outside_tests() {
+        let mut app_config = AppConfig::new().unwrap();
+        app_config.auth.secret = Some("a_real_secret".to_string());
+        app_config.mail.transport = MailTransport::Memory;
+
+        assert!(app_config.validate_for_env("production").is_err());
+        assert!(app_config.validate_for_env("test").is_ok());
+    }
+
     #[test]
     fn test_config_still_loads_if_config_toml_missing() {
         // Temporarily rename config.toml if it exists to simulate it being missing
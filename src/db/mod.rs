@@ -1,134 +1,1449 @@
-use deadpool_postgres::{Config, Pool, Runtime, Client};
-use tokio_postgres::NoTls;
+use deadpool_postgres::{Config, Pool, PoolConfig, Runtime, Client, Timeouts, Transaction};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{NoTls, Row};
 use crate::{config::DatabaseConfig, statistics::StatisticsData};
+use crate::audit::{AuditAction, AuditContext, AuditEvent, AuditLogEntry};
 use crate::auth::User;
+use crate::auth::password::HashScheme;
 use crate::error::AppError;
 use chrono::{DateTime, Utc}; // Removed NaiveDateTime
 use validator::validate_email;
 use serde_json::Value;
 // Removed: use tokio_postgres::types::Json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use crate::statistics::ErrorLog;
 use crate::statistics::RequestLog;
+use crate::statistics::RouteLatency;
+use crate::statistics::histogram::LatencySummary;
+use crate::statistics::registry::{MetricKind, MetricSnapshot};
+use crate::auth::refresh::RefreshToken;
+use crate::auth::sessions::Session;
+use crate::auth::UserStatus;
+use crate::auth::one_time::TokenPurpose;
+use crate::auth::totp;
+use crate::auth::api_keys::ApiKey;
+use crate::auth::Permission;
+use uuid::Uuid;
+use tokio_postgres::types::ToSql;
+use serde::{Deserialize, Serialize};
 
-// Helper function for input validation, can be unit tested easily
-fn validate_new_user_input(email: &str, username: &str, hashed_password: &str) -> Result<(), AppError> {
+fn validate_email_format(email: &str) -> Result<(), AppError> {
     if !validate_email(email) {
         return Err(AppError::BadRequest("Invalid email format".to_string()));
     }
+    Ok(())
+}
+
+fn validate_username(username: &str) -> Result<(), AppError> {
+    // Validate username (example: alphanumeric, 3-20 characters)
+    if !username.chars().all(|c| c.is_alphanumeric()) || username.len() < 3 || username.len() > 20 {
+        return Err(AppError::BadRequest("Invalid username format".to_string()));
+    }
+    Ok(())
+}
+
+fn validate_password_hash(hashed_password: &str) -> Result<(), AppError> {
+    // Only hashes of a known scheme are stored; bcrypt hashes always have 60 characters
+    let valid = match HashScheme::identify(hashed_password) {
+        Some(HashScheme::Bcrypt) => hashed_password.len() == 60,
+        Some(HashScheme::Argon2id) => true,
+        None => false,
+    };
+    if !valid {
+        return Err(AppError::BadRequest("Invalid password hash".to_string()));
+    }
+    Ok(())
+}
+
+// Helper function for input validation, can be unit tested easily
+fn validate_new_user_input(email: &str, username: &str, hashed_password: &str) -> Result<(), AppError> {
+    validate_email_format(email)?;
+    validate_username(username)?;
+    validate_password_hash(hashed_password)?;
+    Ok(())
+}
+
+pub async fn user_exists(client: &Client, email: &str, username: &str) -> Result<bool, AppError> {
+    user_exists_except(client, email, username, None).await
+}
+
+// Like `user_exists`, but ignores the given user so a profile update doesn't conflict with itself
+pub async fn user_exists_except(
+    client: &Client,
+    email: &str,
+    username: &str,
+    except_user_id: Option<i64>,
+) -> Result<bool, AppError> {
+    let row = client
+        .query_one(
+            "SELECT EXISTS(SELECT 1 FROM users WHERE (email = $1 OR username = $2) AND ($3::BIGINT IS NULL OR id <> $3))",
+            &[&email, &username, &except_user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(row.get(0))
+}
+
+pub async fn insert_user(client: &Client, email: &str, username: &str, hashed_password: &str) -> Result<User, AppError> {
+    // Use the refactored validation function
+    validate_new_user_input(email, username, hashed_password)?;
+
+    // Check if user already exists
+    if user_exists(client, email, username).await? {
+        return Err(AppError::BadRequest("User with this email or username already exists".to_string()));
+    }
+
+    // New accounts stay pending until the email address has been verified
+    let row = client.query_one(
+        "INSERT INTO users (email, username, password, status) VALUES ($1, $2, $3, $4) RETURNING id, email, username, created_at, avatar, tokens, status, permissions, last_login, password_changed_at, failed_login_attempts, locked_until",
+        &[&email, &username, &hashed_password, &UserStatus::PendingVerification],
+    ).await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(User {
+        id: row.get(0),
+        email: row.get(1),
+        username: row.get(2),
+        created_at: row.get::<_, DateTime<Utc>>(3),
+        avatar: row.get(4),
+        tokens: row.get::<_, Option<Value>>(5),
+        status: row.get(6),
+        permissions: row.get::<_, Option<Value>>(7),
+        last_login: row.get::<_, Option<DateTime<Utc>>>(8),
+        password_changed_at: row.get::<_, Option<DateTime<Utc>>>(9),
+        failed_login_attempts: row.get(10),
+        locked_until: row.get::<_, Option<DateTime<Utc>>>(11),
+    })
+}
+
+pub fn establish_connection(config: &DatabaseConfig) -> Result<Pool, Box<dyn std::error::Error>> {
+    let mut cfg = Config::new();
+    cfg.dbname = Some(config.url.clone());
+    cfg.user = Some(config.username.clone());
+    cfg.password = Some(config.password.clone());
+    cfg.host = Some(config.host.clone());
+    cfg.port = Some(config.port);
+    // Without timeouts a request waits forever for a connection once the pool is used up,
+    // or for a database that doesn't answer; with them it fails with a database error
+    cfg.pool = Some(PoolConfig {
+        max_size: config.max_connections as usize,
+        timeouts: Timeouts {
+            wait: Some(std::time::Duration::from_secs(config.wait_timeout_seconds)),
+            create: Some(std::time::Duration::from_secs(config.connect_timeout_seconds)),
+            recycle: Some(std::time::Duration::from_secs(config.connect_timeout_seconds)),
+        },
+    });
+
+    let pool = cfg.create_pool(Some(Runtime::Tokio1), NoTls)?;
+    Ok(pool)
+}
+
+pub async fn test_connection(pool: &Pool) -> Result<(), Box<dyn std::error::Error>> {
+    // Get a client from the pool
+    let client: Client = pool.get().await?;
+
+    // Execute a simple query
+    let result = client.query_one("SELECT 1", &[]).await?;
+
+    // Check if the result is as expected
+    let value: i32 = result.get(0);
+    if value == 1 {
+        println!("Database connection test successful!");
+        Ok(())
+    } else {
+        Err("Unexpected result from database test query".into())
+    }
+}
+
+pub async fn get_user_by_id(client: &Client, user_id: i64) -> Result<User, AppError> {
+    let row = client
+        .query_one(
+            "SELECT id, username, email, created_at, avatar, status, last_login, password_changed_at, failed_login_attempts, locked_until FROM users WHERE id = $1",
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(User {
+        id: row.get("id"),
+        username: row.get("username"),
+        email: row.get("email"),
+        created_at: row.get("created_at"),
+        tokens: None,
+        permissions: None,
+        avatar: row.get("avatar"),
+        status: row.get("status"),
+        last_login: row.get("last_login"),
+        password_changed_at: row.get("password_changed_at"),
+        failed_login_attempts: row.get("failed_login_attempts"),
+        locked_until: row.get("locked_until"),
+    })
+}
+
+// Looks a user up by username or email and returns it together with the stored password hash,
+// which is `None` for accounts that only sign in through an external provider
+pub async fn get_user_credentials(client: &Client, login: &str) -> Result<Option<(User, Option<String>)>, AppError> {
+    // Usernames can't contain `@`, so a login with one is an email address. Matching a single
+    // column means a username equal to another account's email can never match two rows.
+    let column = if login.contains('@') { "email" } else { "username" };
+    let row = client
+        .query_opt(
+            format!(
+                "SELECT id, username, email, password, created_at, avatar, status, last_login, password_changed_at, failed_login_attempts, locked_until FROM users WHERE {} = $1",
+                column
+            )
+            .as_str(),
+            &[&login],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(row.map(|row| {
+        let user = User {
+            id: row.get("id"),
+            username: row.get("username"),
+            email: row.get("email"),
+            created_at: row.get("created_at"),
+            tokens: None,
+            permissions: None,
+            avatar: row.get("avatar"),
+            status: row.get("status"),
+            last_login: row.get("last_login"),
+            password_changed_at: row.get("password_changed_at"),
+            failed_login_attempts: row.get("failed_login_attempts"),
+            locked_until: row.get("locked_until"),
+        };
+        (user, row.get("password"))
+    }))
+}
+
+// Maps a row selected with the columns of `USER_COLUMNS`
+fn user_from_row(row: &Row) -> User {
+    User {
+        id: row.get("id"),
+        username: row.get("username"),
+        email: row.get("email"),
+        created_at: row.get("created_at"),
+        tokens: None,
+        permissions: row.get::<_, Option<Value>>("permissions"),
+        avatar: row.get("avatar"),
+        status: row.get("status"),
+        last_login: row.get("last_login"),
+        password_changed_at: row.get("password_changed_at"),
+        failed_login_attempts: row.get("failed_login_attempts"),
+        locked_until: row.get("locked_until"),
+    }
+}
+
+const USER_COLUMNS: &str = "id, username, email, created_at, avatar, status, permissions, last_login, password_changed_at, failed_login_attempts, locked_until";
+
+// Like `get_user_by_id`, but distinguishes a missing user from a database failure
+pub async fn find_user_by_id(client: &Client, user_id: i64) -> Result<Option<User>, AppError> {
+    let row = client
+        .query_opt(
+            format!("SELECT {} FROM users WHERE id = $1", USER_COLUMNS).as_str(),
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(row.as_ref().map(user_from_row))
+}
+
+pub async fn find_user_by_email(client: &Client, email: &str) -> Result<Option<User>, AppError> {
+    let row = client
+        .query_opt(
+            format!("SELECT {} FROM users WHERE email = $1 AND deleted_at IS NULL", USER_COLUMNS).as_str(),
+            &[&email],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(row.as_ref().map(user_from_row))
+}
+
+// Changes the username and/or avatar of a user and returns the user with their pending email
+// address. `None` leaves a field unchanged; `Some(None)` for the avatar removes it. A new email
+// address only becomes pending: it replaces the current one once `verify_email` is called with
+// an `EmailChange` token. Asking for the current address again cancels a pending change.
+pub async fn update_user_profile(
+    client: &Client,
+    user_id: i64,
+    username: Option<&str>,
+    email: Option<&str>,
+    avatar: Option<Option<&str>>,
+) -> Result<(User, Option<String>), AppError> {
+    if let Some(email) = email {
+        validate_email_format(email)?;
+    }
+    if let Some(username) = username {
+        validate_username(username)?;
+    }
+
+    // An empty string never matches, since stored usernames and emails are validated
+    if (username.is_some() || email.is_some())
+        && user_exists_except(client, email.unwrap_or_default(), username.unwrap_or_default(), Some(user_id)).await?
+    {
+        return Err(AppError::BadRequest("User with this email or username already exists".to_string()));
+    }
+
+    let row = client
+        .query_opt(
+            format!(
+                "UPDATE users SET
+                    username = COALESCE($2, username),
+                    pending_email = CASE WHEN $3::TEXT IS NULL THEN pending_email WHEN $3 = email THEN NULL ELSE $3 END,
+                    avatar = CASE WHEN $4 THEN $5 ELSE avatar END
+                 WHERE id = $1 AND deleted_at IS NULL
+                 RETURNING {}, pending_email",
+                USER_COLUMNS
+            )
+            .as_str(),
+            &[&user_id, &username, &email, &avatar.is_some(), &avatar.flatten()],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?
+        .ok_or(AppError::NotFound)?;
+
+    Ok((user_from_row(&row), row.get("pending_email")))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserSortField {
+    #[default]
+    Id,
+    CreatedAt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+// Position after which the next page starts: the sort key of the last user on the previous page
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserListPosition {
+    pub created_at: DateTime<Utc>,
+    pub id: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UserListFilter {
+    pub status: Option<UserStatus>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub last_login_after: Option<DateTime<Utc>>,
+    pub last_login_before: Option<DateTime<Utc>>,
+    // Matches usernames or emails starting with this text, case-insensitively
+    pub search: Option<String>,
+    pub sort: UserSortField,
+    pub order: SortOrder,
+    pub after: Option<UserListPosition>,
+}
+
+// Escapes LIKE wildcards so user input only ever matches literally
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Lists users with keyset pagination: returns at most `limit` users after `filter.after`
+pub async fn list_users(client: &Client, filter: &UserListFilter, limit: i64) -> Result<Vec<User>, AppError> {
+    let mut conditions: Vec<String> = Vec::new();
+    let mut params: Vec<Box<dyn ToSql + Sync + Send>> = Vec::new();
+
+    // Adds a parameter and returns its `$n` placeholder
+    fn bind(params: &mut Vec<Box<dyn ToSql + Sync + Send>>, value: Box<dyn ToSql + Sync + Send>) -> String {
+        params.push(value);
+        format!("${}", params.len())
+    }
+
+    if let Some(status) = filter.status {
+        conditions.push(format!("status = {}", bind(&mut params, Box::new(status))));
+    }
+    if let Some(created_after) = filter.created_after {
+        conditions.push(format!("created_at >= {}", bind(&mut params, Box::new(created_after))));
+    }
+    if let Some(created_before) = filter.created_before {
+        conditions.push(format!("created_at < {}", bind(&mut params, Box::new(created_before))));
+    }
+    if let Some(last_login_after) = filter.last_login_after {
+        conditions.push(format!("last_login >= {}", bind(&mut params, Box::new(last_login_after))));
+    }
+    if let Some(last_login_before) = filter.last_login_before {
+        conditions.push(format!("last_login < {}", bind(&mut params, Box::new(last_login_before))));
+    }
+    if let Some(search) = filter.search.as_deref().filter(|s| !s.is_empty()) {
+        let pattern = bind(&mut params, Box::new(format!("{}%", escape_like(search))));
+        conditions.push(format!("(username ILIKE {0} OR email ILIKE {0})", pattern));
+    }
+
+    let comparison = match filter.order {
+        SortOrder::Asc => ">",
+        SortOrder::Desc => "<",
+    };
+    if let Some(after) = &filter.after {
+        match filter.sort {
+            UserSortField::Id => {
+                conditions.push(format!("id {} {}", comparison, bind(&mut params, Box::new(after.id))));
+            }
+            UserSortField::CreatedAt => {
+                let created_at = bind(&mut params, Box::new(after.created_at));
+                let id = bind(&mut params, Box::new(after.id));
+                conditions.push(format!("(created_at, id) {} ({}, {})", comparison, created_at, id));
+            }
+        }
+    }
+
+    let direction = match filter.order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    let order_by = match filter.sort {
+        UserSortField::Id => format!("id {}", direction),
+        UserSortField::CreatedAt => format!("created_at {0}, id {0}", direction),
+    };
+    let where_clause = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+
+    let limit = bind(&mut params, Box::new(limit));
+    let query = format!(
+        "SELECT {} FROM users {} ORDER BY {} LIMIT {}",
+        USER_COLUMNS, where_clause, order_by, limit
+    );
+
+    let param_refs: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref() as &(dyn ToSql + Sync)).collect();
+    let rows = client
+        .query(query.as_str(), &param_refs)
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(rows.iter().map(user_from_row).collect())
+}
+
+// `None` if the user doesn't exist or has no password
+pub async fn get_password_hash(client: &Client, user_id: i64) -> Result<Option<String>, AppError> {
+    let row = client
+        .query_opt("SELECT password FROM users WHERE id = $1 AND deleted_at IS NULL", &[&user_id])
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(row.and_then(|row| row.get("password")))
+}
+
+pub async fn update_password_hash(client: &Client, user_id: i64, hashed_password: &str) -> Result<(), AppError> {
+    validate_password_hash(hashed_password)?;
+    client
+        .execute(
+            "UPDATE users SET password = $2, password_changed_at = NOW() WHERE id = $1",
+            &[&user_id, &hashed_password],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// Soft-deletes an account: the row stays so foreign keys and history remain valid, but the
+// personal data is replaced with placeholders and the password can no longer match.
+pub async fn soft_delete_user(client: &mut Client, user_id: i64) -> Result<(), AppError> {
+    let transaction = client.transaction().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    let old_status: UserStatus = transaction
+        .query_opt(
+            "SELECT status FROM users WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?
+        .ok_or(AppError::NotFound)?
+        .get("status");
+
+    transaction
+        .execute(
+            // The placeholders contain characters registration rejects, so they can never
+            // collide with the email or username of another account
+            "UPDATE users SET
+                email = 'deleted-' || id,
+                username = 'deleted-' || id,
+                password = '',
+                pending_email = NULL,
+                avatar = NULL,
+                tokens = NULL,
+                permissions = NULL,
+                totp_secret = NULL,
+                totp_enabled_at = NULL,
+                status = $2,
+                deleted_at = NOW()
+             WHERE id = $1",
+            &[&user_id, &UserStatus::Deactivated],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    // The external accounts are free to sign up again
+    transaction
+        .execute("DELETE FROM user_identities WHERE user_id = $1", &[&user_id])
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    // Links emailed earlier, e.g. to confirm an email change or reset the password, and
+    // recovery codes must not act on the deleted account
+    for statement in [
+        "DELETE FROM one_time_tokens WHERE user_id = $1",
+        "DELETE FROM mfa_recovery_codes WHERE user_id = $1",
+    ] {
+        transaction
+            .execute(statement, &[&user_id])
+            .await
+            .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    }
+    transaction
+        .execute(
+            "INSERT INTO user_status_changes (user_id, old_status, new_status, reason, changed_by) VALUES ($1, $2, $3, $4, $1)",
+            &[&user_id, &old_status, &UserStatus::Deactivated, &"Account deleted by its owner"],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    transaction.commit().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// Replaces the stored roles and permissions of a user. Returns false if the user doesn't exist.
+pub async fn update_user_permissions(client: &Client, user_id: i64, permissions: &Value) -> Result<bool, AppError> {
+    let updated = client
+        .execute(
+            "UPDATE users SET permissions = $2 WHERE id = $1",
+            &[&user_id, &permissions],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(updated == 1)
+}
+
+// Moves a user to a new status and records who did it and why.
+// Returns the previous status, or None if the user doesn't exist.
+pub async fn update_user_status(
+    client: &mut Client,
+    user_id: i64,
+    new_status: UserStatus,
+    reason: &str,
+    changed_by: i64,
+) -> Result<Option<UserStatus>, AppError> {
+    let transaction = client.transaction().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    let row = match transaction
+        .query_opt("SELECT status, deleted_at FROM users WHERE id = $1 FOR UPDATE", &[&user_id])
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?
+    {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let old_status: UserStatus = row.get("status");
+
+    if row.get::<_, Option<DateTime<Utc>>>("deleted_at").is_some() {
+        return Err(AppError::BadRequest("Account has been deleted".to_string()));
+    }
+
+    if !old_status.can_transition_to(new_status) {
+        return Err(AppError::BadRequest(format!(
+            "Cannot change status from {} to {}",
+            old_status, new_status
+        )));
+    }
+
+    transaction
+        .execute("UPDATE users SET status = $2 WHERE id = $1", &[&user_id, &new_status])
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    transaction
+        .execute(
+            "INSERT INTO user_status_changes (user_id, old_status, new_status, reason, changed_by) VALUES ($1, $2, $3, $4, $5)",
+            &[&user_id, &old_status, &new_status, &reason, &changed_by],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    transaction.commit().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(Some(old_status))
+}
+
+pub async fn update_last_login(client: &Client, user_id: i64) -> Result<(), tokio_postgres::Error> {
+    client.execute(
+        "UPDATE users SET last_login = NOW() WHERE id = $1",
+        &[&user_id],
+    ).await?;
+    Ok(())
+}
+
+// Counts a failed login and returns the number of consecutive failures. A previous
+// failure older than `forget_before` doesn't count anymore.
+pub async fn record_failed_login(client: &Client, user_id: i64, now: DateTime<Utc>, forget_before: DateTime<Utc>) -> Result<i32, AppError> {
+    let row = client
+        .query_one(
+            "UPDATE users SET
+                 failed_login_attempts = CASE WHEN last_failed_login_at < $3 THEN 1 ELSE failed_login_attempts + 1 END,
+                 last_failed_login_at = $2
+             WHERE id = $1
+             RETURNING failed_login_attempts",
+            &[&user_id, &now, &forget_before],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(row.get(0))
+}
+
+pub async fn lock_user_until(client: &Client, user_id: i64, until: DateTime<Utc>) -> Result<(), AppError> {
+    client
+        .execute("UPDATE users SET locked_until = $2 WHERE id = $1", &[&user_id, &until])
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// Clears the failed login count and any lockout. Returns false if the user doesn't exist.
+pub async fn unlock_user(client: &Client, user_id: i64) -> Result<bool, AppError> {
+    let updated = client
+        .execute(
+            "UPDATE users SET failed_login_attempts = 0, last_failed_login_at = NULL, locked_until = NULL
+             WHERE id = $1 AND deleted_at IS NULL",
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(updated > 0)
+}
+
+// Stores a new one-time token for the user, invalidating any earlier unused token with the same purpose
+pub async fn replace_one_time_token(
+    client: &mut Client,
+    user_id: i64,
+    purpose: TokenPurpose,
+    token_hash: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<(), AppError> {
+    let transaction = client.transaction().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    transaction
+        .execute(
+            "UPDATE one_time_tokens SET used_at = NOW() WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL",
+            &[&user_id, &purpose.as_str()],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    transaction
+        .execute(
+            "INSERT INTO one_time_tokens (user_id, purpose, token_hash, expires_at) VALUES ($1, $2, $3, $4)",
+            &[&user_id, &purpose.as_str(), &token_hash, &expires_at],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    transaction.commit().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// Returns the account of an unused, unexpired token without using it up, so requests with an
+// unknown token can be turned away before any expensive work
+pub async fn find_one_time_token_user(
+    client: &Client,
+    purpose: TokenPurpose,
+    token_hash: &str,
+) -> Result<Option<User>, AppError> {
+    let row = client
+        .query_opt(
+            format!(
+                "SELECT {} FROM users
+                 WHERE id = (SELECT user_id FROM one_time_tokens
+                             WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW())
+                   AND deleted_at IS NULL",
+                USER_COLUMNS
+            )
+            .as_str(),
+            &[&token_hash, &purpose.as_str()],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(row.as_ref().map(user_from_row))
+}
+
+// Marks an unused, unexpired token as used and returns the user it belongs to.
+// The single UPDATE makes sure two concurrent requests can't both use the same token.
+async fn consume_one_time_token(
+    transaction: &Transaction<'_>,
+    purpose: TokenPurpose,
+    token_hash: &str,
+) -> Result<Option<i64>, AppError> {
+    let row = transaction
+        .query_opt(
+            "UPDATE one_time_tokens SET used_at = NOW()
+             WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+             RETURNING user_id",
+            &[&token_hash, &purpose.as_str()],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(row.map(|row| row.get("user_id")))
+}
+
+// Uses an email verification token and activates the pending account it belongs to, or uses
+// an email change token and applies the new address. Returns the user id, or `None` if the
+// token is unknown, expired or already used.
+pub async fn verify_email(client: &mut Client, token_hash: &str) -> Result<Option<i64>, AppError> {
+    let transaction = client.transaction().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    let user_id = match consume_one_time_token(&transaction, TokenPurpose::EmailVerification, token_hash).await? {
+        Some(user_id) => user_id,
+        None => {
+            let changed = confirm_email_change(&transaction, token_hash).await?;
+            transaction.commit().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+            return Ok(changed);
+        }
+    };
+
+    // Accounts an admin changed in the meantime, e.g. to banned, keep their status
+    let activated = transaction
+        .execute(
+            "UPDATE users SET status = $2 WHERE id = $1 AND status = $3 AND deleted_at IS NULL",
+            &[&user_id, &UserStatus::Active, &UserStatus::PendingVerification],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    if activated == 1 {
+        transaction
+            .execute(
+                "INSERT INTO user_status_changes (user_id, old_status, new_status, reason, changed_by) VALUES ($1, $2, $3, $4, $1)",
+                &[&user_id, &UserStatus::PendingVerification, &UserStatus::Active, &"Email address verified"],
+            )
+            .await
+            .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    }
+
+    transaction.commit().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(Some(user_id))
+}
+
+// Replaces the email address of a user with the pending one the email change token was sent to.
+// Returns `None` if the token is not valid or another account has the address by now.
+async fn confirm_email_change(transaction: &Transaction<'_>, token_hash: &str) -> Result<Option<i64>, AppError> {
+    let user_id = match consume_one_time_token(transaction, TokenPurpose::EmailChange, token_hash).await? {
+        Some(user_id) => user_id,
+        None => return Ok(None),
+    };
+
+    // The unique index on `email` settles a race with another account taking the address
+    let changed = transaction
+        .execute(
+            "UPDATE users SET email = pending_email, pending_email = NULL
+             WHERE id = $1 AND pending_email IS NOT NULL AND deleted_at IS NULL
+               AND NOT EXISTS (SELECT 1 FROM users other WHERE other.email = users.pending_email AND other.id <> users.id)",
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| {
+            if e.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+                AppError::BadRequest("The email address is already in use".to_string())
+            } else {
+                AppError::DatabaseError(e.to_string())
+            }
+        })?;
+    Ok((changed == 1).then_some(user_id))
+}
+
+// Replaces a password hash with a stronger one for the same password. Unlike
+// `update_password_hash` this keeps sessions and `password_changed_at`; it does nothing,
+// returning false, if the password was changed since `old_hash` was read.
+pub async fn upgrade_password_hash(client: &Client, user_id: i64, old_hash: &str, new_hash: &str) -> Result<bool, AppError> {
+    validate_password_hash(new_hash)?;
+    let updated = client
+        .execute(
+            "UPDATE users SET password = $3 WHERE id = $1 AND password = $2",
+            &[&user_id, &old_hash, &new_hash],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(updated > 0)
+}
+
+// Uses a password reset token: stores the new password hash and revokes every refresh token
+// of the user. Returns the user id, or `None` if the token is unknown, expired or already used.
+pub async fn reset_password(
+    client: &mut Client,
+    token_hash: &str,
+    hashed_password: &str,
+) -> Result<Option<i64>, AppError> {
+    validate_password_hash(hashed_password)?;
+    let transaction = client.transaction().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    let user_id = match consume_one_time_token(&transaction, TokenPurpose::PasswordReset, token_hash).await? {
+        Some(user_id) => user_id,
+        None => return Ok(None),
+    };
+
+    let updated = transaction
+        .execute(
+            // Proving control of the email address also lifts a login lockout
+            "UPDATE users SET password = $2, password_changed_at = NOW(),
+                failed_login_attempts = 0, last_failed_login_at = NULL, locked_until = NULL
+             WHERE id = $1 AND deleted_at IS NULL",
+            &[&user_id, &hashed_password],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    if updated == 0 {
+        // The account was deleted after the token was issued
+        return Ok(None);
+    }
+    transaction
+        .execute(
+            "UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    transaction
+        .execute(
+            "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL",
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    transaction.commit().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(Some(user_id))
+}
+
+// TOTP state of a user that has started or completed enrolment
+#[derive(Debug, Clone)]
+pub struct TotpSettings {
+    pub secret: Vec<u8>,
+    pub enabled: bool,
+    pub last_step: Option<i64>,
+}
+
+pub async fn get_totp_settings(client: &Client, user_id: i64) -> Result<Option<TotpSettings>, AppError> {
+    let row = client
+        .query_opt(
+            "SELECT totp_secret, totp_enabled_at, totp_last_step FROM users WHERE id = $1 AND totp_secret IS NOT NULL",
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    row.map(|row| {
+        let secret = totp::decode_base32(row.get("totp_secret"))
+            .ok_or_else(|| AppError::DatabaseError(format!("Malformed TOTP secret for user {}", user_id)))?;
+        Ok(TotpSettings {
+            secret,
+            enabled: row.get::<_, Option<DateTime<Utc>>>("totp_enabled_at").is_some(),
+            last_step: row.get("totp_last_step"),
+        })
+    })
+    .transpose()
+}
+
+// Stores a new secret awaiting confirmation. Returns false if TOTP is already enabled.
+pub async fn set_pending_totp_secret(client: &Client, user_id: i64, secret: &[u8]) -> Result<bool, AppError> {
+    let updated = client
+        .execute(
+            "UPDATE users SET totp_secret = $2, totp_last_step = NULL WHERE id = $1 AND totp_enabled_at IS NULL",
+            &[&user_id, &totp::encode_base32(secret)],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(updated == 1)
+}
+
+// Turns on TOTP after the first code was verified and replaces the recovery codes.
+// Returns false if there is no pending secret.
+pub async fn enable_totp(
+    client: &mut Client,
+    user_id: i64,
+    verified_step: i64,
+    recovery_code_hashes: &[String],
+) -> Result<bool, AppError> {
+    let transaction = client.transaction().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    let updated = transaction
+        .execute(
+            "UPDATE users SET totp_enabled_at = NOW(), totp_last_step = $2
+             WHERE id = $1 AND totp_secret IS NOT NULL AND totp_enabled_at IS NULL",
+            &[&user_id, &verified_step],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    if updated == 0 {
+        return Ok(false);
+    }
+
+    transaction
+        .execute("DELETE FROM mfa_recovery_codes WHERE user_id = $1", &[&user_id])
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    for code_hash in recovery_code_hashes {
+        transaction
+            .execute(
+                "INSERT INTO mfa_recovery_codes (user_id, code_hash) VALUES ($1, $2)",
+                &[&user_id, code_hash],
+            )
+            .await
+            .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    }
+
+    transaction.commit().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(true)
+}
+
+// Records the time step of an accepted code. Returns false if that step or a later one was
+// already used, which happens when two requests race with the same code.
+pub async fn record_totp_step(client: &Client, user_id: i64, step: i64) -> Result<bool, AppError> {
+    let updated = client
+        .execute(
+            "UPDATE users SET totp_last_step = $2 WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)",
+            &[&user_id, &step],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(updated == 1)
+}
+
+// Marks an unused recovery code as used. Returns false if it doesn't match any unused code.
+pub async fn use_recovery_code(client: &Client, user_id: i64, code_hash: &str) -> Result<bool, AppError> {
+    let updated = client
+        .execute(
+            "UPDATE mfa_recovery_codes SET used_at = NOW() WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+            &[&user_id, &code_hash],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(updated > 0)
+}
+
+fn api_key_from_row(row: &Row) -> ApiKey {
+    ApiKey {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        name: row.get("name"),
+        prefix: row.get("prefix"),
+        // Scopes are validated on creation; names dropped from the code later are ignored
+        scopes: row
+            .get::<_, Vec<String>>("scopes")
+            .iter()
+            .filter_map(|scope| scope.parse::<Permission>().ok())
+            .collect(),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+        last_used_at: row.get("last_used_at"),
+        revoked_at: row.get("revoked_at"),
+    }
+}
+
+const API_KEY_COLUMNS: &str = "id, user_id, name, prefix, scopes, created_at, expires_at, last_used_at, revoked_at";
+
+pub async fn insert_api_key(
+    client: &Client,
+    user_id: i64,
+    name: &str,
+    prefix: &str,
+    secret_hash: &str,
+    scopes: &[Permission],
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<ApiKey, AppError> {
+    let scopes: Vec<&str> = scopes.iter().map(|scope| scope.as_str()).collect();
+    let row = client
+        .query_one(
+            format!(
+                "INSERT INTO api_keys (user_id, name, prefix, secret_hash, scopes, expires_at)
+                 VALUES ($1, $2, $3, $4, $5, $6) RETURNING {}",
+                API_KEY_COLUMNS
+            )
+            .as_str(),
+            &[&user_id, &name, &prefix, &secret_hash, &scopes, &expires_at],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(api_key_from_row(&row))
+}
+
+// Keys of a user that haven't been revoked, newest first
+pub async fn list_api_keys(client: &Client, user_id: i64) -> Result<Vec<ApiKey>, AppError> {
+    let rows = client
+        .query(
+            format!(
+                "SELECT {} FROM api_keys WHERE user_id = $1 AND revoked_at IS NULL ORDER BY created_at DESC, id DESC",
+                API_KEY_COLUMNS
+            )
+            .as_str(),
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(rows.iter().map(api_key_from_row).collect())
+}
+
+// Returns false if the user has no such unrevoked key
+pub async fn revoke_api_key(client: &Client, user_id: i64, key_id: i64) -> Result<bool, AppError> {
+    let updated = client
+        .execute(
+            "UPDATE api_keys SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+            &[&key_id, &user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(updated == 1)
+}
 
-    // Validate username (example: alphanumeric, 3-20 characters)
-    if !username.chars().all(|c| c.is_alphanumeric()) || username.len() < 3 || username.len() > 20 {
-        return Err(AppError::BadRequest("Invalid username format".to_string()));
-    }
+// Finds a key by its public prefix, together with its secret hash. Keys of accounts
+// that are not active or have been deleted are not returned.
+pub async fn find_api_key_by_prefix(client: &Client, prefix: &str) -> Result<Option<(ApiKey, String)>, AppError> {
+    let row = client
+        .query_opt(
+            "SELECT k.id, k.user_id, k.name, k.prefix, k.scopes, k.created_at, k.expires_at, k.last_used_at,
+                    k.revoked_at, k.secret_hash
+             FROM api_keys k JOIN users u ON u.id = k.user_id
+             WHERE k.prefix = $1 AND u.status = $2 AND u.deleted_at IS NULL",
+            &[&prefix, &UserStatus::Active],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(row.map(|row| (api_key_from_row(&row), row.get("secret_hash"))))
+}
 
-    // Validate hashed password length (assuming bcrypt, which is typically 60 characters)
-    if hashed_password.len() != 60 {
-        return Err(AppError::BadRequest("Invalid password hash".to_string()));
-    }
+// Updates `last_used_at`, at most once a minute per key to keep writes down for busy scripts
+pub async fn touch_api_key(client: &Client, key_id: i64) -> Result<(), AppError> {
+    client
+        .execute(
+            "UPDATE api_keys SET last_used_at = NOW()
+             WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < NOW() - INTERVAL '1 minute')",
+            &[&key_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
     Ok(())
 }
 
-pub async fn user_exists(client: &Client, email: &str, username: &str) -> Result<bool, AppError> {
+pub async fn insert_refresh_token(
+    client: &Client,
+    user_id: i64,
+    family_id: Uuid,
+    token_hash: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<(), AppError> {
+    client
+        .execute(
+            "INSERT INTO refresh_tokens (user_id, family_id, token_hash, expires_at) VALUES ($1, $2, $3, $4)",
+            &[&user_id, &family_id, &token_hash, &expires_at],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+pub async fn find_refresh_token(client: &Client, token_hash: &str) -> Result<Option<RefreshToken>, AppError> {
     let row = client
-        .query_one(
-            "SELECT EXISTS(SELECT 1 FROM users WHERE email = $1 OR username = $2)",
-            &[&email, &username],
+        .query_opt(
+            "SELECT id, user_id, family_id, created_at, expires_at, rotated_at, revoked_at FROM refresh_tokens WHERE token_hash = $1",
+            &[&token_hash],
         )
         .await
         .map_err(|e| AppError::DatabaseError(e.to_string()))?;
 
-    Ok(row.get(0))
+    Ok(row.map(|row| RefreshToken {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        family_id: row.get("family_id"),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+        rotated_at: row.get("rotated_at"),
+        revoked_at: row.get("revoked_at"),
+    }))
 }
 
-pub async fn insert_user(client: &Client, email: &str, username: &str, hashed_password: &str) -> Result<User, AppError> {
-    // Use the refactored validation function
-    validate_new_user_input(email, username, hashed_password)?;
+// Marks a refresh token as used. Returns false if another request already rotated or
+// revoked it, so two concurrent refreshes with the same token can't both succeed.
+pub async fn mark_refresh_token_rotated(client: &Client, id: i64) -> Result<bool, AppError> {
+    let updated = client
+        .execute(
+            "UPDATE refresh_tokens SET rotated_at = NOW() WHERE id = $1 AND rotated_at IS NULL AND revoked_at IS NULL",
+            &[&id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(updated == 1)
+}
 
-    // Check if user already exists
-    if user_exists(client, email, username).await? {
-        return Err(AppError::BadRequest("User with this email or username already exists".to_string()));
-    }
+// Revokes a refresh token family together with the session it belongs to
+pub async fn revoke_refresh_token_family(client: &Client, family_id: Uuid) -> Result<u64, AppError> {
+    client
+        .execute(
+            "WITH revoked_session AS (
+                UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL
+             )
+             UPDATE refresh_tokens SET revoked_at = NOW() WHERE family_id = $1 AND revoked_at IS NULL",
+            &[&family_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))
+}
 
-    // Proceed with insertion...
-    let row = client.query_one(
-        "INSERT INTO users (email, username, password) VALUES ($1, $2, $3) RETURNING id, email, username, created_at, avatar, tokens, status, permissions, last_login",
-        &[&email, &username, &hashed_password],
-    ).await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+// Revokes every session of a user and their refresh tokens, returning the revoked session ids
+pub async fn revoke_user_sessions(client: &Client, user_id: i64) -> Result<Vec<Uuid>, AppError> {
+    let rows = client
+        .query(
+            "WITH revoked_tokens AS (
+                UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL
+             )
+             UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL RETURNING id",
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
 
-    Ok(User {
-        id: row.get(0),
-        email: row.get(1),
-        username: row.get(2),
-        created_at: row.get::<_, DateTime<Utc>>(3),
-        avatar: row.get(4),
-        tokens: row.get::<_, Option<Value>>(5),
-        status: row.get(6),
-        permissions: row.get::<_, Option<Value>>(7),
-        last_login: row.get::<_, Option<DateTime<Utc>>>(8),
-    })
+fn session_from_row(row: &Row) -> Session {
+    Session {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        user_agent: row.get("user_agent"),
+        ip: row.get("ip"),
+        created_at: row.get("created_at"),
+        last_seen_at: row.get("last_seen_at"),
+        revoked_at: row.get("revoked_at"),
+    }
 }
 
-pub fn establish_connection(config: &DatabaseConfig) -> Result<Pool, Box<dyn std::error::Error>> {
-    let mut cfg = Config::new();
-    cfg.dbname = Some(config.url.clone());
-    cfg.user = Some(config.username.clone());
-    cfg.password = Some(config.password.clone());
-    cfg.host = Some(config.host.clone());
-    cfg.port = Some(config.port);
-    
-    let pool = cfg.create_pool(Some(Runtime::Tokio1), NoTls)?;
-    pool.resize(config.max_connections as usize);
-    Ok(pool)
+const SESSION_COLUMNS: &str = "id, user_id, user_agent, ip, created_at, last_seen_at, revoked_at";
+
+// Starts a session, or records that an existing one was used again from the request's
+// client. Returns false if the session has been revoked.
+pub async fn record_session_use(
+    client: &Client,
+    session_id: Uuid,
+    user_id: i64,
+    context: &AuditContext,
+) -> Result<bool, AppError> {
+    let row = client
+        .query_one(
+            "INSERT INTO sessions (id, user_id, user_agent, ip) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (id) DO UPDATE SET
+                user_agent = EXCLUDED.user_agent,
+                ip = EXCLUDED.ip,
+                last_seen_at = NOW()
+             RETURNING revoked_at IS NULL AS active",
+            &[&session_id, &user_id, &context.user_agent, &context.ip],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(row.get("active"))
 }
 
-pub async fn test_connection(pool: &Pool) -> Result<(), Box<dyn std::error::Error>> {
-    // Get a client from the pool
-    let client: Client = pool.get().await?;
+// Starts the session of an impersonation token: it belongs to the impersonated user, so
+// revoking their sessions ends it, and names the admin
+pub async fn insert_impersonation_session(
+    client: &Client,
+    session_id: Uuid,
+    user_id: i64,
+    impersonator_id: i64,
+    context: &AuditContext,
+) -> Result<(), AppError> {
+    client
+        .execute(
+            "INSERT INTO sessions (id, user_id, user_agent, ip, impersonator_id) VALUES ($1, $2, $3, $4, $5)",
+            &[&session_id, &user_id, &context.user_agent, &context.ip, &impersonator_id],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
 
-    // Execute a simple query
-    let result = client.query_one("SELECT 1", &[]).await?;
+// Unknown sessions count as revoked
+pub async fn is_session_revoked(client: &Client, session_id: Uuid) -> Result<bool, AppError> {
+    let row = client
+        .query_opt("SELECT revoked_at IS NOT NULL AS revoked FROM sessions WHERE id = $1", &[&session_id])
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(row.is_none_or(|row| row.get("revoked")))
+}
 
-    // Check if the result is as expected
-    let value: i32 = result.get(0);
-    if value == 1 {
-        println!("Database connection test successful!");
-        Ok(())
-    } else {
-        Err("Unexpected result from database test query".into())
-    }
+// Sessions of a user that are neither revoked nor idle since `seen_since`, most recently used
+// first. Impersonation sessions are the admin's, not the user's, so they are left out.
+pub async fn list_sessions(client: &Client, user_id: i64, seen_since: DateTime<Utc>) -> Result<Vec<Session>, AppError> {
+    let rows = client
+        .query(
+            format!(
+                "SELECT {} FROM sessions
+                 WHERE user_id = $1 AND revoked_at IS NULL AND last_seen_at > $2 AND impersonator_id IS NULL
+                 ORDER BY last_seen_at DESC",
+                SESSION_COLUMNS
+            )
+            .as_str(),
+            &[&user_id, &seen_since],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(rows.iter().map(session_from_row).collect())
 }
 
-pub async fn get_user_by_id(client: &Client, user_id: i64) -> Result<User, AppError> {
+// Revokes one of the user's sessions and its refresh tokens. Returns false if the user
+// has no such session or it was already revoked.
+pub async fn revoke_user_session(client: &Client, user_id: i64, session_id: Uuid) -> Result<bool, AppError> {
     let row = client
-        .query_one(
-            "SELECT id, username, email, created_at, avatar, status, last_login FROM users WHERE id = $1",
-            &[&user_id],
+        .query_opt(
+            "WITH revoked_session AS (
+                UPDATE sessions SET revoked_at = NOW() WHERE id = $2 AND user_id = $1 AND revoked_at IS NULL RETURNING id
+             ), revoked_tokens AS (
+                UPDATE refresh_tokens SET revoked_at = NOW()
+                WHERE family_id IN (SELECT id FROM revoked_session) AND revoked_at IS NULL
+             )
+             SELECT id FROM revoked_session",
+            &[&user_id, &session_id],
         )
         .await
         .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(row.is_some())
+}
 
-    Ok(User {
-        id: row.get("id"),
-        username: row.get("username"),
-        email: row.get("email"),
-        created_at: row.get("created_at"),
-        tokens: None,
-        permissions: None,
-        avatar: row.get("avatar"),
-        status: row.get("status"),
-        last_login: row.get("last_login"),
-    })
+// Filters for `list_audit_log`; `None` doesn't filter
+#[derive(Debug, Default)]
+pub struct AuditLogFilter {
+    pub actor_id: Option<i64>,
+    pub action: Option<AuditAction>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    // Only entries older than this one, for paging
+    pub before_id: Option<i64>,
 }
 
-pub async fn update_last_login(client: &Client, user_id: i64) -> Result<(), tokio_postgres::Error> {
-    client.execute(
-        "UPDATE users SET last_login = NOW() WHERE id = $1",
-        &[&user_id],
-    ).await?;
+pub async fn insert_audit_entry(client: &Client, context: &AuditContext, event: &AuditEvent) -> Result<(), AppError> {
+    client
+        .execute(
+            "INSERT INTO audit_log (action, actor_id, target_id, ip, user_agent, request_id, impersonator_id, details)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            &[
+                &event.action.as_str(),
+                &event.actor_id,
+                &event.target_id,
+                &context.ip,
+                &context.user_agent,
+                &context.request_id,
+                &context.impersonator_id,
+                &event.details,
+            ],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+// Newest entries first
+pub async fn list_audit_log(client: &Client, filter: &AuditLogFilter, limit: i64) -> Result<Vec<AuditLogEntry>, AppError> {
+    let rows = client
+        .query(
+            "SELECT id, occurred_at, action, actor_id, target_id, ip, user_agent, request_id, impersonator_id, details
+             FROM audit_log
+             WHERE ($1::BIGINT IS NULL OR actor_id = $1)
+               AND ($2::TEXT IS NULL OR action = $2)
+               AND ($3::TIMESTAMPTZ IS NULL OR occurred_at >= $3)
+               AND ($4::TIMESTAMPTZ IS NULL OR occurred_at < $4)
+               AND ($5::BIGINT IS NULL OR id < $5)
+             ORDER BY id DESC
+             LIMIT $6",
+            &[
+                &filter.actor_id,
+                &filter.action.map(|action| action.as_str()),
+                &filter.from,
+                &filter.to,
+                &filter.before_id,
+                &limit,
+            ],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(rows
+        .iter()
+        .map(|row| AuditLogEntry {
+            id: row.get("id"),
+            occurred_at: row.get("occurred_at"),
+            action: row.get("action"),
+            actor_id: row.get("actor_id"),
+            target_id: row.get("target_id"),
+            ip: row.get("ip"),
+            user_agent: row.get("user_agent"),
+            request_id: row.get("request_id"),
+            impersonator_id: row.get("impersonator_id"),
+            details: row.get("details"),
+        })
+        .collect())
+}
+
+// An OpenID Connect authorization request waiting for the user to return from the provider
+#[derive(Debug, Clone)]
+pub struct OidcLoginState {
+    pub code_verifier: String,
+    pub nonce: String,
+    pub link_user_id: Option<i64>,
+}
+
+pub async fn insert_oidc_login_state(
+    client: &Client,
+    state_hash: &str,
+    provider: &str,
+    login_state: &OidcLoginState,
+    expires_at: DateTime<Utc>,
+) -> Result<(), AppError> {
+    // Abandoned requests are cleaned up here rather than by a background task
+    client
+        .execute("DELETE FROM oidc_login_states WHERE expires_at <= NOW()", &[])
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    client
+        .execute(
+            "INSERT INTO oidc_login_states (state_hash, provider, code_verifier, nonce, link_user_id, expires_at)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            &[
+                &state_hash,
+                &provider,
+                &login_state.code_verifier,
+                &login_state.nonce,
+                &login_state.link_user_id,
+                &expires_at,
+            ],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
     Ok(())
 }
 
+// Removes an authorization request and returns it unless it has expired, so each `state`
+// can be used only once
+pub async fn take_oidc_login_state(client: &Client, state_hash: &str, provider: &str) -> Result<Option<OidcLoginState>, AppError> {
+    let row = client
+        .query_opt(
+            "DELETE FROM oidc_login_states WHERE state_hash = $1 AND provider = $2
+             RETURNING code_verifier, nonce, link_user_id, expires_at > NOW() AS unexpired",
+            &[&state_hash, &provider],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(row.filter(|row| row.get::<_, bool>("unexpired")).map(|row| OidcLoginState {
+        code_verifier: row.get("code_verifier"),
+        nonce: row.get("nonce"),
+        link_user_id: row.get("link_user_id"),
+    }))
+}
+
+pub async fn find_user_by_identity(client: &Client, issuer: &str, subject: &str) -> Result<Option<User>, AppError> {
+    let row = client
+        .query_opt(
+            format!(
+                "SELECT {} FROM users
+                 WHERE id = (SELECT user_id FROM user_identities WHERE issuer = $1 AND subject = $2) AND deleted_at IS NULL",
+                USER_COLUMNS
+            )
+            .as_str(),
+            &[&issuer, &subject],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    Ok(row.as_ref().map(user_from_row))
+}
+
+// Links an external account to a user. Returns false if it is already linked to another user.
+pub async fn link_identity(
+    client: &Client,
+    user_id: i64,
+    provider: &str,
+    issuer: &str,
+    subject: &str,
+    email: Option<&str>,
+) -> Result<bool, AppError> {
+    let linked = client
+        .execute(
+            "INSERT INTO user_identities (user_id, provider, issuer, subject, email) VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (issuer, subject) DO UPDATE SET email = EXCLUDED.email
+             WHERE user_identities.user_id = EXCLUDED.user_id",
+            &[&user_id, &provider, &issuer, &subject, &email],
+        )
+        .await
+        .map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(linked > 0)
+}
+
+// Creates an account without a password for an external identity and links the two.
+// Returns `None` if the username is taken, so the caller can try another one; the unique
+// constraints decide, since a check beforehand could race with another sign-up.
+pub async fn insert_external_user(
+    client: &mut Client,
+    email: &str,
+    username: &str,
+    status: UserStatus,
+    provider: &str,
+    issuer: &str,
+    subject: &str,
+) -> Result<Option<User>, AppError> {
+    validate_email_format(email)?;
+    validate_username(username)?;
+    let transaction = client.transaction().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    let row = match transaction
+        .query_one(
+            format!("INSERT INTO users (email, username, password, status) VALUES ($1, $2, NULL, $3) RETURNING {}", USER_COLUMNS).as_str(),
+            &[&email, &username, &status],
+        )
+        .await
+    {
+        Ok(row) => row,
+        Err(e) => match violated_constraint(&e) {
+            Some("users_username_key") => return Ok(None),
+            Some("users_email_key") => {
+                return Err(AppError::BadRequest(
+                    "An account with this email address already exists; log in and link the provider to it".to_string(),
+                ))
+            }
+            _ => return Err(AppError::DatabaseError(e.to_string())),
+        },
+    };
+    let user = user_from_row(&row);
+    transaction
+        .execute(
+            "INSERT INTO user_identities (user_id, provider, issuer, subject, email) VALUES ($1, $2, $3, $4, $5)",
+            &[&user.id, &provider, &issuer, &subject, &email],
+        )
+        .await
+        .map_err(|e| match violated_constraint(&e) {
+            // A concurrent callback for the same provider account got there first
+            Some("user_identities_issuer_subject_key") => {
+                AppError::BadRequest("This provider account is already linked to another user".to_string())
+            }
+            _ => AppError::DatabaseError(e.to_string()),
+        })?;
+
+    transaction.commit().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+    Ok(Some(user))
+}
+
+// Name of the unique constraint a statement failed on, if that is why it failed
+fn violated_constraint(error: &tokio_postgres::Error) -> Option<&str> {
+    error
+        .as_db_error()
+        .filter(|db_error| *db_error.code() == SqlState::UNIQUE_VIOLATION)
+        .and_then(|db_error| db_error.constraint())
+}
+
 pub async fn create_statistics_tables(client: &Client) -> Result<(), tokio_postgres::Error> {
     client
         .batch_execute(
@@ -139,13 +1454,50 @@ pub async fn create_statistics_tables(client: &Client) -> Result<(), tokio_postg
                 total_requests BIGINT NOT NULL,
                 avg_response_time DOUBLE PRECISION NOT NULL,
                 error_rate DOUBLE PRECISION NOT NULL,
-                uptime DOUBLE PRECISION NOT NULL,
-                register_requests BIGINT NOT NULL,
-                register_success BIGINT NOT NULL,
-                get_user_requests BIGINT NOT NULL,
-                get_user_success BIGINT NOT NULL
+                uptime DOUBLE PRECISION NOT NULL
+            );
+
+            -- One row per registered metric and statistic at each save: `value` for counters
+            -- and gauges; `count`, `sum`, `p50`, `p95`, `p99` and `max` for histograms
+            CREATE TABLE IF NOT EXISTS api_metric_samples (
+                id BIGSERIAL PRIMARY KEY,
+                timestamp TIMESTAMP WITH TIME ZONE NOT NULL,
+                name TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                statistic TEXT NOT NULL,
+                value DOUBLE PRECISION NOT NULL
             );
 
+            -- Endpoint counters are registered metrics now. Counters saved in the old columns of
+            -- `api_statistics` are copied to `api_metric_samples` before the columns go, so
+            -- their history survives; the columns only exist on databases created before.
+            DO $$
+            BEGIN
+                IF EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_schema = current_schema()
+                      AND table_name = 'api_statistics'
+                      AND column_name = 'register_requests'
+                ) THEN
+                    EXECUTE '
+                        INSERT INTO api_metric_samples (timestamp, name, kind, statistic, value)
+                        SELECT s.timestamp, c.name, ''counter'', ''value'', c.value
+                        FROM api_statistics s
+                        CROSS JOIN LATERAL (VALUES
+                            (''register_requests'', s.register_requests::DOUBLE PRECISION),
+                            (''register_success'', s.register_success::DOUBLE PRECISION),
+                            (''get_user_requests'', s.get_user_requests::DOUBLE PRECISION),
+                            (''get_user_success'', s.get_user_success::DOUBLE PRECISION)
+                        ) AS c(name, value)';
+                    ALTER TABLE api_statistics
+                        DROP COLUMN register_requests,
+                        DROP COLUMN register_success,
+                        DROP COLUMN get_user_requests,
+                        DROP COLUMN get_user_success;
+                END IF;
+            END
+            $$;
+
             CREATE TABLE IF NOT EXISTS api_traffic_distribution (
                 id BIGSERIAL PRIMARY KEY,
                 timestamp TIMESTAMP WITH TIME ZONE NOT NULL,
@@ -161,13 +1513,34 @@ pub async fn create_statistics_tables(client: &Client) -> Result<(), tokio_postg
                 status SMALLINT NOT NULL
             );
 
+            ALTER TABLE api_request_log ADD COLUMN IF NOT EXISTS impersonated_by BIGINT;
+
             CREATE TABLE IF NOT EXISTS api_error_log (
                 id BIGSERIAL PRIMARY KEY,
                 timestamp TIMESTAMP WITH TIME ZONE NOT NULL,
                 message TEXT NOT NULL
             );
 
+            -- Response times per route and method, for the requests between two saves
+            CREATE TABLE IF NOT EXISTS api_latency_histogram (
+                id BIGSERIAL PRIMARY KEY,
+                timestamp TIMESTAMP WITH TIME ZONE NOT NULL,
+                method TEXT NOT NULL,
+                route TEXT NOT NULL,
+                count BIGINT NOT NULL,
+                sum_ms DOUBLE PRECISION NOT NULL,
+                p50_ms DOUBLE PRECISION NOT NULL,
+                p95_ms DOUBLE PRECISION NOT NULL,
+                p99_ms DOUBLE PRECISION NOT NULL,
+                max_ms DOUBLE PRECISION NOT NULL,
+                -- Counts of the `statistics::histogram` buckets, the last one unbounded
+                buckets BIGINT[] NOT NULL
+            );
+
             CREATE INDEX IF NOT EXISTS idx_api_statistics_timestamp ON api_statistics(timestamp);
+            CREATE INDEX IF NOT EXISTS idx_api_metric_samples_timestamp ON api_metric_samples(timestamp);
+            CREATE INDEX IF NOT EXISTS idx_api_metric_samples_name_timestamp ON api_metric_samples(name, timestamp);
+            CREATE INDEX IF NOT EXISTS idx_api_latency_histogram_timestamp ON api_latency_histogram(timestamp);
             CREATE INDEX IF NOT EXISTS idx_api_traffic_distribution_timestamp ON api_traffic_distribution(timestamp);
             CREATE INDEX IF NOT EXISTS idx_api_request_log_timestamp ON api_request_log(timestamp);
             CREATE INDEX IF NOT EXISTS idx_api_error_log_timestamp ON api_error_log(timestamp);
@@ -183,14 +1556,25 @@ pub async fn insert_statistics(client: &Client, data: &StatisticsData) -> Result
     // Insert main statistics
     client
         .execute(
-            "INSERT INTO api_statistics (timestamp, total_requests, avg_response_time, error_rate, uptime, register_requests, register_success, get_user_requests, get_user_success)
-             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
-            &[&timestamp, &data.total_requests, &data.avg_response_time, &data.error_rate, &data.uptime, 
-              &(data.register_requests as i64), &(data.register_success as i64), 
-              &(data.get_user_requests as i64), &(data.get_user_success as i64)],
+            "INSERT INTO api_statistics (timestamp, total_requests, avg_response_time, error_rate, uptime)
+             VALUES ($1, $2, $3, $4, $5)",
+            &[&timestamp, &data.total_requests, &data.avg_response_time, &data.error_rate, &data.uptime],
         )
         .await?;
 
+    // Insert registered metrics
+    for (name, metric) in &data.metrics {
+        for (statistic, value) in metric.samples() {
+            client
+                .execute(
+                    "INSERT INTO api_metric_samples (timestamp, name, kind, statistic, value)
+                     VALUES ($1, $2, $3, $4, $5)",
+                    &[&timestamp, name, &metric.kind().as_str(), &statistic, &value],
+                )
+                .await?;
+        }
+    }
+
     // Insert traffic distribution
     for (route, count) in &data.traffic_distribution {
         client
@@ -206,9 +1590,35 @@ pub async fn insert_statistics(client: &Client, data: &StatisticsData) -> Result
     for request in &data.last_requests {
         client
             .execute(
-                "INSERT INTO api_request_log (timestamp, method, endpoint, status)
-                 VALUES ($1, $2, $3, $4)",
-                &[&request.timestamp, &request.method, &request.endpoint, &(request.status as i16)],
+                "INSERT INTO api_request_log (timestamp, method, endpoint, status, impersonated_by)
+                 VALUES ($1, $2, $3, $4, $5)",
+                &[&request.timestamp, &request.method, &request.endpoint, &(request.status as i16), &request.impersonated_by],
+            )
+            .await?;
+    }
+
+    // Insert latency histograms
+    for latency in &data.latency {
+        let (sum, buckets) = match &latency.histogram {
+            Some(histogram) => (histogram.sum(), histogram.counts().iter().map(|count| *count as i64).collect()),
+            None => (0.0, Vec::new()),
+        };
+        client
+            .execute(
+                "INSERT INTO api_latency_histogram (timestamp, method, route, count, sum_ms, p50_ms, p95_ms, p99_ms, max_ms, buckets)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+                &[
+                    &timestamp,
+                    &latency.method,
+                    &latency.route,
+                    &(latency.summary.count as i64),
+                    &sum,
+                    &latency.summary.p50,
+                    &latency.summary.p95,
+                    &latency.summary.p99,
+                    &latency.summary.max,
+                    &buckets,
+                ],
             )
             .await?;
     }
@@ -242,14 +1652,12 @@ pub async fn get_latest_statistics(client: &Client) -> Result<StatisticsData, to
         avg_response_time: row.get("avg_response_time"),
         error_rate: row.get("error_rate"),
         uptime: row.get("uptime"),
-        register_requests: row.get::<_, i64>("register_requests") as usize,
-        register_success: row.get::<_, i64>("register_success") as usize,
-        get_user_requests: row.get::<_, i64>("get_user_requests") as usize,
-        get_user_success: row.get::<_, i64>("get_user_success") as usize,
         timestamp: row.get("timestamp"),
         traffic_distribution: HashMap::new(), // We'll populate this separately
         last_requests: Vec::new(), // We'll populate this separately
         error_log: Vec::new(), // We'll populate this separately
+        latency: Vec::new(), // We'll populate this separately
+        metrics: BTreeMap::new(), // We'll populate this separately
         last_saved: None, // This will be set to the timestamp from the database
     })
 }
@@ -257,7 +1665,8 @@ pub async fn get_latest_statistics(client: &Client) -> Result<StatisticsData, to
 pub async fn get_traffic_distribution(client: &Client) -> Result<HashMap<String, u64>, tokio_postgres::Error> {
     let rows = client
         .query(
-            "SELECT route, SUM(count) as total FROM api_traffic_distribution GROUP BY route",
+            // SUM of a BIGINT is a NUMERIC
+            "SELECT route, SUM(count)::BIGINT as total FROM api_traffic_distribution GROUP BY route",
             &[],
         )
         .await?;
@@ -287,12 +1696,68 @@ pub async fn get_last_requests(client: &Client) -> Result<Vec<RequestLog>, tokio
             endpoint: row.get("endpoint"),
             status: row.get::<_, i16>("status") as u16,
             timestamp: row.get("timestamp"),
+            impersonated_by: row.get("impersonated_by"),
         })
         .collect();
 
     Ok(requests)
 }
 
+// Response time percentiles of the most recent save
+pub async fn get_latest_latency(client: &Client) -> Result<Vec<RouteLatency>, tokio_postgres::Error> {
+    let rows = client
+        .query(
+            "SELECT method, route, count, p50_ms, p95_ms, p99_ms, max_ms FROM api_latency_histogram
+             WHERE timestamp = (SELECT MAX(timestamp) FROM api_latency_histogram)
+             ORDER BY route, method",
+            &[],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| RouteLatency {
+            method: row.get("method"),
+            route: row.get("route"),
+            summary: LatencySummary {
+                count: row.get::<_, i64>("count") as u64,
+                p50: row.get("p50_ms"),
+                p95: row.get("p95_ms"),
+                p99: row.get("p99_ms"),
+                max: row.get("max_ms"),
+            },
+            histogram: None,
+        })
+        .collect())
+}
+
+// Registered metrics as of the most recent save
+pub async fn get_latest_metrics(client: &Client) -> Result<BTreeMap<String, MetricSnapshot>, tokio_postgres::Error> {
+    let rows = client
+        .query(
+            "SELECT name, kind, statistic, value FROM api_metric_samples
+             WHERE timestamp = (SELECT MAX(timestamp) FROM api_metric_samples)",
+            &[],
+        )
+        .await?;
+
+    let mut samples: BTreeMap<String, (String, BTreeMap<String, f64>)> = BTreeMap::new();
+    for row in rows {
+        let (_, statistics) = samples
+            .entry(row.get("name"))
+            .or_insert_with(|| (row.get("kind"), BTreeMap::new()));
+        statistics.insert(row.get("statistic"), row.get("value"));
+    }
+
+    // Skips kinds this version doesn't know, e.g. ones written by a newer server
+    Ok(samples
+        .into_iter()
+        .filter_map(|(name, (kind, statistics))| {
+            MetricKind::parse(&kind).map(|kind| (name, MetricSnapshot::from_samples(kind, &statistics)))
+        })
+        .collect())
+}
+
 pub async fn get_error_log(client: &Client) -> Result<Vec<ErrorLog>, tokio_postgres::Error> {
     let rows = client
         .query(
@@ -317,12 +1782,20 @@ mod tests {
     use super::*;
     // We don't need a real client for testing validate_new_user_input
 
+    const TEST_BCRYPT_HASH: &str = "$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW";
+
+    #[test]
+    fn test_escape_like() {
+        assert_eq!(escape_like("alice"), "alice");
+        assert_eq!(escape_like("50%_off\\"), "50\\%\\_off\\\\");
+    }
+
     #[test]
     fn test_validate_new_user_input_valid() {
         let result = validate_new_user_input(
             "test@example.com",
             "validuser",
-            "valid_bcrypt_hash_string_of_exactly_60_characters_long_abcXY", // Now 60 chars
+            TEST_BCRYPT_HASH,
         );
         assert!(result.is_ok());
     }
@@ -332,7 +1805,7 @@ mod tests {
         let result = validate_new_user_input(
             "invalid-email",
             "validuser",
-            "valid_bcrypt_hash_string_of_exactly_60_characters_long_abc",
+            TEST_BCRYPT_HASH,
         );
         assert!(result.is_err());
         match result.unwrap_err() {
@@ -346,7 +1819,7 @@ mod tests {
         let result = validate_new_user_input(
             "test@example.com",
             "ab", // Too short
-            "valid_bcrypt_hash_string_of_exactly_60_characters_long_abc",
+            TEST_BCRYPT_HASH,
         );
         assert!(result.is_err());
         match result.unwrap_err() {
@@ -360,7 +1833,7 @@ mod tests {
         let result = validate_new_user_input(
             "test@example.com",
             "thisusernameiswaytoolongandshouldfailvalidation", // Too long
-            "valid_bcrypt_hash_string_of_exactly_60_characters_long_abc",
+            TEST_BCRYPT_HASH,
         );
         assert!(result.is_err());
         match result.unwrap_err() {
@@ -374,7 +1847,7 @@ mod tests {
         let result = validate_new_user_input(
             "test@example.com",
             "user name with spaces", // Invalid chars
-            "valid_bcrypt_hash_string_of_exactly_60_characters_long_abc",
+            TEST_BCRYPT_HASH,
         );
         assert!(result.is_err());
         match result.unwrap_err() {
@@ -383,6 +1856,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_deleted_account_placeholders_are_never_valid() {
+        // Mirrors the placeholders `soft_delete_user` sets
+        assert!(validate_username("deleted-42").is_err());
+        assert!(validate_email_format("deleted-42").is_err());
+    }
+
+    #[test]
+    fn test_validate_username_rejects_email_addresses() {
+        // `get_user_credentials` relies on this to tell usernames and emails apart
+        assert!(validate_username("alice@example.com").is_err());
+        assert!(validate_username("alice").is_ok());
+    }
+
     #[test]
     fn test_validate_new_user_input_password_hash_too_short() {
         let result = validate_new_user_input(
@@ -411,6 +1898,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_validate_password_hash_accepts_known_schemes() {
+        assert!(validate_password_hash(TEST_BCRYPT_HASH).is_ok());
+        assert!(validate_password_hash("$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHRzYWx0$aGFzaGhhc2hoYXNoaGFzaA").is_ok());
+        // A 60 character string that isn't a bcrypt hash
+        assert!(validate_password_hash("valid_bcrypt_hash_string_of_exactly_60_characters_long_abcXY").is_err());
+        assert!(validate_password_hash("$2b$12$tooshort").is_err());
+    }
+
     // The function `establish_connection` could be tested if we could mock `Config::create_pool`
     // or by trying to connect to a dummy/non-existent DB and checking the error type,
     // but that leans more towards integration testing or requires more setup.
@@ -1,4 +1,4 @@
-use actix_web::{http::StatusCode, ResponseError}; // Removed HttpResponse
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 use serde::Serialize;
 use std::fmt;
 use thiserror::Error;
@@ -17,10 +17,35 @@ pub enum AppError {
     BadRequest(String),
     #[error("Unauthorized")]
     Unauthorized,
+    #[error("Forbidden")]
+    Forbidden,
+    #[error("Account inactive: {0}")]
+    AccountInactive(String),
     #[error("Rate Limit Exceeded")]
     RateLimitExceeded,
     #[error("Database error: {0}")]
     DatabaseError(String),
+    // Input rejected for one or more fields, answered with the list of problems
+    #[error("Validation failed")]
+    ValidationFailed(Vec<FieldError>),
+}
+
+// One problem with one input field, e.g. `password` / `too_short`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, code: &str, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.to_string(),
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
 }
 
 // Define the structure for error responses
@@ -30,6 +55,8 @@ pub struct ErrorResponse {
     pub code: u16,
     pub message: String,
     pub error_type: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
 }
 
 // Implement ResponseError trait for AppError
@@ -41,8 +68,27 @@ impl ResponseError for AppError {
             AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
             AppError::NotFound => StatusCode::NOT_FOUND,
             AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::AccountInactive(_) => StatusCode::FORBIDDEN,
             AppError::RateLimitExceeded => StatusCode::TOO_MANY_REQUESTS,
             AppError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR, // Add this line
+            AppError::ValidationFailed(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    // Field errors are returned as JSON so clients can show them next to the inputs;
+    // other errors keep the default plain text body
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AppError::ValidationFailed(errors) => HttpResponse::build(self.status_code()).json(ErrorResponse {
+                code: self.status_code().as_u16(),
+                message: self.to_string(),
+                error_type: "ValidationFailed".to_string(),
+                errors: errors.clone(),
+            }),
+            _ => HttpResponse::build(self.status_code())
+                .content_type("text/plain; charset=utf-8")
+                .body(self.to_string()),
         }
     }
 }
@@ -71,6 +117,8 @@ mod tests {
         assert_eq!(AppError::NotFound.status_code(), StatusCode::NOT_FOUND);
         assert_eq!(AppError::BadRequest("test".to_string()).status_code(), StatusCode::BAD_REQUEST);
         assert_eq!(AppError::Unauthorized.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(AppError::Forbidden.status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(AppError::AccountInactive("Account is banned".to_string()).status_code(), StatusCode::FORBIDDEN);
         assert_eq!(AppError::RateLimitExceeded.status_code(), StatusCode::TOO_MANY_REQUESTS);
         assert_eq!(AppError::DatabaseError("db issue".to_string()).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
     }
@@ -82,6 +130,8 @@ mod tests {
         assert_eq!(AppError::NotFound.to_string(), "Not Found");
         assert_eq!(AppError::BadRequest("test message".to_string()).to_string(), "Bad Request: test message");
         assert_eq!(AppError::Unauthorized.to_string(), "Unauthorized");
+        assert_eq!(AppError::Forbidden.to_string(), "Forbidden");
+        assert_eq!(AppError::AccountInactive("Account is banned".to_string()).to_string(), "Account inactive: Account is banned");
         assert_eq!(AppError::RateLimitExceeded.to_string(), "Rate Limit Exceeded");
         assert_eq!(AppError::DatabaseError("connection failed".to_string()).to_string(), "Database error: connection failed");
     }
@@ -93,6 +143,7 @@ mod tests {
             code: 404,
             message: "Resource not found".to_string(),
             error_type: "NotFound".to_string(),
+            errors: Vec::new(),
         };
         assert_eq!(
             error_response.to_string(),
@@ -107,11 +158,26 @@ mod tests {
             code: 500,
             message: "Server issue".to_string(),
             error_type: "InternalServerError".to_string(),
+            errors: Vec::new(),
         };
         let serialized = serde_json::to_string(&error_response).unwrap();
         // Basic check, could be more specific if needed
         assert!(serialized.contains("\"code\":500"));
         assert!(serialized.contains("\"message\":\"Server issue\""));
         assert!(serialized.contains("\"error_type\":\"InternalServerError\""));
+        assert!(!serialized.contains("errors"));
+    }
+
+    #[actix_web::test]
+    async fn test_validation_failed_returns_field_errors_as_json() {
+        let error = AppError::ValidationFailed(vec![FieldError::new("password", "too_short", "Password is too short")]);
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+
+        let response = error.error_response();
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error_type"], "ValidationFailed");
+        assert_eq!(json["errors"][0]["field"], "password");
+        assert_eq!(json["errors"][0]["code"], "too_short");
     }
 }
\ No newline at end of file
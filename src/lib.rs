@@ -1,9 +1,12 @@
 // Module declarations - these will be shared between the library and main.rs
+pub mod audit;
 pub mod auth;
+pub mod clock;
 pub mod config;
 pub mod db;
 pub mod error;
 pub mod logger;
+pub mod mail;
 pub mod middleware;
 pub mod routes;
 pub mod statistics;
@@ -24,11 +27,8 @@ use log::error as log_error; // aliased to avoid conflict if error module is use
 // This function will contain the core app configuration logic
 // It will be called by main.rs and by integration tests.
 pub fn configure_app_routes(cfg: &mut web::ServiceConfig) {
-    // This is where the .configure(routes::config) and .configure(routes::statistics::config)
-    // calls from main.rs will go.
-    // Example:
-    cfg.configure(routes::config)
-       .configure(routes::statistics::config);
+    // Statistics routes are configured inside routes::config, behind authentication
+    cfg.configure(routes::config);
 }
 
 // Placeholder for a function that might start the server, to be called from main.rs
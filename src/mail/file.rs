@@ -0,0 +1,68 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use super::{Email, MailError, Mailer};
+
+// Writes every email to its own `.eml` file instead of sending it, for local development
+pub struct FileMailer {
+    dir: PathBuf,
+    from: String,
+}
+
+impl FileMailer {
+    pub fn new(dir: &str, from: &str) -> Result<Self, MailError> {
+        std::fs::create_dir_all(dir)?;
+        Ok(FileMailer {
+            dir: PathBuf::from(dir),
+            from: from.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        let now = Utc::now();
+        let path = self
+            .dir
+            .join(format!("{}_{}.eml", now.format("%Y%m%dT%H%M%S%.6f"), Uuid::new_v4()));
+        let contents = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\r\n{}",
+            self.from,
+            email.to,
+            email.subject,
+            now.to_rfc2822(),
+            email.body
+        );
+        tokio::fs::write(&path, contents).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_file_mailer_writes_one_file_per_email() {
+        let dir = std::env::temp_dir().join(format!("file_mailer_test_{}", Uuid::new_v4()));
+        let mailer = FileMailer::new(dir.to_str().unwrap(), "no-reply@example.com").unwrap();
+        let email = Email {
+            to: "alice@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "Body text".to_string(),
+        };
+        mailer.send(&email).await.unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let contents = std::fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(contents.contains("To: alice@example.com\r\n"));
+        assert!(contents.contains("Subject: Hello\r\n"));
+        assert!(contents.ends_with("Body text"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+use super::{Email, MailError, Mailer};
+
+// Keeps sent emails in memory so tests can read them back
+#[derive(Debug, Default)]
+pub struct InMemoryMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl InMemoryMailer {
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // The most recent email sent to `to`, if any
+    pub fn last_to(&self, to: &str) -> Option<Email> {
+        self.sent().into_iter().rev().find(|email| email.to == to)
+    }
+}
+
+#[async_trait]
+impl Mailer for InMemoryMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        self.sent.lock().unwrap_or_else(|e| e.into_inner()).push(email.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(to: &str, subject: &str) -> Email {
+        Email {
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body: String::new(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_last_to_returns_latest_email_for_recipient() {
+        let mailer = InMemoryMailer::default();
+        mailer.send(&email("a@example.com", "first")).await.unwrap();
+        mailer.send(&email("b@example.com", "other")).await.unwrap();
+        mailer.send(&email("a@example.com", "second")).await.unwrap();
+
+        assert_eq!(mailer.sent().len(), 3);
+        assert_eq!(mailer.last_to("a@example.com").unwrap().subject, "second");
+        assert!(mailer.last_to("c@example.com").is_none());
+    }
+}
@@ -0,0 +1,120 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::config::{MailConfig, MailTransport};
+
+pub mod file;
+pub mod memory;
+pub mod smtp;
+
+pub use file::FileMailer;
+pub use memory::InMemoryMailer;
+pub use smtp::SmtpMailer;
+
+#[derive(Error, Debug)]
+pub enum MailError {
+    #[error("Missing mail setting: {0}")]
+    Missing(&'static str),
+    #[error("Invalid email address {address}: {reason}")]
+    InvalidAddress { address: String, reason: String },
+    #[error("Failed to write email: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to send email: {0}")]
+    Transport(String),
+}
+
+// A plain-text email ready to be delivered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Delivers emails. Handlers take it as `web::Data<dyn Mailer>` so the transport can be swapped by configuration.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+pub fn build_mailer(config: &MailConfig) -> Result<Arc<dyn Mailer>, MailError> {
+    Ok(match config.transport {
+        MailTransport::Smtp => Arc::new(SmtpMailer::new(config)?),
+        MailTransport::File => Arc::new(FileMailer::new(
+            config.file_dir.as_deref().ok_or(MailError::Missing("mail.file_dir"))?,
+            &config.from,
+        )?),
+        MailTransport::Memory => Arc::new(InMemoryMailer::default()),
+    })
+}
+
+pub fn verification_email(to: &str, link: &str, valid_for_minutes: u64) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Welcome!\n\nPlease confirm your email address by opening the link below:\n\n{}\n\n\
+             The link is valid for {} minutes. If you did not create an account, you can ignore this email.\n",
+            link, valid_for_minutes
+        ),
+    }
+}
+
+pub fn email_change_email(to: &str, link: &str, valid_for_minutes: u64) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Confirm your new email address".to_string(),
+        body: format!(
+            "Someone asked to use this address for their account.\n\n\
+             To confirm the change, open the link below:\n\n{}\n\n\
+             The link is valid for {} minutes. Until it is opened, the account keeps its old address. \
+             If you did not ask for this, you can ignore this email.\n",
+            link, valid_for_minutes
+        ),
+    }
+}
+
+pub fn password_reset_email(to: &str, link: &str, valid_for_minutes: u64) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Someone asked to reset the password of your account.\n\n\
+             To choose a new password, open the link below:\n\n{}\n\n\
+             The link can be used once and is valid for {} minutes. If you did not ask for this, \
+             you can ignore this email; your password stays the same.\n",
+            link, valid_for_minutes
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verification_email_contains_link() {
+        let email = verification_email("alice@example.com", "http://localhost/api/verify-email?token=abc", 30);
+        assert_eq!(email.to, "alice@example.com");
+        assert!(email.body.contains("http://localhost/api/verify-email?token=abc"));
+        assert!(email.body.contains("30 minutes"));
+    }
+
+    #[test]
+    fn test_email_change_email_goes_to_the_new_address() {
+        let email = email_change_email("new@example.com", "http://localhost/api/verify-email?token=abc", 1440);
+        assert_eq!(email.to, "new@example.com");
+        assert_eq!(email.subject, "Confirm your new email address");
+        assert!(email.body.contains("http://localhost/api/verify-email?token=abc"));
+        assert!(email.body.contains("1440 minutes"));
+    }
+
+    #[test]
+    fn test_password_reset_email_contains_link() {
+        let email = password_reset_email("alice@example.com", "http://localhost/reset-password?token=abc", 60);
+        assert_eq!(email.subject, "Reset your password");
+        assert!(email.body.contains("http://localhost/reset-password?token=abc"));
+        assert!(email.body.contains("60 minutes"));
+    }
+}
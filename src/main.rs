@@ -1,6 +1,13 @@
 // Use items from the library crate
 use my_actix_api::{
     AppConfig,
+    audit::AuditLogger,
+    auth::{lockout::LoginThrottle, oidc::OidcProviders, sessions::SessionCache, Credential, JwtKeys, PasswordHashers, PasswordPolicy},
+    clock::{Clock, SystemClock},
+    mail::build_mailer,
+    middleware::{rate_limiter::MailRateLimiter, request_id::AssignRequestId},
+    routes::UserMetrics,
+    statistics::{prometheus::ScrapeAllowlist, route_of},
     // Pool, // Pool is used via db_pool which is typed, direct import not needed
     Statistics,
     RateLimiter,
@@ -14,11 +21,10 @@ use my_actix_api::{
     // logger, // logger module itself not directly used, log macros are via `log` crate
 };
 
-use actix_web::{web, App, HttpServer};
+use actix_web::{web, App, HttpMessage, HttpServer};
 use std::sync::Arc;
+use std::time::Instant;
 use chrono::Utc;
-use log; // Ensure log macros are available
-use log4rs; // For logger initialization
 use futures::FutureExt; // For .map on futures
 use actix_web::dev::Service; // For srv.call
 
@@ -26,6 +32,7 @@ use actix_web::dev::Service; // For srv.call
 async fn main() -> std::io::Result<()> {
     // Load application configuration using AppConfig from the library
     let app_config = AppConfig::new().expect("Failed to load configuration");
+    app_config.validate().expect("Invalid configuration");
    
     // Initialize the logger (assuming log4rs.yaml is still the config file)
     // If logger::init_logger is now in lib.rs, call that.
@@ -41,16 +48,58 @@ async fn main() -> std::io::Result<()> {
     // This specific test_connection might not be pub. If it's important, it should be.
     // For now, assume establish_connection is enough indication.
 
+    // Load the JWT signing and verification keys once, shared by all workers
+    let jwt_keys = web::Data::new(
+        JwtKeys::from_config(&app_config.auth).expect("Failed to load JWT keys")
+    );
+
+    // Mail transport and the limiter for verification emails, shared by all workers
+    let mailer = web::Data::from(build_mailer(&app_config.mail).expect("Failed to set up mail transport"));
+    let mail_rate_limiter = web::Data::new(MailRateLimiter::per_hour(app_config.mail.resend_per_hour));
+
+    let password_hashers = web::Data::new(
+        PasswordHashers::from_config(&app_config.password_hashing).expect("Invalid password hashing settings")
+    );
+
+    let password_policy = web::Data::new(
+        PasswordPolicy::from_config(&app_config.password_policy).expect("Failed to load the breached password list")
+    );
+
+    // Failed logins per client IP, shared by all workers
+    let login_throttle = web::Data::new(LoginThrottle::new(app_config.lockout.clone()));
+
+    // External sign-in providers; their metadata and keys are cached and shared by all workers
+    let oidc_providers = web::Data::new(OidcProviders::from_config(&app_config.oidc));
+
+    // Revocation state of login sessions, shared by all workers
+    let session_cache = web::Data::new(SessionCache::new(
+        app_config.auth.session_cache_seconds,
+        app_config.auth.token_ttl_seconds,
+    ));
+
+    // Security audit log, written to the `audit_log` table
+    let audit_logger = web::Data::new(AuditLogger::new(db_pool.clone()));
+
+    let clock: web::Data<dyn Clock> = web::Data::from(Arc::new(SystemClock) as Arc<dyn Clock>);
+
     // Create rate limiter middleware
     let rate_limiter = RateLimiter::new(
         app_config.rate_limit.requests_per_second,
         app_config.rate_limit.burst_size,
     );
 
+    // Networks allowed to scrape `/metrics`
+    let scrape_allowlist = web::Data::new(
+        ScrapeAllowlist::new(&app_config.metrics.allowed_networks).expect("Invalid metrics settings")
+    );
+
     log::info!("Starting server at {}:{}", app_config.server.host, app_config.server.port);
 
     let statistics_manager = Arc::new(Statistics::new());
 
+    // Endpoint counters, registered once and shared by all workers
+    let user_metrics = web::Data::new(UserMetrics::register(statistics_manager.metrics()));
+
     // Initialize database schema (e.g., create tables if they don't exist)
     // This replaces the direct call to db::create_statistics_tables
     if let Err(e) = init_database_schema(&db_pool).await {
@@ -73,6 +122,19 @@ async fn main() -> std::io::Result<()> {
         let factory_statistics_arc = Arc::clone(&server_statistics);
         let factory_app_config = server_app_config.clone();
         let factory_rate_limiter = rate_limiter.clone();
+        let factory_rate_limiter_data = web::Data::new(rate_limiter.clone());
+        let factory_scrape_allowlist = scrape_allowlist.clone();
+        let factory_jwt_keys = jwt_keys.clone();
+        let factory_mailer = mailer.clone();
+        let factory_clock = clock.clone();
+        let factory_login_throttle = login_throttle.clone();
+        let factory_password_hashers = password_hashers.clone();
+        let factory_password_policy = password_policy.clone();
+        let factory_mail_rate_limiter = mail_rate_limiter.clone();
+        let factory_audit_logger = audit_logger.clone();
+        let factory_oidc_providers = oidc_providers.clone();
+        let factory_session_cache = session_cache.clone();
+        let factory_user_metrics = user_metrics.clone();
 
         App::new()
             .wrap(actix_web::middleware::Logger::default())
@@ -83,20 +145,29 @@ async fn main() -> std::io::Result<()> {
                 move |req, srv| { // This closure is the service factory
                     let stats_clone_for_log_task = Arc::clone(&stats_clone_for_wrap_service); // Clone #2 for the spawned task
                     let req_method_owned = req.method().clone().to_string();
-                    let req_path_owned = req.path().to_owned();
-                    let start_time = Utc::now();
+                    let start_time = Instant::now();
 
                     srv.call(req).map(move |res: Result<actix_web::dev::ServiceResponse<_>, actix_web::Error>| {
                         if let Ok(res_ok) = &res {
-                            let end_time = Utc::now();
-                            let duration = (end_time - start_time).num_milliseconds() as f64;
+                            // Milliseconds with sub-millisecond precision, for the latency histograms
+                            let duration = start_time.elapsed().as_secs_f64() * 1000.0;
                             let status_code = res_ok.status().as_u16();
+                            // The route template rather than the path, so `/api/user/1` and
+                            // `/api/user/2` are counted together
+                            let route = route_of(res_ok.request());
+                            // Set by `RequireAuth` for requests made with an impersonation token
+                            let impersonated_by = res_ok
+                                .request()
+                                .extensions()
+                                .get::<Credential>()
+                                .and_then(Credential::impersonator);
                             tokio::spawn(async move {
                                 stats_clone_for_log_task.log_request(
                                     &req_method_owned,
-                                    &req_path_owned,
+                                    &route,
                                     status_code,
                                     duration,
+                                    impersonated_by,
                                 ).await;
                             });
                         }
@@ -104,9 +175,24 @@ async fn main() -> std::io::Result<()> {
                     })
                 }
             }) // End of wrap_fn
+            // Registered last so it runs first and every other middleware sees the request id
+            .wrap(AssignRequestId)
             .app_data(web::Data::new(factory_db_pool))
             .app_data(web::Data::new(factory_statistics_arc)) // Use the original factory_statistics_arc
             .app_data(web::Data::new(factory_app_config))
+            .app_data(factory_jwt_keys)
+            .app_data(factory_mailer)
+            .app_data(factory_mail_rate_limiter)
+            .app_data(factory_clock)
+            .app_data(factory_login_throttle)
+            .app_data(factory_password_hashers)
+            .app_data(factory_password_policy)
+            .app_data(factory_audit_logger)
+            .app_data(factory_oidc_providers)
+            .app_data(factory_session_cache)
+            .app_data(factory_rate_limiter_data)
+            .app_data(factory_scrape_allowlist)
+            .app_data(factory_user_metrics)
             .configure(configure_app_routes)
     })
     .bind(format!("{}:{}", app_config.server.host, app_config.server.port))?
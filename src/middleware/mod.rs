@@ -1 +1,3 @@
-pub mod rate_limiter;
\ No newline at end of file
+pub mod auth;
+pub mod rate_limiter;
+pub mod request_id;
\ No newline at end of file
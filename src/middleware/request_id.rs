@@ -0,0 +1,118 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, Ready};
+use futures::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// Id of the current request, available from the request extensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+// Only ids that are safe to log and to echo in a header are taken from clients
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+// Middleware giving every request an id: the `X-Request-Id` sent by the client or a
+// proxy in front of the API if it is usable, a new UUID otherwise. The id is stored
+// as `RequestId` in the request extensions and returned in the `X-Request-Id` header.
+#[derive(Clone, Default)]
+pub struct AssignRequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for AssignRequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AssignRequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AssignRequestIdMiddleware { service })
+    }
+}
+
+pub struct AssignRequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for AssignRequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid_request_id(id))
+            .map(str::to_string)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+
+    async fn echo_request_id(req: HttpRequest) -> HttpResponse {
+        let id = req.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default();
+        HttpResponse::Ok().body(id)
+    }
+
+    #[actix_web::test]
+    async fn test_request_id_is_generated_and_returned() {
+        let app = test::init_service(App::new().wrap(AssignRequestId).route("/", web::get().to(echo_request_id))).await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        let header = res.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+        assert!(uuid::Uuid::parse_str(&header).is_ok());
+        assert_eq!(test::read_body(res).await, header.as_bytes());
+    }
+
+    #[actix_web::test]
+    async fn test_request_id_from_client_is_kept_if_valid() {
+        let app = test::init_service(App::new().wrap(AssignRequestId).route("/", web::get().to(echo_request_id))).await;
+
+        let req = test::TestRequest::get().uri("/").insert_header((REQUEST_ID_HEADER, "edge-1234.abc")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "edge-1234.abc");
+
+        let req = test::TestRequest::get().uri("/").insert_header((REQUEST_ID_HEADER, "no spaces allowed")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_ne!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "no spaces allowed");
+    }
+}
@@ -0,0 +1,464 @@
+use actix_web::{get, post, put, web, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::audit::{Audit, AuditAction, AuditEvent, AuditLogEntry};
+use crate::auth::permissions::{PermissionSet, StoredPermissions};
+use crate::auth::sessions::SessionCache;
+use crate::auth::{self, AuthenticatedUser, JwtKeys, Permission, UserStatus};
+use crate::db::{self, AuditLogFilter, SortOrder, UserListFilter, UserListPosition, UserSortField};
+use crate::error::AppError;
+use crate::middleware::auth::RequirePermission;
+use crate::statistics::Statistics;
+use crate::routes::user::UserResponse;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct UserListQuery {
+    status: Option<UserStatus>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    last_login_after: Option<DateTime<Utc>>,
+    last_login_before: Option<DateTime<Utc>>,
+    q: Option<String>,
+    #[serde(default)]
+    sort: UserSortField,
+    #[serde(default)]
+    order: SortOrder,
+    limit: Option<i64>,
+    cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UserListResponse {
+    users: Vec<UserResponse>,
+    next_cursor: Option<String>,
+}
+
+// Opaque pagination cursor. It remembers the sort it was issued for,
+// so it can't be replayed against a different ordering.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct UserListCursor {
+    sort: UserSortField,
+    order: SortOrder,
+    #[serde(flatten)]
+    position: UserListPosition,
+}
+
+impl UserListCursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(value).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditLogQuery {
+    actor_id: Option<i64>,
+    action: Option<AuditAction>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+    // `next_before_id` of the previous page
+    before_id: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AuditLogResponse {
+    entries: Vec<AuditLogEntry>,
+    next_before_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct StatusChange {
+    status: UserStatus,
+    reason: String,
+}
+
+#[derive(Serialize)]
+pub struct StatusChangeResponse {
+    user_id: i64,
+    old_status: UserStatus,
+    status: UserStatus,
+}
+
+#[derive(Deserialize)]
+pub struct ImpersonationRequest {
+    reason: String,
+}
+
+#[derive(Serialize)]
+pub struct ImpersonationResponse {
+    user_id: i64,
+    token: String,
+    expires_in: u64,
+}
+
+// Lists accounts page by page, newest or oldest first, with optional filters
+#[get("/users", wrap = "RequirePermission::new(Permission::UsersRead)")]
+pub async fn list_users(
+    pool: web::Data<Pool>,
+    query: web::Query<UserListQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(AppError::BadRequest(format!("limit must be between 1 and {}", MAX_PAGE_SIZE)));
+    }
+
+    let after = match query.cursor.as_deref() {
+        Some(cursor) => {
+            let cursor = UserListCursor::decode(cursor)
+                .ok_or_else(|| AppError::BadRequest("Invalid cursor".to_string()))?;
+            if cursor.sort != query.sort || cursor.order != query.order {
+                return Err(AppError::BadRequest("Cursor does not match the requested sort order".to_string()));
+            }
+            Some(cursor.position)
+        }
+        None => None,
+    };
+
+    let filter = UserListFilter {
+        status: query.status,
+        created_after: query.created_after,
+        created_before: query.created_before,
+        last_login_after: query.last_login_after,
+        last_login_before: query.last_login_before,
+        search: query.q.map(|q| q.trim().to_string()),
+        sort: query.sort,
+        order: query.order,
+        after,
+    };
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    // Fetch one extra row to find out whether another page follows
+    let mut users = db::list_users(&client, &filter, limit + 1).await?;
+    let next_cursor = if users.len() as i64 > limit {
+        users.truncate(limit as usize);
+        users.last().map(|last| {
+            UserListCursor {
+                sort: query.sort,
+                order: query.order,
+                position: UserListPosition {
+                    created_at: last.created_at,
+                    id: last.id,
+                },
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(UserListResponse {
+        users: users.into_iter().map(UserResponse::from).collect(),
+        next_cursor,
+    }))
+}
+
+#[put("/admin/users/{user_id}/permissions", wrap = "RequirePermission::new(Permission::UsersWrite)")]
+pub async fn update_permissions(
+    pool: web::Data<Pool>,
+    admin: AuthenticatedUser,
+    audit: Audit,
+    user_id: web::Path<i64>,
+    body: web::Json<StoredPermissions>,
+) -> Result<HttpResponse, AppError> {
+    // API keys and impersonation tokens can't hand out permissions
+    admin.require_session()?;
+    let user_id = user_id.into_inner();
+    if user_id == admin.user.id {
+        return Err(AppError::BadRequest("Admins cannot change their own permissions".to_string()));
+    }
+    let permissions = body.into_inner();
+    permissions.validate().map_err(AppError::BadRequest)?;
+
+    // Nobody can grant permissions they don't hold themselves
+    let admin_permissions = admin.user.permission_set();
+    if !PermissionSet::from_value(Some(&permissions.to_value())).is_subset(&admin_permissions) {
+        return Err(AppError::Forbidden);
+    }
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    // ... or take them away from someone who holds more than they do
+    let user = db::find_user_by_id(&client, user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    if !user.permission_set().is_subset(&admin_permissions) {
+        return Err(AppError::Forbidden);
+    }
+
+    if !db::update_user_permissions(&client, user_id, &permissions.to_value()).await? {
+        return Err(AppError::NotFound);
+    }
+
+    audit
+        .record(
+            AuditEvent::new(AuditAction::PermissionChange)
+                .actor(admin.user.id)
+                .target(user_id)
+                .details(permissions.to_value()),
+        );
+
+    info!(
+        "User {} set permissions of user {} to roles {:?}, permissions {:?}",
+        admin.user.id, user_id, permissions.roles, permissions.permissions
+    );
+    Ok(HttpResponse::Ok().json(permissions))
+}
+
+// Suspends, bans, deactivates or reactivates an account
+#[put("/admin/users/{user_id}/status", wrap = "RequirePermission::new(Permission::UsersWrite)")]
+pub async fn update_status(
+    pool: web::Data<Pool>,
+    sessions: web::Data<SessionCache>,
+    admin: AuthenticatedUser,
+    audit: Audit,
+    user_id: web::Path<i64>,
+    body: web::Json<StatusChange>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user_id.into_inner();
+    let reason = body.reason.trim();
+    if reason.is_empty() {
+        return Err(AppError::BadRequest("A reason is required".to_string()));
+    }
+    if user_id == admin.user.id {
+        return Err(AppError::BadRequest("Admins cannot change their own status".to_string()));
+    }
+
+    let mut client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let old_status = db::update_user_status(&mut client, user_id, body.status, reason, admin.user.id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+
+    // Sessions must not outlive the account being taken out of service
+    if !body.status.is_active() {
+        let revoked = db::revoke_user_sessions(&client, user_id).await?;
+        sessions.revoke(&revoked, Utc::now());
+    }
+
+    audit
+        .record(
+            AuditEvent::new(AuditAction::StatusChange)
+                .actor(admin.user.id)
+                .target(user_id)
+                .details(json!({"old_status": old_status, "status": body.status, "reason": reason})),
+        );
+
+    info!(
+        "User {} changed status of user {} from {} to {}: {}",
+        admin.user.id, user_id, old_status, body.status, reason
+    );
+    Ok(HttpResponse::Ok().json(StatusChangeResponse {
+        user_id,
+        old_status,
+        status: body.status,
+    }))
+}
+
+// Lifts a login lockout and clears the failed login count
+#[post("/admin/users/{user_id}/unlock", wrap = "RequirePermission::new(Permission::UsersWrite)")]
+pub async fn unlock_user(
+    pool: web::Data<Pool>,
+    stats: web::Data<Arc<Statistics>>,
+    admin: AuthenticatedUser,
+    audit: Audit,
+    user_id: web::Path<i64>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = user_id.into_inner();
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    if !db::unlock_user(&client, user_id).await? {
+        return Err(AppError::NotFound);
+    }
+
+    audit.record(AuditEvent::new(AuditAction::AccountUnlock).actor(admin.user.id).target(user_id));
+
+    info!("User {} unlocked logins of user {}", admin.user.id, user_id);
+    stats
+        .log_error(format!("Login lockout: user {} unlocked by user {}", user_id, admin.user.id))
+        .await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Issues a short-lived access token for another user, e.g. to reproduce a bug report. It can't
+// be refreshed or used for account management, and requests made with it are flagged with
+// the admin in the logs, statistics and audit log.
+#[post("/admin/users/{user_id}/impersonate", wrap = "RequirePermission::new(Permission::UsersImpersonate)")]
+pub async fn impersonate_user(
+    pool: web::Data<Pool>,
+    keys: web::Data<JwtKeys>,
+    admin: AuthenticatedUser,
+    audit: Audit,
+    user_id: web::Path<i64>,
+    body: web::Json<ImpersonationRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Neither API keys nor impersonation tokens can start an impersonation
+    admin.require_session()?;
+    let user_id = user_id.into_inner();
+    let reason = body.reason.trim();
+    if reason.is_empty() {
+        return Err(AppError::BadRequest("A reason is required".to_string()));
+    }
+    if user_id == admin.user.id {
+        return Err(AppError::BadRequest("Admins cannot impersonate themselves".to_string()));
+    }
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let user = db::find_user_by_id(&client, user_id)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    if !user.status.is_active() {
+        return Err(AppError::BadRequest(format!("Cannot impersonate a {} account", user.status)));
+    }
+    // Acting as someone with more permissions would be a way to gain them
+    if !user.permission_set().is_subset(&admin.user.permission_set()) {
+        return Err(AppError::Forbidden);
+    }
+
+    let session_id = Uuid::new_v4();
+    db::insert_impersonation_session(&client, session_id, user_id, admin.user.id, audit.context()).await?;
+    let token = auth::generate_impersonation_token(&keys, &user_id.to_string(), admin.user.id, session_id).map_err(|e| {
+        error!("Failed to generate impersonation token for user {}: {}", user_id, e);
+        AppError::InternalServerError
+    })?;
+
+    audit
+        .record(
+            AuditEvent::new(AuditAction::Impersonate)
+                .actor(admin.user.id)
+                .target(user_id)
+                .details(json!({"reason": reason, "session_id": session_id})),
+        );
+
+    info!("User {} started impersonating user {}: {}", admin.user.id, user_id, reason);
+    Ok(HttpResponse::Ok().json(ImpersonationResponse {
+        user_id,
+        token,
+        expires_in: keys.impersonation_ttl_seconds,
+    }))
+}
+
+// Audit log entries, newest first, optionally filtered by actor, action and time range
+#[get("/admin/audit-log", wrap = "RequirePermission::new(Permission::AuditRead)")]
+pub async fn list_audit_log(
+    pool: web::Data<Pool>,
+    query: web::Query<AuditLogQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(AppError::BadRequest(format!("limit must be between 1 and {}", MAX_PAGE_SIZE)));
+    }
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from >= to {
+            return Err(AppError::BadRequest("from must be before to".to_string()));
+        }
+    }
+
+    let filter = AuditLogFilter {
+        actor_id: query.actor_id,
+        action: query.action,
+        from: query.from,
+        to: query.to,
+        before_id: query.before_id,
+    };
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    // Fetch one extra row to find out whether another page follows
+    let mut entries = db::list_audit_log(&client, &filter, limit + 1).await?;
+    let next_before_id = if entries.len() as i64 > limit {
+        entries.truncate(limit as usize);
+        entries.last().map(|last| last.id)
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(AuditLogResponse { entries, next_before_id }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = UserListCursor {
+            sort: UserSortField::CreatedAt,
+            order: SortOrder::Desc,
+            position: UserListPosition {
+                created_at: "2026-10-17T10:00:00.123456Z".parse().unwrap(),
+                id: 42,
+            },
+        };
+        let encoded = cursor.encode();
+        assert!(!encoded.contains('='), "cursor should be URL safe");
+        assert_eq!(UserListCursor::decode(&encoded), Some(cursor));
+    }
+
+    #[test]
+    fn test_invalid_cursor_is_rejected() {
+        assert_eq!(UserListCursor::decode("not a cursor"), None);
+        assert_eq!(UserListCursor::decode(&URL_SAFE_NO_PAD.encode(b"{\"id\":1}")), None);
+    }
+
+    #[test]
+    fn test_list_query_defaults() {
+        let query = web::Query::<UserListQuery>::from_query("status=suspended&created_after=2026-01-01T00:00:00Z")
+            .unwrap()
+            .into_inner();
+        assert_eq!(query.sort, UserSortField::Id);
+        assert_eq!(query.order, SortOrder::Asc);
+        assert_eq!(query.status, Some(UserStatus::Suspended));
+        assert!(query.created_after.is_some());
+    }
+
+    #[test]
+    fn test_audit_log_query() {
+        let query = web::Query::<AuditLogQuery>::from_query("actor_id=7&action=login_failure&from=2026-10-01T00:00:00Z")
+            .unwrap()
+            .into_inner();
+        assert_eq!(query.actor_id, Some(7));
+        assert_eq!(query.action, Some(AuditAction::LoginFailure));
+        assert!(query.from.is_some());
+        assert!(query.to.is_none());
+
+        assert!(web::Query::<AuditLogQuery>::from_query("action=unknown").is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use actix_web::{delete, get, post, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use crate::auth::api_keys::{self, ApiKey};
+use crate::auth::{AuthenticatedUser, Permission};
+use crate::db;
+use crate::error::AppError;
+use crate::middleware::auth::RequireAuth;
+
+const MAX_NAME_LENGTH: usize = 100;
+
+#[derive(Deserialize)]
+pub struct CreateApiKey {
+    name: String,
+    #[serde(default)]
+    scopes: Vec<String>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyResponse {
+    id: i64,
+    name: String,
+    prefix: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiKey> for ApiKeyResponse {
+    fn from(key: ApiKey) -> Self {
+        ApiKeyResponse {
+            id: key.id,
+            name: key.name,
+            prefix: key.prefix,
+            scopes: key.scopes.iter().map(|scope| scope.as_str().to_string()).collect(),
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+            last_used_at: key.last_used_at,
+        }
+    }
+}
+
+// Returned once on creation; the full key can't be retrieved later
+#[derive(Serialize)]
+pub struct CreatedApiKeyResponse {
+    key: String,
+    #[serde(flatten)]
+    details: ApiKeyResponse,
+}
+
+#[post("/user/me/api-keys", wrap = "RequireAuth")]
+pub async fn create_api_key(
+    pool: web::Data<Pool>,
+    current: AuthenticatedUser,
+    body: web::Json<CreateApiKey>,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+
+    let name = body.name.trim();
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(AppError::BadRequest(format!("name must be 1 to {} characters", MAX_NAME_LENGTH)));
+    }
+    if body.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(AppError::BadRequest("expires_at must be in the future".to_string()));
+    }
+
+    // Keys can only be scoped to permissions the user holds
+    let permissions = current.user.permission_set();
+    let mut scopes = Vec::with_capacity(body.scopes.len());
+    for scope in &body.scopes {
+        let permission: Permission = scope.parse().map_err(AppError::BadRequest)?;
+        if !permissions.contains(permission) {
+            return Err(AppError::BadRequest(format!("You don't have the permission {}", permission)));
+        }
+        if !scopes.contains(&permission) {
+            scopes.push(permission);
+        }
+    }
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let generated = api_keys::generate_api_key();
+    let stored = db::insert_api_key(
+        &client,
+        current.user.id,
+        name,
+        &generated.prefix,
+        &generated.secret_hash,
+        &scopes,
+        body.expires_at,
+    )
+    .await?;
+
+    info!("User {} created API key {} ({})", current.user.id, stored.prefix, stored.name);
+    Ok(HttpResponse::Created().json(CreatedApiKeyResponse {
+        key: generated.key,
+        details: ApiKeyResponse::from(stored),
+    }))
+}
+
+#[get("/user/me/api-keys", wrap = "RequireAuth")]
+pub async fn list_api_keys(
+    pool: web::Data<Pool>,
+    current: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let keys = db::list_api_keys(&client, current.user.id).await?;
+    Ok(HttpResponse::Ok().json(keys.into_iter().map(ApiKeyResponse::from).collect::<Vec<_>>()))
+}
+
+#[delete("/user/me/api-keys/{key_id}", wrap = "RequireAuth")]
+pub async fn revoke_api_key(
+    pool: web::Data<Pool>,
+    current: AuthenticatedUser,
+    key_id: web::Path<i64>,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+    let key_id = key_id.into_inner();
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    if !db::revoke_api_key(&client, current.user.id, key_id).await? {
+        return Err(AppError::NotFound);
+    }
+
+    info!("User {} revoked API key {}", current.user.id, key_id);
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,12 @@
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::{get, web, HttpResponse};
+use crate::auth::JwtKeys;
+
+// Public keys for verifying our tokens offline. Caches may keep them for a few minutes,
+// so a new key should be published (as a verification key) before it starts signing.
+#[get("/.well-known/jwks.json")]
+pub async fn jwks(keys: web::Data<JwtKeys>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(300)]))
+        .json(keys.jwks())
+}
@@ -0,0 +1,87 @@
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use crate::error::AppError;
+use crate::middleware::rate_limiter::RateLimiter;
+use crate::statistics::prometheus::{MetricsWriter, ScrapeAllowlist, CONTENT_TYPE};
+use crate::statistics::Statistics;
+use deadpool_postgres::Pool;
+use log::warn;
+use std::sync::Arc;
+
+// Whether the request came through a reverse proxy. The peer address is then the proxy's,
+// which says nothing about the client.
+fn forwarded_by_proxy(req: &HttpRequest) -> bool {
+    let headers = req.headers();
+    headers.contains_key(header::FORWARDED) || headers.contains_key(header::X_FORWARDED_FOR)
+}
+
+// Metrics for Prometheus, in its text exposition format. There is no authentication;
+// only peers in `metrics.allowed_networks` may scrape them, and only directly.
+#[get("/metrics")]
+pub async fn metrics(
+    req: HttpRequest,
+    allowlist: web::Data<ScrapeAllowlist>,
+    stats: web::Data<Arc<Statistics>>,
+    rate_limiter: web::Data<RateLimiter>,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse, AppError> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if !peer.is_some_and(|ip| allowlist.allows(ip)) {
+        warn!("Metrics scrape refused for {:?}", peer);
+        return Err(AppError::Forbidden);
+    }
+    if forwarded_by_proxy(&req) {
+        warn!("Metrics scrape refused for a request forwarded by {:?}", peer);
+        return Err(AppError::Forbidden);
+    }
+
+    let mut writer = MetricsWriter::new();
+    stats.write_metrics(&mut writer);
+
+    writer.family("api_rate_limit_rejections_total", "counter", "Requests rejected by the rate limiter");
+    writer.sample("api_rate_limit_rejections_total", &[], rate_limiter.rejected_requests() as f64);
+
+    // A negative number of available connections is the number of requests waiting for one
+    let status = pool.status();
+    for (name, help, value) in [
+        ("api_db_pool_max_size", "Maximum number of database connections", status.max_size as f64),
+        ("api_db_pool_size", "Open database connections", status.size as f64),
+        ("api_db_pool_available", "Idle database connections", status.available.max(0) as f64),
+        ("api_db_pool_waiting", "Requests waiting for a database connection", (-status.available).max(0) as f64),
+    ] {
+        writer.family(name, "gauge", help);
+        writer.sample(name, &[], value);
+    }
+
+    writer.family("api_build_info", "gauge", "Version of the running server");
+    writer.sample(
+        "api_build_info",
+        &[("name", env!("CARGO_PKG_NAME")), ("version", env!("CARGO_PKG_VERSION"))],
+        1.0,
+    );
+
+    Ok(HttpResponse::Ok().content_type(CONTENT_TYPE).body(writer.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_forwarded_requests_are_recognized() {
+        assert!(!forwarded_by_proxy(&TestRequest::get().uri("/metrics").to_http_request()));
+        assert!(forwarded_by_proxy(
+            &TestRequest::get()
+                .uri("/metrics")
+                .insert_header((header::X_FORWARDED_FOR, "203.0.113.7"))
+                .to_http_request()
+        ));
+        assert!(forwarded_by_proxy(
+            &TestRequest::get()
+                .uri("/metrics")
+                .insert_header((header::FORWARDED, "for=203.0.113.7"))
+                .to_http_request()
+        ));
+    }
+}
@@ -0,0 +1,195 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use chrono::DateTime;
+use deadpool_postgres::Pool;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::audit::{Audit, AuditAction, AuditEvent};
+use crate::auth::lockout::{self, LoginThrottle};
+use crate::auth::{self, totp, AuthenticatedUser, JwtKeys};
+use crate::clock::Clock;
+use crate::config::AppConfig;
+use crate::db;
+use crate::error::AppError;
+use crate::routes::user;
+use crate::middleware::auth::RequireAuth;
+use crate::statistics::Statistics;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub struct TotpSetupResponse {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpCode {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodesResponse {
+    recovery_codes: Vec<String>,
+}
+
+// Second step of a two-factor login: the token from the password step plus either
+// a TOTP code or one of the recovery codes
+#[derive(Deserialize)]
+pub struct MfaLogin {
+    mfa_token: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+// Starts TOTP enrolment by generating a secret. Calling it again before confirming replaces the secret.
+#[post("/user/me/mfa/totp", wrap = "RequireAuth")]
+pub async fn setup_totp(
+    pool: web::Data<Pool>,
+    config: web::Data<AppConfig>,
+    current: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let secret = totp::generate_secret();
+    if !db::set_pending_totp_secret(&client, current.user.id, &secret).await? {
+        return Err(AppError::BadRequest("Two-factor authentication is already enabled".to_string()));
+    }
+
+    info!("User {} started TOTP enrolment", current.user.id);
+    Ok(HttpResponse::Ok().json(TotpSetupResponse {
+        secret: totp::encode_base32(&secret),
+        otpauth_uri: totp::otpauth_uri(&config.auth.totp_issuer, &current.user.email, &secret),
+    }))
+}
+
+// Enables TOTP once the user proves their app produces valid codes.
+// The recovery codes are returned only this once.
+#[post("/user/me/mfa/totp/confirm", wrap = "RequireAuth")]
+pub async fn confirm_totp(
+    pool: web::Data<Pool>,
+    clock: web::Data<dyn Clock>,
+    current: AuthenticatedUser,
+    body: web::Json<TotpCode>,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+
+    let mut client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let settings = match db::get_totp_settings(&client, current.user.id).await? {
+        Some(settings) if !settings.enabled => settings,
+        Some(_) => return Err(AppError::BadRequest("Two-factor authentication is already enabled".to_string())),
+        None => return Err(AppError::BadRequest("Start TOTP setup first".to_string())),
+    };
+
+    let step = totp::verify(&settings.secret, &body.code, clock.get_ref(), settings.last_step)
+        .ok_or_else(|| AppError::BadRequest("Invalid code".to_string()))?;
+
+    let recovery_codes = totp::generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+    if !db::enable_totp(&mut client, current.user.id, step, &hashes).await? {
+        return Err(AppError::BadRequest("Start TOTP setup first".to_string()));
+    }
+
+    info!("User {} enabled TOTP two-factor authentication", current.user.id);
+    Ok(HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes }))
+}
+
+// Wrong codes count as failed logins of the account and the client IP, like wrong passwords,
+// and each `mfa_token` only allows `lockout.mfa_attempts_per_challenge` of them
+#[post("/login/mfa")]
+#[allow(clippy::too_many_arguments)]
+pub async fn login_mfa(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    keys: web::Data<JwtKeys>,
+    config: web::Data<AppConfig>,
+    clock: web::Data<dyn Clock>,
+    throttle: web::Data<LoginThrottle>,
+    stats: web::Data<Arc<Statistics>>,
+    audit: Audit,
+    body: web::Json<MfaLogin>,
+) -> Result<HttpResponse, AppError> {
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_else(|| "unknown".to_string());
+    let now = clock.now();
+
+    let (ip_failures, ip_locked_until) = throttle.status(&ip, now);
+    if let Some(until) = ip_locked_until {
+        warn!("Rejected second factor from locked IP {} (locked until {})", ip, until);
+        return Err(AppError::RateLimitExceeded);
+    }
+
+    let claims = auth::decode_mfa_pending_token(&keys, &body.mfa_token).map_err(|_| AppError::Unauthorized)?;
+    let user_id: i64 = claims.sub.parse().map_err(|_| AppError::Unauthorized)?;
+    if throttle.challenge_exhausted(&claims.jti, now) {
+        info!("Rejected second factor for user {}: the MFA challenge has no attempts left", user_id);
+        return Err(AppError::Unauthorized);
+    }
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let user = db::find_user_by_id(&client, user_id)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    user.ensure_active()?;
+    if user.token_predates_password_change(&claims) {
+        return Err(AppError::Unauthorized);
+    }
+    // A lockout that started after the password step also stops the second one
+    if let Some(until) = user.locked_until(now) {
+        info!("Rejected second factor for locked user {} (locked until {})", user_id, until);
+        return Err(AppError::Unauthorized);
+    }
+    let settings = db::get_totp_settings(&client, user_id)
+        .await?
+        .filter(|settings| settings.enabled)
+        .ok_or(AppError::Unauthorized)?;
+
+    let delay = lockout::backoff_delay(throttle.config(), (user.failed_login_attempts.max(0) as u32).max(ip_failures));
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+
+    let accepted = match (&body.code, &body.recovery_code) {
+        (Some(code), None) => match totp::verify(&settings.secret, code, clock.get_ref(), settings.last_step) {
+            // Recording the step fails if a concurrent request already used this code
+            Some(step) => db::record_totp_step(&client, user_id, step).await?,
+            None => false,
+        },
+        (None, Some(recovery_code)) => {
+            let used = db::use_recovery_code(&client, user_id, &totp::hash_recovery_code(recovery_code)).await?;
+            if used {
+                warn!("User {} logged in with a recovery code", user_id);
+            }
+            used
+        }
+        _ => return Err(AppError::BadRequest("Provide either code or recovery_code".to_string())),
+    };
+    if !accepted {
+        info!("Failed second factor for user {}", user_id);
+        audit
+            .record(
+                AuditEvent::new(AuditAction::LoginFailure)
+                    .target(user_id)
+                    .details(json!({"reason": "invalid_second_factor"})),
+            );
+        let expires_at = DateTime::from_timestamp(claims.exp as i64, 0).unwrap_or(now);
+        if throttle.record_challenge_failure(&claims.jti, expires_at, now) {
+            info!("MFA challenge of user {} used up its attempts", user_id);
+        }
+        user::record_failed_login(&client, throttle.get_ref(), &stats, &ip, Some(user), now).await?;
+        return Err(AppError::Unauthorized);
+    }
+
+    user::complete_login(&client, &keys, &config, &audit, &user).await
+}
@@ -1,18 +1,82 @@
 use actix_web::web;
 
+mod admin;
+mod api_keys;
 mod health;
+mod jwks;
+mod metrics;
+mod mfa;
+mod oidc;
+mod password;
 mod rate_test;
+mod sessions;
+mod token;
 mod user;
+mod verification;
 pub(crate) mod statistics;
 
+pub use user::UserMetrics;
+
 pub fn config(cfg: &mut web::ServiceConfig) {
+    // Well-known URIs live at the root, outside `/api`
+    cfg.service(jwks::jwks);
+    cfg.service(metrics::metrics);
     cfg.service(
         web::scope("/api")
-            .service(statistics::get_statistics)
             .service(health::health_check)
             .service(rate_test::rate_test)
             .service(user::register)
+            .service(user::login)
+            .service(mfa::login_mfa)
+            .service(oidc::authorize)
+            .service(oidc::callback)
+            .service(token::refresh_token)
+            .service(token::logout)
+            .service(verification::verify_email)
+            .service(verification::resend_verification)
+            .service(password::forgot_password)
+            .service(password::reset_password)
+            // Protected routes are wrapped in `RequireAuth` or `RequirePermission` one by one,
+            // so unknown paths under `/api` still answer 404. `/user/me` routes go before
+            // `/user/{user_id}`.
+            .service(user::update_me)
+            .service(user::change_password)
+            .service(user::delete_me)
+            .service(sessions::list_sessions)
+            .service(sessions::revoke_session)
+            .service(sessions::revoke_all_sessions)
+            .service(mfa::setup_totp)
+            .service(mfa::confirm_totp)
+            .service(oidc::link_identity)
+            .service(api_keys::create_api_key)
+            .service(api_keys::list_api_keys)
+            .service(api_keys::revoke_api_key)
             .service(user::get_user)
+            .service(admin::list_users)
+            .service(admin::update_permissions)
+            .service(admin::update_status)
+            .service(admin::unlock_user)
+            .service(admin::impersonate_user)
+            .service(admin::list_audit_log)
             .configure(statistics::config)
     );
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::keys::tests::test_keys;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn test_unknown_api_paths_are_not_found() {
+        let app = test::init_service(App::new().app_data(web::Data::new(test_keys())).configure(config)).await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/api/no-such-route").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::get().uri("/api/user/me/sessions").to_request();
+        let err = test::try_call_service(&app, req).await.expect_err("Expected request to be rejected");
+        assert_eq!(err.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,312 @@
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use deadpool_postgres::{Client, Pool};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::audit::{Audit, AuditAction, AuditEvent};
+use crate::auth::oidc::{self, IdTokenClaims, OidcProvider, OidcProviders};
+use crate::auth::one_time;
+use crate::auth::{AuthenticatedUser, JwtKeys, User, UserStatus};
+use crate::config::AppConfig;
+use crate::db::{self, OidcLoginState};
+use crate::error::AppError;
+use crate::mail::Mailer;
+use crate::routes::{user, verification};
+use crate::middleware::auth::RequireAuth;
+
+// Usernames are limited to 20 characters; this leaves room for a numeric suffix
+const USERNAME_BASE_LENGTH: usize = 15;
+const USERNAME_ATTEMPTS: usize = 5;
+
+// Holds the `state` of the authorization request the browser started. The callback must
+// come with it, so nobody can finish their own sign-in in someone else's browser, e.g. to
+// log them into the attacker's account or link the attacker's provider account to theirs.
+const STATE_COOKIE: &str = "oidc_state";
+const STATE_COOKIE_PATH: &str = "/api/oidc";
+
+#[derive(Serialize)]
+pub struct AuthorizationResponse {
+    authorization_url: String,
+}
+
+// What the provider sent back to the `redirect_uri` page
+#[derive(Deserialize)]
+pub struct OidcCallback {
+    code: String,
+    state: String,
+}
+
+// Username for a new account, from the provider's `preferred_username` or the email address
+fn username_base(claims: &IdTokenClaims) -> String {
+    let source = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| claims.email.as_deref().and_then(|email| email.split('@').next()))
+        .unwrap_or_default();
+    let base: String = source
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(USERNAME_BASE_LENGTH)
+        .collect();
+    if base.len() < 3 {
+        "user".to_string()
+    } else {
+        base
+    }
+}
+
+fn state_cookie(config: &AppConfig, state: &str) -> Cookie<'static> {
+    Cookie::build(STATE_COOKIE, state.to_string())
+        .path(STATE_COOKIE_PATH)
+        .http_only(true)
+        .secure(config.mail.public_base_url.starts_with("https://"))
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(config.oidc.state_ttl_seconds as i64))
+        .finish()
+}
+
+// The state works once, so its cookie goes once the callback has used it
+fn removed_state_cookie() -> Cookie<'static> {
+    let mut cookie = Cookie::build(STATE_COOKIE, "").path(STATE_COOKIE_PATH).finish();
+    cookie.make_removal();
+    cookie
+}
+
+// Whether the callback comes from the browser that started the authorization request. The
+// values are compared by hash, so the time the comparison takes says nothing about the cookie.
+fn state_matches_cookie(req: &HttpRequest, state: &str) -> bool {
+    req.cookie(STATE_COOKIE).is_some_and(|cookie| {
+        one_time::hash_one_time_token(cookie.value()) == one_time::hash_one_time_token(state)
+    })
+}
+
+// Starts an authorization request and returns the provider URL to send the user to
+async fn start_authorization(
+    pool: &Pool,
+    config: &AppConfig,
+    provider: &OidcProvider,
+    link_user_id: Option<i64>,
+) -> Result<HttpResponse, AppError> {
+    let state = oidc::generate_random_value();
+    let login_state = OidcLoginState {
+        code_verifier: oidc::generate_random_value(),
+        nonce: oidc::generate_random_value(),
+        link_user_id,
+    };
+
+    let authorization_url = provider
+        .authorization_url(&state, &login_state.nonce, &login_state.code_verifier)
+        .await
+        .map_err(|e| {
+            error!("Failed to start sign-in with provider {}: {}", provider.name(), e);
+            AppError::InternalServerError
+        })?;
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+    let expires_at = Utc::now() + Duration::seconds(config.oidc.state_ttl_seconds as i64);
+    db::insert_oidc_login_state(&client, &one_time::hash_one_time_token(&state), provider.name(), &login_state, expires_at)
+        .await?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(state_cookie(config, &state))
+        .json(AuthorizationResponse { authorization_url }))
+}
+
+#[get("/oidc/{provider}/authorize")]
+pub async fn authorize(
+    pool: web::Data<Pool>,
+    config: web::Data<AppConfig>,
+    providers: web::Data<OidcProviders>,
+    provider: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let provider = providers.get(&provider).ok_or(AppError::NotFound)?;
+    start_authorization(&pool, &config, provider, None).await
+}
+
+// Like `authorize`, but the callback links the external account to the current user
+#[post("/user/me/identities/{provider}", wrap = "RequireAuth")]
+pub async fn link_identity(
+    pool: web::Data<Pool>,
+    config: web::Data<AppConfig>,
+    providers: web::Data<OidcProviders>,
+    current: AuthenticatedUser,
+    provider: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+    let provider = providers.get(&provider).ok_or(AppError::NotFound)?;
+    start_authorization(&pool, &config, provider, Some(current.user.id)).await
+}
+
+// Creates the account for someone signing in with a provider for the first time. Accounts
+// whose email the provider hasn't verified have to verify it like after a registration.
+async fn register_external_user(
+    client: &mut Client,
+    mailer: &dyn Mailer,
+    config: &AppConfig,
+    audit: &Audit,
+    provider: &str,
+    claims: &IdTokenClaims,
+) -> Result<User, AppError> {
+    let email = claims
+        .email
+        .as_deref()
+        .ok_or_else(|| AppError::BadRequest("The provider did not share an email address".to_string()))?;
+    // Taking over an existing account just because the email matches would trust the provider
+    // with accounts it doesn't own; the owner can link it after logging in instead
+    if db::find_user_by_email(client, email).await?.is_some() {
+        return Err(AppError::BadRequest(
+            "An account with this email address already exists; log in and link the provider to it".to_string(),
+        ));
+    }
+
+    let status = if claims.email_verified {
+        UserStatus::Active
+    } else {
+        UserStatus::PendingVerification
+    };
+    let base = username_base(claims);
+    let mut username = base.clone();
+    let mut new_user = None;
+    for _ in 0..USERNAME_ATTEMPTS {
+        new_user = db::insert_external_user(client, email, &username, status, provider, &claims.iss, &claims.sub).await?;
+        if new_user.is_some() {
+            break;
+        }
+        username = format!("{}{}", base, rand::random::<u16>() % 10000);
+    }
+    let new_user = new_user.ok_or_else(|| {
+        error!("No free username found for a new user of provider {} after {} attempts", provider, USERNAME_ATTEMPTS);
+        AppError::InternalServerError
+    })?;
+    audit
+        .record(
+            AuditEvent::new(AuditAction::Register)
+                .actor(new_user.id)
+                .target(new_user.id)
+                .details(json!({"provider": provider})),
+        );
+    info!("User {} registered through provider {}", new_user.id, provider);
+
+    if status == UserStatus::PendingVerification {
+        if let Err(e) = verification::send_verification_email(client, mailer, &config.mail, &new_user).await {
+            error!("Verification email for new user {} was not sent: {}", new_user.id, e);
+        }
+    }
+    Ok(new_user)
+}
+
+// Finishes an authorization request: logs the user in, creating the account on first
+// sign-in, or links the provider account when the request was started for linking
+#[post("/oidc/{provider}/callback")]
+#[allow(clippy::too_many_arguments)]
+pub async fn callback(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    keys: web::Data<JwtKeys>,
+    config: web::Data<AppConfig>,
+    mailer: web::Data<dyn Mailer>,
+    providers: web::Data<OidcProviders>,
+    audit: Audit,
+    provider: web::Path<String>,
+    body: web::Json<OidcCallback>,
+) -> Result<HttpResponse, AppError> {
+    let provider = providers.get(&provider).ok_or(AppError::NotFound)?;
+    let name = provider.name();
+    if !state_matches_cookie(&req, &body.state) {
+        warn!("Sign-in callback for provider {} without the state cookie of its request", name);
+        return Err(AppError::BadRequest("Invalid or expired sign-in request".to_string()));
+    }
+
+    let mut client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let login_state = db::take_oidc_login_state(&client, &one_time::hash_one_time_token(&body.state), name)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("Invalid or expired sign-in request".to_string()))?;
+
+    let claims = match provider.authenticate(&body.code, &login_state.code_verifier, &login_state.nonce).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            warn!("Sign-in with provider {} failed: {}", name, e);
+            audit
+                .record(
+                    AuditEvent::new(AuditAction::LoginFailure)
+                        .details(json!({"provider": name, "reason": "invalid_id_token"})),
+                );
+            return Err(AppError::Unauthorized);
+        }
+    };
+
+    if let Some(user_id) = login_state.link_user_id {
+        if !db::link_identity(&client, user_id, name, &claims.iss, &claims.sub, claims.email.as_deref()).await? {
+            return Err(AppError::BadRequest("This provider account is already linked to another user".to_string()));
+        }
+        audit
+            .record(
+                AuditEvent::new(AuditAction::IdentityLink)
+                    .actor(user_id)
+                    .target(user_id)
+                    .details(json!({"provider": name, "subject": claims.sub})),
+            );
+        info!("User {} linked an account at provider {}", user_id, name);
+        return Ok(HttpResponse::NoContent().cookie(removed_state_cookie()).finish());
+    }
+
+    let user = match db::find_user_by_identity(&client, &claims.iss, &claims.sub).await? {
+        Some(user) => user,
+        None => register_external_user(&mut client, mailer.get_ref(), &config, &audit, name, &claims).await?,
+    };
+    if let Err(e) = user.ensure_active() {
+        info!("Rejected sign-in with provider {} for {} user {}", name, user.status, user.username);
+        return Err(e);
+    }
+
+    let mut response = user::finish_first_factor(&client, &keys, &config, &audit, &user).await?;
+    response.add_cookie(&removed_state_cookie()).map_err(|e| {
+        error!("Failed to clear the state cookie: {}", e);
+        AppError::InternalServerError
+    })?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(preferred_username: Option<&str>, email: Option<&str>) -> IdTokenClaims {
+        IdTokenClaims {
+            iss: "https://id.example.com".to_string(),
+            sub: "1".to_string(),
+            nonce: None,
+            email: email.map(str::to_string),
+            email_verified: true,
+            preferred_username: preferred_username.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_username_base() {
+        assert_eq!(username_base(&claims(Some("alice.smith"), Some("a@example.com"))), "alicesmith");
+        assert_eq!(username_base(&claims(None, Some("bob-jones@example.com"))), "bobjones");
+        assert_eq!(username_base(&claims(None, Some("averyveryverylongname@example.com"))), "averyveryverylo");
+        assert_eq!(username_base(&claims(Some("李"), None)), "user");
+        assert_eq!(username_base(&claims(None, None)), "user");
+    }
+
+    #[test]
+    fn test_callback_needs_the_state_cookie() {
+        use actix_web::test::TestRequest;
+
+        let req = TestRequest::default().cookie(Cookie::new(STATE_COOKIE, "state-1")).to_http_request();
+        assert!(state_matches_cookie(&req, "state-1"));
+        assert!(!state_matches_cookie(&req, "state-2"));
+        assert!(!state_matches_cookie(&TestRequest::default().to_http_request(), "state-1"));
+    }
+}
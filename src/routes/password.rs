@@ -0,0 +1,166 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use deadpool_postgres::Pool;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use crate::audit::{Audit, AuditAction, AuditEvent};
+use crate::auth::one_time::{self, TokenPurpose};
+use crate::auth::{PasswordHashers, PasswordPolicy};
+use crate::config::AppConfig;
+use crate::db;
+use crate::error::AppError;
+use crate::mail::{self, Mailer};
+use crate::middleware::rate_limiter::MailRateLimiter;
+
+#[derive(Deserialize)]
+pub struct ForgotPassword {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    token: String,
+    new_password: String,
+}
+
+#[derive(Serialize)]
+pub struct MessageResponse {
+    message: String,
+}
+
+fn password_reset_link(password_reset_url: &str, token: &str) -> String {
+    let separator = if password_reset_url.contains('?') { '&' } else { '?' };
+    format!("{}{}token={}", password_reset_url, separator, token)
+}
+
+// Emails a password reset link. Always answers 202, whether or not the address
+// belongs to an account, so it can't be used to probe for accounts. The lookup and the
+// email happen after the response, so both cases also take the same time to answer.
+#[post("/password/forgot")]
+pub async fn forgot_password(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+    config: web::Data<AppConfig>,
+    limiter: web::Data<MailRateLimiter>,
+    body: web::Json<ForgotPassword>,
+) -> Result<HttpResponse, AppError> {
+    let email = body.email.trim().to_string();
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_else(|| "unknown".to_string());
+    limiter.check(&format!("ip:{}", ip))?;
+    limiter.check(&format!("email:{}", email))?;
+
+    let pool = pool.get_ref().clone();
+    let mailer = mailer.into_inner();
+    tokio::spawn(async move {
+        // Failures are only logged; reporting them would tell the caller the account exists
+        if let Err(e) = send_password_reset_email(&pool, mailer.as_ref(), &config, &email).await {
+            error!("Failed to handle password reset request: {}", e);
+        }
+    });
+
+    Ok(HttpResponse::Accepted().json(MessageResponse {
+        message: "If the address belongs to an account, a password reset email has been sent".to_string(),
+    }))
+}
+
+async fn send_password_reset_email(pool: &Pool, mailer: &dyn Mailer, config: &AppConfig, email: &str) -> Result<(), AppError> {
+    let mut client = pool.get().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
+
+    let user = match db::find_user_by_email(&client, email).await? {
+        Some(user) => user,
+        None => {
+            info!("Password reset requested for an unknown address");
+            return Ok(());
+        }
+    };
+
+    let token = one_time::generate_one_time_token();
+    let ttl_seconds = config.mail.password_reset_token_ttl_seconds;
+    db::replace_one_time_token(
+        &mut client,
+        user.id,
+        TokenPurpose::PasswordReset,
+        &one_time::hash_one_time_token(&token),
+        Utc::now() + Duration::seconds(ttl_seconds as i64),
+    )
+    .await?;
+    // The email may take a while to send, so the connection goes back to the pool first
+    drop(client);
+
+    let email = mail::password_reset_email(
+        &user.email,
+        &password_reset_link(&config.mail.password_reset_url, &token),
+        ttl_seconds / 60,
+    );
+    match mailer.send(&email).await {
+        Ok(()) => info!("Sent password reset email to user {}", user.id),
+        Err(e) => error!("Failed to send password reset email to user {}: {}", user.id, e),
+    }
+    Ok(())
+}
+
+#[post("/password/reset")]
+pub async fn reset_password(
+    pool: web::Data<Pool>,
+    hashers: web::Data<PasswordHashers>,
+    policy: web::Data<PasswordPolicy>,
+    audit: Audit,
+    body: web::Json<ResetPassword>,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    // Hashing is expensive, so made-up tokens are turned away first. The token is only used
+    // up once the new password is stored.
+    let token_hash = one_time::hash_one_time_token(&body.token);
+    let invalid_token = || AppError::BadRequest("Invalid or expired password reset token".to_string());
+    let user = db::find_one_time_token_user(&client, TokenPurpose::PasswordReset, &token_hash)
+        .await?
+        .ok_or_else(invalid_token)?;
+    drop(client);
+
+    let violations = policy.check(&body.new_password, &[&user.username, &user.email]);
+    if !violations.is_empty() {
+        return Err(AppError::ValidationFailed(violations));
+    }
+
+    let hashed_password = hashers.hash_password(&body.new_password).await.map_err(|e| {
+        error!("Failed to hash password during reset: {}", e);
+        AppError::InternalServerError
+    })?;
+
+    let mut client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    // Also ends existing sessions: refresh tokens are revoked here, and access tokens
+    // issued before the reset are rejected because they predate `password_changed_at`
+    let user_id = db::reset_password(&mut client, &token_hash, &hashed_password)
+        .await?
+        .ok_or_else(invalid_token)?;
+
+    audit.record(AuditEvent::new(AuditAction::PasswordReset).actor(user_id).target(user_id));
+    info!("User {} reset their password", user_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_reset_link() {
+        assert_eq!(
+            password_reset_link("https://app.example.com/reset-password", "abc"),
+            "https://app.example.com/reset-password?token=abc"
+        );
+        assert_eq!(
+            password_reset_link("https://app.example.com/account?view=reset", "abc"),
+            "https://app.example.com/account?view=reset&token=abc"
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use actix_web::{delete, get, web, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use deadpool_postgres::Pool;
+use log::{error, info};
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+use crate::audit::{Audit, AuditAction, AuditEvent};
+use crate::auth::sessions::{Session, SessionCache};
+use crate::auth::{AuthenticatedUser, Credential};
+use crate::config::AppConfig;
+use crate::db;
+use crate::error::AppError;
+use crate::middleware::auth::RequireAuth;
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    id: Uuid,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    created_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    // Whether this is the session of the token making the request
+    current: bool,
+}
+
+impl SessionResponse {
+    fn new(session: Session, current_id: Option<Uuid>) -> Self {
+        SessionResponse {
+            current: Some(session.id) == current_id,
+            id: session.id,
+            user_agent: session.user_agent,
+            ip: session.ip,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+        }
+    }
+}
+
+// Session of the access token the request was made with
+fn current_session_id(current: &AuthenticatedUser) -> Option<Uuid> {
+    match &current.credential {
+        Credential::Token(claims) => claims.sid.as_deref().and_then(|sid| Uuid::parse_str(sid).ok()),
+        Credential::ApiKey(_) => None,
+    }
+}
+
+#[get("/user/me/sessions", wrap = "RequireAuth")]
+pub async fn list_sessions(
+    pool: web::Data<Pool>,
+    config: web::Data<AppConfig>,
+    current: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    // Sessions not refreshed within the refresh token lifetime can't be resumed anymore
+    let seen_since = Utc::now() - Duration::seconds(config.auth.refresh_token_ttl_seconds as i64);
+    let sessions = db::list_sessions(&client, current.user.id, seen_since).await?;
+    let current_id = current_session_id(&current);
+    Ok(HttpResponse::Ok().json(
+        sessions
+            .into_iter()
+            .map(|session| SessionResponse::new(session, current_id))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+#[delete("/user/me/sessions/{session_id}", wrap = "RequireAuth")]
+pub async fn revoke_session(
+    pool: web::Data<Pool>,
+    sessions: web::Data<SessionCache>,
+    audit: Audit,
+    current: AuthenticatedUser,
+    session_id: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+    let session_id = session_id.into_inner();
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    if !db::revoke_user_session(&client, current.user.id, session_id).await? {
+        return Err(AppError::NotFound);
+    }
+    sessions.revoke(&[session_id], Utc::now());
+
+    audit
+        .record(
+            AuditEvent::new(AuditAction::SessionRevoke)
+                .actor(current.user.id)
+                .target(current.user.id)
+                .details(json!({"session_id": session_id})),
+        );
+    info!("User {} revoked session {}", current.user.id, session_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Logs out everywhere, including the session making the request
+#[delete("/user/me/sessions", wrap = "RequireAuth")]
+pub async fn revoke_all_sessions(
+    pool: web::Data<Pool>,
+    sessions: web::Data<SessionCache>,
+    audit: Audit,
+    current: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let revoked = db::revoke_user_sessions(&client, current.user.id).await?;
+    sessions.revoke(&revoked, Utc::now());
+
+    audit
+        .record(
+            AuditEvent::new(AuditAction::SessionRevoke)
+                .actor(current.user.id)
+                .target(current.user.id)
+                .details(json!({"all": true, "count": revoked.len()})),
+        );
+    info!("User {} revoked all {} of their sessions", current.user.id, revoked.len());
+    Ok(HttpResponse::NoContent().finish())
+}
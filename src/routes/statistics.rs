@@ -1,10 +1,12 @@
 use actix_web::{get, web, HttpResponse, Responder};
 use crate::statistics::Statistics;
 use crate::error::AppError;
+use crate::auth::Permission;
+use crate::middleware::auth::RequirePermission;
 use std::sync::Arc;
 use deadpool_postgres::Pool;
 
-#[get("/statistics")]
+#[get("/statistics", wrap = "RequirePermission::new(Permission::StatsRead)")]
 async fn get_statistics(stats: web::Data<Arc<Statistics>>, pool: web::Data<Pool>) -> Result<impl Responder, AppError> {
     let client = pool.get().await
         .map_err(|e| AppError::DatabaseError(e.to_string()))?;
@@ -15,7 +17,7 @@ async fn get_statistics(stats: web::Data<Arc<Statistics>>, pool: web::Data<Pool>
     Ok(HttpResponse::Ok().json(statistics))
 }
 
-#[get("/system_health")]
+#[get("/system_health", wrap = "RequirePermission::new(Permission::SystemRead)")]
 async fn get_system_health() -> Result<impl Responder, AppError> {
     let cpu_usage = sys_info::loadavg().map_err(|_| AppError::InternalServerError)?;
     let mem_info = sys_info::mem_info().map_err(|_| AppError::InternalServerError)?;
@@ -0,0 +1,127 @@
+use actix_web::{post, web, HttpResponse};
+use chrono::{Duration, Utc};
+use deadpool_postgres::{Client, Pool};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::audit::{Audit, AuditAction, AuditEvent};
+use crate::auth::refresh::{self, RefreshTokenState};
+use crate::auth::sessions::SessionCache;
+use crate::auth::{self, JwtKeys};
+use crate::config::AppConfig;
+use crate::db;
+use crate::error::AppError;
+
+#[derive(Deserialize)]
+pub struct RefreshTokenRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+// Stores a new refresh token for the user and returns its plaintext value.
+// The family is the id of the login session the token belongs to.
+pub(crate) async fn issue_refresh_token(
+    client: &Client,
+    config: &AppConfig,
+    user_id: i64,
+    family_id: Uuid,
+) -> Result<String, AppError> {
+    let token = refresh::generate_refresh_token();
+    let expires_at = Utc::now() + Duration::seconds(config.auth.refresh_token_ttl_seconds as i64);
+
+    db::insert_refresh_token(client, user_id, family_id, &refresh::hash_refresh_token(&token), expires_at).await?;
+    Ok(token)
+}
+
+#[post("/token/refresh")]
+pub async fn refresh_token(
+    pool: web::Data<Pool>,
+    keys: web::Data<JwtKeys>,
+    config: web::Data<AppConfig>,
+    sessions: web::Data<SessionCache>,
+    audit: Audit,
+    body: web::Json<RefreshTokenRequest>,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let stored = db::find_refresh_token(&client, &refresh::hash_refresh_token(&body.refresh_token))
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+
+    match stored.state(Utc::now()) {
+        RefreshTokenState::Active => {}
+        RefreshTokenState::Rotated => {
+            // A token that was already exchanged is being replayed, so it leaked.
+            // Revoke the whole family, which also logs out whoever holds the newest token.
+            let revoked = db::revoke_refresh_token_family(&client, stored.family_id).await?;
+            sessions.revoke(&[stored.family_id], Utc::now());
+            warn!(
+                "Refresh token reuse detected for user {} (family {}), revoked {} tokens",
+                stored.user_id, stored.family_id, revoked
+            );
+            return Err(AppError::Unauthorized);
+        }
+        RefreshTokenState::Expired | RefreshTokenState::Revoked => return Err(AppError::Unauthorized),
+    }
+
+    if !db::mark_refresh_token_rotated(&client, stored.id).await? {
+        // Lost a race against another request using the same token: treat it as reuse
+        db::revoke_refresh_token_family(&client, stored.family_id).await?;
+        sessions.revoke(&[stored.family_id], Utc::now());
+        warn!(
+            "Concurrent use of refresh token for user {} (family {}), family revoked",
+            stored.user_id, stored.family_id
+        );
+        return Err(AppError::Unauthorized);
+    }
+
+    let user = db::find_user_by_id(&client, stored.user_id)
+        .await?
+        .ok_or(AppError::Unauthorized)?;
+    user.ensure_active()?;
+
+    // Families from before sessions were tracked become sessions on their next refresh
+    if !db::record_session_use(&client, stored.family_id, stored.user_id, audit.context()).await? {
+        return Err(AppError::Unauthorized);
+    }
+
+    let refresh_token = issue_refresh_token(&client, &config, stored.user_id, stored.family_id).await?;
+    let token = auth::generate_session_token(&keys, &stored.user_id.to_string(), stored.family_id).map_err(|e| {
+        error!("Failed to generate JWT token for user {}: {}", stored.user_id, e);
+        AppError::InternalServerError
+    })?;
+
+    audit
+        .record(AuditEvent::new(AuditAction::TokenRefresh).actor(stored.user_id).target(stored.user_id));
+    info!("Refresh token rotated for user {}", stored.user_id);
+    Ok(HttpResponse::Ok().json(TokenResponse { token, refresh_token }))
+}
+
+#[post("/logout")]
+pub async fn logout(
+    pool: web::Data<Pool>,
+    sessions: web::Data<SessionCache>,
+    body: web::Json<RefreshTokenRequest>,
+) -> Result<HttpResponse, AppError> {
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    // Unknown tokens are ignored so that logging out is idempotent
+    if let Some(stored) = db::find_refresh_token(&client, &refresh::hash_refresh_token(&body.refresh_token)).await? {
+        db::revoke_refresh_token_family(&client, stored.family_id).await?;
+        sessions.revoke(&[stored.family_id], Utc::now());
+        info!("User {} logged out", stored.user_id);
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
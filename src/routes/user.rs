@@ -1,12 +1,44 @@
-use actix_web::{post, get, web, HttpResponse};
-use deadpool_postgres::Pool;
-use serde::{Deserialize, Serialize};
-use log::{error, info};
-use crate::auth;
+use actix_web::{delete, patch, post, put, get, web, HttpRequest, HttpResponse};
+use deadpool_postgres::{Client, Pool};
+use serde::{Deserialize, Deserializer, Serialize};
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+use serde_json::json;
+use crate::audit::{Audit, AuditAction, AuditEvent};
+use crate::auth::lockout::{self, LoginThrottle};
+use crate::auth::sessions::SessionCache;
+use crate::auth::{self, AuthenticatedUser, JwtKeys, PasswordHashers, PasswordPolicy, Permission};
+use crate::clock::Clock;
+use crate::config::AppConfig;
 use crate::db;
 use crate::error::AppError;
+use crate::mail::Mailer;
+use crate::middleware::auth::RequireAuth;
+use crate::routes::{token, verification};
+use crate::statistics::registry::{Counter, MetricRegistry};
 use crate::statistics::Statistics; // Removed StatisticsData
 use std::sync::Arc;
+use uuid::Uuid;
+
+// Counters of the registration and user lookup endpoints, registered with the statistics
+// at startup and shared by all workers
+pub struct UserMetrics {
+    register_requests: Counter,
+    register_success: Counter,
+    get_user_requests: Counter,
+    get_user_success: Counter,
+}
+
+impl UserMetrics {
+    pub fn register(registry: &MetricRegistry) -> Self {
+        UserMetrics {
+            register_requests: registry.counter("register_requests", "Registration attempts"),
+            register_success: registry.counter("register_success", "Successful registrations"),
+            get_user_requests: registry.counter("get_user_requests", "User lookups"),
+            get_user_success: registry.counter("get_user_success", "Successful user lookups"),
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct RegisterUser {
@@ -17,8 +49,31 @@ pub struct RegisterUser {
 
 #[derive(Serialize)]
 pub struct RegisterResponse {
+    message: String,
+    user: UserResponse,
+}
+
+#[derive(Deserialize)]
+pub struct LoginUser {
+    // Either the username or the email address of the account
+    #[serde(alias = "username", alias = "email")]
+    login: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginResponse {
     message: String,
     token: String,
+    refresh_token: String,
+}
+
+// Returned by the password step of a login when the account has two-factor authentication
+#[derive(Serialize)]
+pub struct MfaRequiredResponse {
+    message: String,
+    mfa_required: bool,
+    mfa_token: String,
 }
 
 #[derive(Serialize)]
@@ -28,21 +83,72 @@ pub struct UserResponse {
     email: String,
     created_at: chrono::DateTime<chrono::Utc>,
     avatar: Option<String>,
-    status: String,
+    status: auth::UserStatus,
     last_login: Option<chrono::DateTime<chrono::Utc>>,
+    // Only shown to the user themselves and to holders of `users:read`
+    locked_until: Option<chrono::DateTime<chrono::Utc>>,
+    // A new address waiting for its confirmation link to be opened; only returned by `PATCH /api/user/me`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending_email: Option<String>,
+}
+
+impl From<auth::User> for UserResponse {
+    fn from(user: auth::User) -> Self {
+        UserResponse {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            created_at: user.created_at,
+            avatar: user.avatar,
+            status: user.status,
+            last_login: user.last_login,
+            locked_until: user.locked_until,
+            pending_email: None,
+        }
+    }
+}
+
+// Distinguishes a missing field (`None`) from an explicit `null` (`Some(None)`)
+fn deserialize_present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+pub struct UpdateProfile {
+    username: Option<String>,
+    email: Option<String>,
+    // `null` removes the avatar, leaving the field out keeps it
+    #[serde(default, deserialize_with = "deserialize_present")]
+    avatar: Option<Option<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    current_password: String,
+    new_password: String,
 }
 
 #[post("/register")]
+#[allow(clippy::too_many_arguments)]
 pub async fn register(
     pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+    config: web::Data<AppConfig>,
+    hashers: web::Data<PasswordHashers>,
+    policy: web::Data<PasswordPolicy>,
     user: web::Json<RegisterUser>,
-    statistics: web::Data<Arc<Statistics>>,
+    metrics: web::Data<UserMetrics>,
+    audit: Audit,
 ) -> Result<HttpResponse, AppError> {
-    statistics.as_ref().increment("register_requests").await;
+    metrics.register_requests.inc();
 
     info!("Register function called with username: {}", user.username);
 
-    let client = pool.get().await.map_err(|e| {
+    let mut client = pool.get().await.map_err(|e| {
         error!("Failed to get database connection: {}", e);
         AppError::DatabaseError(e.to_string())
     })?;
@@ -52,41 +158,416 @@ pub async fn register(
         return Err(AppError::BadRequest("User with this email or username already exists".to_string()));
     }
 
+    let violations = policy.check(&user.password, &[&user.username, &user.email]);
+    if !violations.is_empty() {
+        info!("Rejected registration of {}: password breaks the password policy", user.username);
+        return Err(AppError::ValidationFailed(violations));
+    }
+
     // Hash the password
-    let hashed_password = auth::hash_password(&user.password).map_err(|e| {
+    let hashed_password = hashers.hash_password(&user.password).await.map_err(|e| {
         error!("Failed to hash password for user {}: {}", user.username, e);
         AppError::InternalServerError
     })?;
 
     // Insert the user into the database
-    db::insert_user(&client, &user.email, &user.username, &hashed_password).await.map_err(|e| {
+    let new_user = db::insert_user(&client, &user.email, &user.username, &hashed_password).await.map_err(|e| {
         error!("Failed to insert user {} into database: {}", user.username, e);
         AppError::DatabaseError(e.to_string())
     })?;
 
-    // Generate a JWT token
-    let token = auth::generate_token(&user.username).map_err(|e| {
-        error!("Failed to generate JWT token for user {}: {}", user.username, e);
-        AppError::InternalServerError
-    })?;
+    // The account is created either way; if sending fails the user can ask for the email again
+    if let Err(e) = verification::send_verification_email(&mut client, mailer.get_ref(), &config.mail, &new_user).await {
+        error!("Verification email for new user {} was not sent: {}", new_user.id, e);
+    }
+
+    audit.record(AuditEvent::new(AuditAction::Register).actor(new_user.id).target(new_user.id));
 
     let response = RegisterResponse {
-        message: "User registered successfully".to_string(),
-        token,
+        message: "User registered successfully. Check your email to verify your account".to_string(),
+        user: UserResponse::from(new_user),
     };
 
-    statistics.as_ref().increment("register_success").await;
+    metrics.register_success.inc();
     info!("User {} registered successfully", user.username);
     Ok(HttpResponse::Ok().json(response))
 }
 
-#[get("/user/{user_id}")]
+#[post("/login")]
+#[allow(clippy::too_many_arguments)]
+pub async fn login(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    keys: web::Data<JwtKeys>,
+    config: web::Data<AppConfig>,
+    clock: web::Data<dyn Clock>,
+    throttle: web::Data<LoginThrottle>,
+    stats: web::Data<Arc<Statistics>>,
+    hashers: web::Data<PasswordHashers>,
+    audit: Audit,
+    credentials: web::Json<LoginUser>,
+) -> Result<HttpResponse, AppError> {
+    info!("Login function called for: {}", credentials.login);
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_else(|| "unknown".to_string());
+    let now = clock.now();
+
+    let (ip_failures, ip_locked_until) = throttle.status(&ip, now);
+    if let Some(until) = ip_locked_until {
+        warn!("Rejected login from locked IP {} (locked until {})", ip, until);
+        return Err(AppError::RateLimitExceeded);
+    }
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let account = db::get_user_credentials(&client, &credentials.login).await?;
+
+    // Slow down repeated guessing, whether it targets one account or comes from one IP.
+    // Login names without an account are slowed down the same way, so the delay doesn't
+    // reveal which ones exist.
+    let account_failures = match &account {
+        Some((user, _)) => user.failed_login_attempts.max(0) as u32,
+        None => throttle.unknown_login_failures(&credentials.login, now),
+    };
+    let delay = lockout::backoff_delay(throttle.config(), account_failures.max(ip_failures));
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+
+    // Always run a password verification, even for unknown users and accounts without a
+    // password, so the response time does not reveal whether the account exists.
+    let stored_hash = account
+        .as_ref()
+        .and_then(|(_, hash)| hash.as_deref())
+        .unwrap_or_else(|| hashers.dummy_hash());
+    let password_matches = hashers.verify_password(&credentials.password, stored_hash).await.unwrap_or_else(|e| {
+        error!("Failed to verify password for {}: {}", credentials.login, e);
+        false
+    });
+
+    // A locked account gets the same answer as a wrong password, whatever the password:
+    // a distinct answer would reveal that the account exists, or that the password is right
+    if let Some((user, until)) = account.as_ref().and_then(|(user, _)| user.locked_until(now).map(|until| (user, until))) {
+        info!("Rejected login for locked user {} (locked until {})", credentials.login, until);
+        audit
+            .record(
+                AuditEvent::new(AuditAction::LoginFailure)
+                    .target(user.id)
+                    .details(json!({"login": credentials.login, "reason": "locked"})),
+            );
+        return Err(AppError::Unauthorized);
+    }
+
+    let (user, stored_hash) = match account {
+        Some((user, Some(stored_hash))) if password_matches => (user, stored_hash),
+        account => {
+            info!("Failed login attempt for: {}", credentials.login);
+            let mut event = AuditEvent::new(AuditAction::LoginFailure)
+                .details(json!({"login": credentials.login, "reason": "invalid_credentials"}));
+            if let Some((user, _)) = &account {
+                event = event.target(user.id);
+            }
+            audit.record(event);
+            if account.is_none() {
+                throttle.record_unknown_login_failure(&credentials.login, now);
+            }
+            record_failed_login(&client, throttle.get_ref(), &stats, &ip, account.map(|(user, _)| user), now).await?;
+            return Err(AppError::Unauthorized);
+        }
+    };
+
+    // The plain password is only available now, so this is when outdated hashes get replaced
+    if hashers.needs_rehash(&stored_hash) {
+        upgrade_password_hash(&client, &hashers, &user, &credentials.password, &stored_hash).await;
+    }
+
+    // Only reveal the account status once the password has been proven
+    if let Err(e) = user.ensure_active() {
+        info!("Rejected login for {} user {}", user.status, user.username);
+        return Err(e);
+    }
+
+    finish_first_factor(&client, &keys, &config, &audit, &user).await
+}
+
+// Continues a login whose first factor, the password or an external provider, was accepted.
+// With two-factor authentication that only earns a short-lived token for the second step.
+pub(crate) async fn finish_first_factor(
+    client: &Client,
+    keys: &JwtKeys,
+    config: &AppConfig,
+    audit: &Audit,
+    user: &auth::User,
+) -> Result<HttpResponse, AppError> {
+    if db::get_totp_settings(client, user.id).await?.is_some_and(|totp| totp.enabled) {
+        let mfa_token = auth::generate_mfa_pending_token(keys, &user.id.to_string()).map_err(|e| {
+            error!("Failed to generate MFA pending token for user {}: {}", user.username, e);
+            AppError::InternalServerError
+        })?;
+        info!("User {} passed the first login step, second factor required", user.username);
+        return Ok(HttpResponse::Ok().json(MfaRequiredResponse {
+            message: "Two-factor authentication required".to_string(),
+            mfa_required: true,
+            mfa_token,
+        }));
+    }
+
+    complete_login(client, keys, config, audit, user).await
+}
+
+// Re-hashes a password with the current algorithm and parameters. Failing to do so
+// doesn't fail the login; the next login tries again.
+async fn upgrade_password_hash(
+    client: &Client,
+    hashers: &Arc<PasswordHashers>,
+    user: &auth::User,
+    password: &str,
+    old_hash: &str,
+) {
+    let new_hash = match hashers.hash_password(password).await {
+        Ok(new_hash) => new_hash,
+        Err(e) => {
+            error!("Failed to re-hash password of user {}: {}", user.id, e);
+            return;
+        }
+    };
+    match db::upgrade_password_hash(client, user.id, old_hash, &new_hash).await {
+        Ok(true) => info!("Upgraded password hash of user {}", user.id),
+        Ok(false) => info!("Password of user {} changed meanwhile, hash not upgraded", user.id),
+        Err(e) => error!("Failed to store upgraded password hash of user {}: {}", user.id, e),
+    }
+}
+
+// Counts a failed password or second factor against the client IP and, if it exists, the account,
+// locking either once it reaches its threshold
+pub(crate) async fn record_failed_login(
+    client: &Client,
+    throttle: &LoginThrottle,
+    stats: &Statistics,
+    ip: &str,
+    user: Option<auth::User>,
+    now: DateTime<Utc>,
+) -> Result<(), AppError> {
+    let config = throttle.config();
+
+    if let Some(until) = throttle.record_failure(ip, now) {
+        warn!("Locked IP {} out of logins until {}", ip, until);
+        stats.log_error(format!("Login lockout: IP {} locked until {}", ip, until)).await;
+    }
+
+    if let Some(user) = user {
+        let forget_before = now - Duration::seconds(config.max_lockout_seconds as i64);
+        let failures = db::record_failed_login(client, user.id, now, forget_before).await?;
+        if let Some(lockout) = lockout::lockout_duration(config, config.max_failed_attempts, failures.max(0) as u32) {
+            let until = now + lockout;
+            db::lock_user_until(client, user.id, until).await?;
+            warn!("Locked user {} until {} after {} failed logins", user.username, until, failures);
+            stats
+                .log_error(format!("Login lockout: user {} locked until {} after {} failed logins", user.id, until, failures))
+                .await;
+        }
+    }
+    Ok(())
+}
+
+// Finishes a login once every factor has been checked: records it and issues an access
+// token plus a refresh token starting a new family
+pub(crate) async fn complete_login(
+    client: &Client,
+    keys: &JwtKeys,
+    config: &AppConfig,
+    audit: &Audit,
+    user: &auth::User,
+) -> Result<HttpResponse, AppError> {
+    // Failures are only forgotten now: resetting them after the password step would let
+    // wrong second factors be spread over new challenges without ever locking the account
+    if user.failed_login_attempts > 0 {
+        db::unlock_user(client, user.id).await?;
+    }
+
+    db::update_last_login(client, user.id).await.map_err(|e| {
+        error!("Failed to update last login for user {}: {}", user.id, e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    // Every login starts a new session, which also names the refresh token family
+    let session_id = Uuid::new_v4();
+    db::record_session_use(client, session_id, user.id, audit.context()).await?;
+
+    let token = auth::generate_session_token(keys, &user.id.to_string(), session_id).map_err(|e| {
+        error!("Failed to generate JWT token for user {}: {}", user.username, e);
+        AppError::InternalServerError
+    })?;
+
+    let refresh_token = token::issue_refresh_token(client, config, user.id, session_id).await?;
+
+    audit.record(AuditEvent::new(AuditAction::LoginSuccess).actor(user.id).target(user.id));
+    info!("User {} logged in successfully", user.username);
+    Ok(HttpResponse::Ok().json(LoginResponse {
+        message: "Login successful".to_string(),
+        token,
+        refresh_token,
+    }))
+}
+
+#[patch("/user/me", wrap = "RequireAuth")]
+pub async fn update_me(
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+    config: web::Data<AppConfig>,
+    current: AuthenticatedUser,
+    audit: Audit,
+    body: web::Json<UpdateProfile>,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+
+    let body = body.into_inner();
+    let mut client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let (user, pending_email) = db::update_user_profile(
+        &client,
+        current.user.id,
+        body.username.as_deref(),
+        body.email.as_deref(),
+        body.avatar.as_ref().map(|avatar| avatar.as_deref()),
+    )
+    .await?;
+
+    // The new address only replaces the current one once the link sent to it has been opened,
+    // so nobody can move an account to an address they don't control
+    if let Some(pending) = pending_email.as_deref().filter(|_| body.email.is_some()) {
+        audit.record(
+            AuditEvent::new(AuditAction::EmailChange)
+                .actor(user.id)
+                .target(user.id)
+                .details(json!({"old_email": user.email, "new_email": pending})),
+        );
+        if let Err(e) = verification::send_email_change_email(&mut client, mailer.get_ref(), &config.mail, user.id, pending).await {
+            error!("Email change confirmation for user {} was not sent: {}", user.id, e);
+        }
+    }
+
+    info!("User {} updated their profile", user.id);
+    Ok(HttpResponse::Ok().json(UserResponse { pending_email, ..UserResponse::from(user) }))
+}
+
+#[put("/user/me/password", wrap = "RequireAuth")]
+#[allow(clippy::too_many_arguments)]
+pub async fn change_password(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    hashers: web::Data<PasswordHashers>,
+    policy: web::Data<PasswordPolicy>,
+    sessions: web::Data<SessionCache>,
+    clock: web::Data<dyn Clock>,
+    throttle: web::Data<LoginThrottle>,
+    stats: web::Data<Arc<Statistics>>,
+    current: AuthenticatedUser,
+    audit: Audit,
+    body: web::Json<ChangePassword>,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_else(|| "unknown".to_string());
+    let now = clock.now();
+
+    let violations = policy.check(&body.new_password, &[&current.user.username, &current.user.email]);
+    if !violations.is_empty() {
+        return Err(AppError::ValidationFailed(violations));
+    }
+
+    let client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let stored_hash = db::get_password_hash(&client, current.user.id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("This account has no password; set one with a password reset".to_string()))?;
+
+    // A stolen access token must not give more guesses at the password than the login does,
+    // so the current password gets the same lockout and backoff
+    let (ip_failures, ip_locked_until) = throttle.status(&ip, now);
+    if ip_locked_until.is_some() {
+        return Err(AppError::RateLimitExceeded);
+    }
+    if current.user.locked_until(now).is_some() {
+        return Err(AppError::Unauthorized);
+    }
+    let account_failures = current.user.failed_login_attempts.max(0) as u32;
+    let delay = lockout::backoff_delay(throttle.config(), account_failures.max(ip_failures));
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+
+    let password_matches = hashers.verify_password(&body.current_password, &stored_hash).await.unwrap_or_else(|e| {
+        error!("Failed to verify password for user {}: {}", current.user.id, e);
+        false
+    });
+    if !password_matches {
+        info!("Wrong current password in password change for user {}", current.user.id);
+        audit
+            .record(
+                AuditEvent::new(AuditAction::LoginFailure)
+                    .actor(current.user.id)
+                    .target(current.user.id)
+                    .details(json!({"reason": "wrong_current_password"})),
+            );
+        record_failed_login(&client, throttle.get_ref(), &stats, &ip, Some(current.user), now).await?;
+        return Err(AppError::Unauthorized);
+    }
+
+    let hashed_password = hashers.hash_password(&body.new_password).await.map_err(|e| {
+        error!("Failed to hash password for user {}: {}", current.user.id, e);
+        AppError::InternalServerError
+    })?;
+    db::update_password_hash(&client, current.user.id, &hashed_password).await?;
+
+    // Sessions started with the old password must log in again
+    let revoked = db::revoke_user_sessions(&client, current.user.id).await?;
+    sessions.revoke(&revoked, Utc::now());
+
+    audit
+        .record(AuditEvent::new(AuditAction::PasswordChange).actor(current.user.id).target(current.user.id));
+    info!("User {} changed their password", current.user.id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/user/me", wrap = "RequireAuth")]
+pub async fn delete_me(
+    pool: web::Data<Pool>,
+    sessions: web::Data<SessionCache>,
+    current: AuthenticatedUser,
+    audit: Audit,
+) -> Result<HttpResponse, AppError> {
+    current.require_session()?;
+
+    let mut client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    db::soft_delete_user(&mut client, current.user.id).await?;
+    let revoked = db::revoke_user_sessions(&client, current.user.id).await?;
+    sessions.revoke(&revoked, Utc::now());
+
+    audit.record(AuditEvent::new(AuditAction::AccountDelete).actor(current.user.id).target(current.user.id));
+    info!("User {} deleted their account", current.user.id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/user/{user_id}", wrap = "RequireAuth")]
 pub async fn get_user(
     pool: web::Data<Pool>,
+    current: AuthenticatedUser,
     user_id: web::Path<i64>,
-    statistics: web::Data<Arc<Statistics>>,
+    metrics: web::Data<UserMetrics>,
 ) -> Result<HttpResponse, AppError> {
-    statistics.as_ref().increment("get_user_requests").await;
+    metrics.get_user_requests.inc();
 
     let user_id = user_id.into_inner();
     info!("Get user function called for user_id: {}", user_id);
@@ -105,18 +586,13 @@ pub async fn get_user(
         }
     };
 
-    let response = UserResponse {
-        id: user.id,
-        username: user.username,
-        email: user.email,
-        created_at: user.created_at,
-        avatar: user.avatar,
-        status: user.status,
-        
-        last_login: user.last_login,
-    };
+    let mut response = UserResponse::from(user);
+    // Whether another account is locked would tell that someone is guessing its password
+    if current.user.id != user_id && !current.has_permission(Permission::UsersRead) {
+        response.locked_until = None;
+    }
 
-    statistics.as_ref().increment("get_user_success").await;
+    metrics.get_user_success.inc();
     info!("User {} retrieved successfully", user_id);
     Ok(HttpResponse::Ok().json(response))
 }
@@ -137,4 +613,21 @@ pub async fn get_statistics(
     })?;
 
     Ok(HttpResponse::Ok().json(stats))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_profile_avatar_presence() {
+        let missing: UpdateProfile = serde_json::from_str(r#"{"username": "newname"}"#).unwrap();
+        assert_eq!(missing.username.as_deref(), Some("newname"));
+        assert_eq!(missing.avatar, None);
+
+        let cleared: UpdateProfile = serde_json::from_str(r#"{"avatar": null}"#).unwrap();
+        assert_eq!(cleared.avatar, Some(None));
+
+        let set: UpdateProfile = serde_json::from_str(r#"{"avatar": "https://example.com/a.png"}"#).unwrap();
+        assert_eq!(set.avatar, Some(Some("https://example.com/a.png".to_string())));
+    }
+}
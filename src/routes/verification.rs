@@ -0,0 +1,148 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use deadpool_postgres::{Client, Pool};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use crate::auth::one_time::{self, TokenPurpose};
+use crate::auth::{User, UserStatus};
+use crate::config::MailConfig;
+use crate::db;
+use crate::error::AppError;
+use crate::mail::{self, Mailer};
+use crate::middleware::rate_limiter::MailRateLimiter;
+
+#[derive(Deserialize)]
+pub struct VerifyEmailQuery {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResendVerification {
+    email: String,
+}
+
+#[derive(Serialize)]
+pub struct MessageResponse {
+    message: String,
+}
+
+fn verification_link(public_base_url: &str, token: &str) -> String {
+    // Tokens are URL-safe base64, so they need no further encoding
+    format!("{}/api/verify-email?token={}", public_base_url.trim_end_matches('/'), token)
+}
+
+// Issues a new verification token for the user, replacing any earlier one, and emails the link
+pub(crate) async fn send_verification_email(
+    client: &mut Client,
+    mailer: &dyn Mailer,
+    config: &MailConfig,
+    user: &User,
+) -> Result<(), AppError> {
+    let link = issue_verification_link(client, config, user.id, TokenPurpose::EmailVerification).await?;
+    let email = mail::verification_email(&user.email, &link, config.verification_token_ttl_seconds / 60);
+    mailer.send(&email).await.map_err(|e| {
+        error!("Failed to send verification email to user {}: {}", user.id, e);
+        AppError::InternalServerError
+    })
+}
+
+// Emails a link to the new address of a user; opening it replaces their current address
+pub(crate) async fn send_email_change_email(
+    client: &mut Client,
+    mailer: &dyn Mailer,
+    config: &MailConfig,
+    user_id: i64,
+    new_email: &str,
+) -> Result<(), AppError> {
+    let link = issue_verification_link(client, config, user_id, TokenPurpose::EmailChange).await?;
+    let email = mail::email_change_email(new_email, &link, config.verification_token_ttl_seconds / 60);
+    mailer.send(&email).await.map_err(|e| {
+        error!("Failed to send email change confirmation for user {}: {}", user_id, e);
+        AppError::InternalServerError
+    })
+}
+
+async fn issue_verification_link(
+    client: &mut Client,
+    config: &MailConfig,
+    user_id: i64,
+    purpose: TokenPurpose,
+) -> Result<String, AppError> {
+    let token = one_time::generate_one_time_token();
+    let expires_at = Utc::now() + Duration::seconds(config.verification_token_ttl_seconds as i64);
+    db::replace_one_time_token(client, user_id, purpose, &one_time::hash_one_time_token(&token), expires_at).await?;
+    Ok(verification_link(&config.public_base_url, &token))
+}
+
+#[get("/verify-email")]
+pub async fn verify_email(
+    pool: web::Data<Pool>,
+    query: web::Query<VerifyEmailQuery>,
+) -> Result<HttpResponse, AppError> {
+    let mut client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    let user_id = db::verify_email(&mut client, &one_time::hash_one_time_token(&query.token))
+        .await?
+        .ok_or_else(|| AppError::BadRequest("Invalid or expired verification token".to_string()))?;
+
+    info!("User {} verified their email address", user_id);
+    Ok(HttpResponse::Ok().json(MessageResponse {
+        message: "Email address verified".to_string(),
+    }))
+}
+
+// Sends a new verification email. The response is the same whether or not the
+// address belongs to a pending account, so it can't be used to probe for accounts.
+#[post("/verify-email/resend")]
+pub async fn resend_verification(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    mailer: web::Data<dyn Mailer>,
+    config: web::Data<crate::config::AppConfig>,
+    limiter: web::Data<MailRateLimiter>,
+    body: web::Json<ResendVerification>,
+) -> Result<HttpResponse, AppError> {
+    let email = body.email.trim();
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_else(|| "unknown".to_string());
+    limiter.check(&format!("ip:{}", ip))?;
+    limiter.check(&format!("email:{}", email))?;
+
+    let mut client = pool.get().await.map_err(|e| {
+        error!("Failed to get database connection: {}", e);
+        AppError::DatabaseError(e.to_string())
+    })?;
+
+    match db::find_user_by_email(&client, email).await? {
+        Some(user) if user.status == UserStatus::PendingVerification => {
+            // Failures are logged but not reported, for the same reason as above
+            if send_verification_email(&mut client, mailer.get_ref(), &config.mail, &user).await.is_ok() {
+                info!("Resent verification email to user {}", user.id);
+            }
+        }
+        _ => info!("Verification email requested for an address without a pending account"),
+    }
+
+    Ok(HttpResponse::Accepted().json(MessageResponse {
+        message: "If the address belongs to an unverified account, a new verification email has been sent".to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verification_link() {
+        assert_eq!(
+            verification_link("https://api.example.com/", "abc-_123"),
+            "https://api.example.com/api/verify-email?token=abc-_123"
+        );
+        assert_eq!(
+            verification_link("http://127.0.0.1:8080", "t"),
+            "http://127.0.0.1:8080/api/verify-email?token=t"
+        );
+    }
+}
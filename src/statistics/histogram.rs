@@ -0,0 +1,242 @@
+use serde::Serialize;
+
+// Upper bound of the first bucket, in milliseconds
+const FIRST_BOUND_MS: f64 = 0.05;
+// Each bucket is this much wider than the one before: four buckets per doubling, so a
+// percentile is never more than 19% above the true value
+const GROWTH: f64 = 1.189_207_115_002_721; // 2^(1/4)
+// Buckets up to just over a minute; anything slower lands in the overflow bucket
+const BUCKETS: usize = 82;
+
+// Upper bound of bucket `index`, in milliseconds. The last bucket has none.
+pub fn bucket_bound(index: usize) -> f64 {
+    if index >= BUCKETS {
+        f64::INFINITY
+    } else {
+        FIRST_BOUND_MS * GROWTH.powi(index as i32)
+    }
+}
+
+fn bucket_index(value_ms: f64) -> usize {
+    if value_ms <= FIRST_BOUND_MS {
+        return 0;
+    }
+    let index = (value_ms / FIRST_BOUND_MS).log(GROWTH).ceil() as usize;
+    // Rounding can put a value sitting right on a bound one bucket too high
+    if index > 0 && index <= BUCKETS && value_ms <= bucket_bound(index - 1) {
+        index - 1
+    } else {
+        index.min(BUCKETS)
+    }
+}
+
+// Latency histogram with logarithmic buckets. Recording is O(1) and the memory use is
+// fixed, whatever the number of requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    // `BUCKETS` buckets plus one for everything above the last bound
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+    max: f64,
+    // Largest value since `restart_interval`, which is what `subtract` leaves as the maximum
+    interval_max: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            counts: vec![0; BUCKETS + 1],
+            count: 0,
+            sum: 0.0,
+            max: 0.0,
+            interval_max: 0.0,
+        }
+    }
+
+    pub fn record(&mut self, value_ms: f64) {
+        let value_ms = value_ms.max(0.0);
+        self.counts[bucket_index(value_ms)] += 1;
+        self.count += 1;
+        self.sum += value_ms;
+        self.max = self.max.max(value_ms);
+        self.interval_max = self.interval_max.max(value_ms);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    // Value below which a `quantile` (0 to 1) of the recorded values lie, as the upper bound
+    // of the bucket it falls in. Never more than the largest value recorded.
+    pub fn percentile(&self, quantile: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let rank = ((quantile * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_bound(index).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    // Removes the values of `earlier`, a previous copy of this histogram, leaving what was
+    // recorded since. The maximum becomes the one since the last `restart_interval`, which
+    // should be when `earlier` was saved.
+    pub fn subtract(&mut self, earlier: &Histogram) {
+        for (count, earlier) in self.counts.iter_mut().zip(&earlier.counts) {
+            *count = count.saturating_sub(*earlier);
+        }
+        self.count = self.count.saturating_sub(earlier.count);
+        self.sum = (self.sum - earlier.sum).max(0.0);
+        self.max = self.interval_max;
+    }
+
+    // Starts a new interval for the maximum once `saved`, an earlier copy of this histogram,
+    // has been written. Values recorded after the copy was taken stay covered: the maximum
+    // drops to the bound of the highest bucket they fell in, or to zero if there are none.
+    pub fn restart_interval(&mut self, saved: &Histogram) {
+        let since = self
+            .counts
+            .iter()
+            .zip(&saved.counts)
+            .rposition(|(count, saved)| count > saved)
+            .map_or(0.0, bucket_bound);
+        self.interval_max = self.interval_max.min(since);
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            p50: self.percentile(0.50),
+            p95: self.percentile(0.95),
+            p99: self.percentile(0.99),
+            max: self.max,
+        }
+    }
+}
+
+// Percentiles of a histogram, in milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_index_respects_bounds() {
+        assert_eq!(bucket_index(0.0), 0);
+        assert_eq!(bucket_index(FIRST_BOUND_MS), 0);
+        for index in 1..BUCKETS {
+            assert_eq!(bucket_index(bucket_bound(index)), index);
+            assert_eq!(bucket_index(bucket_bound(index - 1) * 1.0001), index);
+        }
+        assert_eq!(bucket_index(1_000_000.0), BUCKETS);
+        assert!(bucket_bound(BUCKETS - 1) > 60_000.0);
+    }
+
+    #[test]
+    fn test_empty_histogram() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.percentile(0.99), 0.0);
+    }
+
+    #[test]
+    fn test_percentiles_are_within_bucket_precision() {
+        let mut histogram = Histogram::new();
+        for ms in 1..=1000 {
+            histogram.record(ms as f64);
+        }
+        for (quantile, exact) in [(0.5, 500.0), (0.95, 950.0), (0.99, 990.0)] {
+            let estimate = histogram.percentile(quantile);
+            assert!(estimate >= exact && estimate <= exact * GROWTH, "p{} was {}", quantile * 100.0, estimate);
+        }
+        assert_eq!(histogram.percentile(1.0), 1000.0);
+        assert_eq!(histogram.sum(), 500_500.0);
+    }
+
+    #[test]
+    fn test_tail_latency_shows_in_p99() {
+        let mut histogram = Histogram::new();
+        for _ in 0..98 {
+            histogram.record(2.0);
+        }
+        histogram.record(800.0);
+        histogram.record(900.0);
+        let summary = histogram.summary();
+        assert!(summary.p50 < 2.5);
+        assert!(summary.p99 >= 800.0);
+        assert_eq!(summary.max, 900.0);
+    }
+
+    #[test]
+    fn test_subtract_leaves_recent_values() {
+        let mut histogram = Histogram::new();
+        histogram.record(1.0);
+        let earlier = histogram.clone();
+        histogram.record(100.0);
+        histogram.subtract(&earlier);
+        assert_eq!(histogram.count(), 1);
+        assert!(histogram.percentile(0.5) >= 100.0);
+        // Nothing restarted the interval, so the maximum still covers the earlier value
+        assert_eq!(histogram.summary().max, 100.0);
+    }
+
+    #[test]
+    fn test_subtract_keeps_the_maximum_of_the_interval() {
+        let mut histogram = Histogram::new();
+        histogram.record(900.0);
+        let saved = histogram.clone();
+        histogram.restart_interval(&saved);
+        histogram.record(5.0);
+
+        let mut interval = histogram.clone();
+        interval.subtract(&saved);
+        assert_eq!(interval.count(), 1);
+        assert_eq!(interval.summary().max, 5.0);
+        assert!(interval.percentile(0.99) <= 5.0);
+        // The overall histogram still knows about the slow request
+        assert_eq!(histogram.summary().max, 900.0);
+    }
+
+    #[test]
+    fn test_restart_interval_covers_values_recorded_after_the_copy() {
+        let mut histogram = Histogram::new();
+        histogram.record(900.0);
+        let saved = histogram.clone();
+        // Recorded while the save was running
+        histogram.record(3.0);
+        histogram.restart_interval(&saved);
+
+        let mut interval = histogram.clone();
+        interval.subtract(&saved);
+        let max = interval.summary().max;
+        assert!((3.0..=3.0 * GROWTH).contains(&max), "max was {}", max);
+    }
+}
@@ -0,0 +1,607 @@
+pub mod histogram;
+pub mod prometheus;
+pub mod registry;
+
+use std::collections::{BTreeMap, HashMap};
+use deadpool_postgres::Pool;
+use deadpool_postgres::Client;
+use crate::db;
+use serde::Serialize;
+use chrono::{DateTime, Utc};
+use actix_web::HttpRequest;
+
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::RwLock;
+use histogram::{Histogram, LatencySummary};
+use prometheus::MetricsWriter;
+use registry::{MetricRegistry, MetricSnapshot};
+
+// Number of recent requests kept in memory for `last_requests`
+const RECENT_REQUESTS_CAPACITY: usize = 100;
+
+// Route counted for requests that matched no route, so that scans of random paths share
+// one entry instead of adding one each
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+// Method counted for requests with a method outside the standard ones. Clients can send any
+// token as the method, and each would otherwise add entries to the statistics and `/metrics`.
+pub const OTHER_METHOD: &str = "OTHER";
+
+fn method_label(method: &str) -> &str {
+    match method {
+        "GET" | "HEAD" | "POST" | "PUT" | "DELETE" | "CONNECT" | "OPTIONS" | "TRACE" | "PATCH" => method,
+        _ => OTHER_METHOD,
+    }
+}
+
+// Route template a request was routed to, e.g. `/api/user/{user_id}`, so that statistics
+// are kept per route rather than per path. Only known once the request has been routed.
+pub fn route_of(req: &HttpRequest) -> String {
+    req.match_pattern().unwrap_or_else(|| UNMATCHED_ROUTE.to_string())
+}
+
+#[derive(Clone, Serialize)]
+pub struct StatisticsData {
+    pub total_requests: i64,  // Changed from u64 to i64
+    pub avg_response_time: f64,
+    pub error_rate: f64,
+    pub uptime: f64,
+    pub traffic_distribution: HashMap<String, u64>,
+    pub last_requests: Vec<RequestLog>,
+    pub error_log: Vec<ErrorLog>,
+    pub last_saved: Option<DateTime<Utc>>,
+    // Everything registered with the `MetricRegistry`, by name
+    pub metrics: BTreeMap<String, MetricSnapshot>,
+    // Response time percentiles per route and method
+    pub latency: Vec<RouteLatency>,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct RouteLatency {
+    pub method: String,
+    pub route: String,
+    #[serde(flatten)]
+    pub summary: LatencySummary,
+    // The histogram behind the summary, saved to `api_latency_histogram`
+    #[serde(skip)]
+    pub histogram: Option<Histogram>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct RequestLog {
+    pub method: String,
+    pub endpoint: String,
+    pub status: u16,
+    pub timestamp: DateTime<Utc>,
+    // Admin who made the request with an impersonation token
+    pub impersonated_by: Option<i64>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ErrorLog {
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+// Method and route of a request
+type RouteKey = (String, String);
+// Method, route and response status of a request
+type ResponseKey = (String, String, u16);
+
+// The most recent requests, oldest first. The newest `unsaved` of them haven't been written
+// to `api_request_log` yet.
+#[derive(Default)]
+struct RecentRequests {
+    entries: VecDeque<RequestLog>,
+    unsaved: usize,
+}
+
+// Traffic counters updated by `log_request`. They are kept out of `data` so that logging a
+// request only ever waits for other short, non-async critical sections.
+#[derive(Default)]
+struct RequestAggregates {
+    total: AtomicU64,
+    // Responses with a 4xx or 5xx status
+    errors: AtomicU64,
+    // Sum of all response times, in microseconds
+    response_time_us: AtomicU64,
+    recent: Mutex<RecentRequests>,
+    // Requests per route since the last save; `api_traffic_distribution` is summed up when read
+    traffic: Mutex<HashMap<String, u64>>,
+    // Response times per method and route since start, and as they were at the last save
+    latency: Mutex<HashMap<RouteKey, Histogram>>,
+    saved_latency: Mutex<HashMap<RouteKey, Histogram>>,
+    // Responses per method, route and status since start, for `/metrics`
+    responses: Mutex<HashMap<ResponseKey, u64>>,
+}
+
+impl RequestAggregates {
+    fn record(&self, request: RequestLog, duration_ms: f64) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        if request.status >= 400 {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.response_time_us
+            .fetch_add((duration_ms.max(0.0) * 1000.0) as u64, Ordering::Relaxed);
+
+        *self.traffic.lock().unwrap().entry(request.endpoint.clone()).or_insert(0) += 1;
+        *self
+            .responses
+            .lock()
+            .unwrap()
+            .entry((request.method.clone(), request.endpoint.clone(), request.status))
+            .or_insert(0) += 1;
+        self.latency
+            .lock()
+            .unwrap()
+            .entry((request.method.clone(), request.endpoint.clone()))
+            .or_default()
+            .record(duration_ms);
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.entries.len() == RECENT_REQUESTS_CAPACITY {
+            recent.entries.pop_front();
+        }
+        recent.entries.push_back(request);
+        recent.unsaved = (recent.unsaved + 1).min(RECENT_REQUESTS_CAPACITY);
+    }
+
+    fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    // Mean response time in milliseconds
+    fn avg_response_time(&self) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => self.response_time_us.load(Ordering::Relaxed) as f64 / total as f64 / 1000.0,
+        }
+    }
+
+    // Share of responses with a 4xx or 5xx status, between 0 and 1
+    fn error_rate(&self) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => self.errors.load(Ordering::Relaxed) as f64 / total as f64,
+        }
+    }
+
+    fn unsaved_traffic(&self) -> HashMap<String, u64> {
+        self.traffic.lock().unwrap().clone()
+    }
+
+    fn responses(&self) -> HashMap<ResponseKey, u64> {
+        self.responses.lock().unwrap().clone()
+    }
+
+    fn latency_histograms(&self) -> HashMap<RouteKey, Histogram> {
+        self.latency.lock().unwrap().clone()
+    }
+
+    // Response times recorded between the last save and when `histograms` were taken
+    fn unsaved_latency(&self, histograms: &HashMap<RouteKey, Histogram>) -> Vec<RouteLatency> {
+        let saved = self.saved_latency.lock().unwrap();
+        let mut latency: Vec<RouteLatency> = histograms
+            .iter()
+            .filter_map(|(key, histogram)| {
+                let mut histogram = histogram.clone();
+                if let Some(saved) = saved.get(key) {
+                    histogram.subtract(saved);
+                }
+                (histogram.count() > 0).then(|| RouteLatency {
+                    method: key.0.clone(),
+                    route: key.1.clone(),
+                    summary: histogram.summary(),
+                    histogram: Some(histogram),
+                })
+            })
+            .collect();
+        latency.sort_by(|a, b| (&a.route, &a.method).cmp(&(&b.route, &b.method)));
+        latency
+    }
+
+    fn unsaved_requests(&self) -> Vec<RequestLog> {
+        let recent = self.recent.lock().unwrap();
+        let skip = recent.entries.len() - recent.unsaved;
+        recent.entries.iter().skip(skip).cloned().collect()
+    }
+
+    // Forgets what a save wrote, keeping whatever was logged while it ran. `histograms` are
+    // the response times as they were when the save started.
+    fn mark_saved(&self, traffic: &HashMap<String, u64>, requests: usize, histograms: HashMap<RouteKey, Histogram>) {
+        let mut latency = self.latency.lock().unwrap();
+        for (key, saved) in &histograms {
+            if let Some(histogram) = latency.get_mut(key) {
+                histogram.restart_interval(saved);
+            }
+        }
+        drop(latency);
+        *self.saved_latency.lock().unwrap() = histograms;
+
+        let mut current = self.traffic.lock().unwrap();
+        for (route, saved) in traffic {
+            if let Some(count) = current.get_mut(route) {
+                *count = count.saturating_sub(*saved);
+                if *count == 0 {
+                    current.remove(route);
+                }
+            }
+        }
+        drop(current);
+
+        let mut recent = self.recent.lock().unwrap();
+        recent.unsaved = recent.unsaved.saturating_sub(requests);
+    }
+}
+
+pub struct Statistics {
+    // Uptime and the error log; `snapshot` fills in the request statistics
+    data: RwLock<StatisticsData>,
+    requests: RequestAggregates,
+    metrics: MetricRegistry,
+    started_at: Instant,
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Self {
+            data: RwLock::new(StatisticsData {
+                // Initialize with default values
+                total_requests: 0,
+                avg_response_time: 0.0,
+                error_rate: 0.0,
+                uptime: 0.0,
+                traffic_distribution: HashMap::new(),
+                last_requests: Vec::new(),
+                error_log: Vec::new(),
+                last_saved: None,
+                metrics: BTreeMap::new(),
+                latency: Vec::new(),
+                timestamp: Utc::now(),
+            }),
+            requests: RequestAggregates::default(),
+            metrics: MetricRegistry::new(),
+            started_at: Instant::now(),
+        }
+    }
+
+    // Counters, gauges and histograms registered by the rest of the application
+    pub fn metrics(&self) -> &MetricRegistry {
+        &self.metrics
+    }
+
+    pub async fn get_statistics(&self, client: &Client) -> Result<StatisticsData, Box<dyn std::error::Error>> {
+        let db_stats = db::get_latest_statistics(client).await?;
+        let traffic_distribution = db::get_traffic_distribution(client).await?;
+        let last_requests = db::get_last_requests(client).await?;
+        let error_log = db::get_error_log(client).await?;
+        let latency = db::get_latest_latency(client).await?;
+        let metrics = db::get_latest_metrics(client).await?;
+
+        Ok(StatisticsData {
+            total_requests: db_stats.total_requests,
+            avg_response_time: db_stats.avg_response_time,
+            error_rate: db_stats.error_rate,
+            uptime: db_stats.uptime,
+            traffic_distribution,
+            last_requests,
+            error_log,
+            last_saved: Some(db_stats.timestamp),
+            metrics,
+            latency,
+            timestamp: db_stats.timestamp,
+        })
+    }
+
+    // What the next `save` writes: totals and registered metrics since start, and the traffic,
+    // response times, requests and errors logged since the last save
+    pub async fn snapshot(&self) -> StatisticsData {
+        self.snapshot_with(&self.requests.latency_histograms()).await
+    }
+
+    async fn snapshot_with(&self, histograms: &HashMap<RouteKey, Histogram>) -> StatisticsData {
+        let mut data = self.data.read().await.clone();
+        data.total_requests = self.requests.total() as i64;
+        data.avg_response_time = self.requests.avg_response_time();
+        data.error_rate = self.requests.error_rate();
+        data.traffic_distribution = self.requests.unsaved_traffic();
+        data.last_requests = self.requests.unsaved_requests();
+        data.latency = self.requests.unsaved_latency(histograms);
+        data.metrics = self.metrics.snapshot();
+        data.timestamp = Utc::now();
+        data
+    }
+
+    pub async fn save(&self, pool: &Pool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let histograms = self.requests.latency_histograms();
+        let data = self.snapshot_with(&histograms).await;
+        save_statistics_to_db(pool, &data).await?;
+
+        // What was saved is in the database now; keep only what was logged meanwhile
+        self.requests.mark_saved(&data.traffic_distribution, data.last_requests.len(), histograms);
+        let mut current = self.data.write().await;
+        let saved = data.error_log.len().min(current.error_log.len());
+        current.error_log.drain(..saved);
+        current.last_saved = Some(data.timestamp);
+        Ok(())
+    }
+
+    // Adds an entry to the error log, persisted with the next `save`. Also used for
+    // security events such as account lockouts.
+    pub async fn log_error(&self, message: String) {
+        let mut data = self.data.write().await;
+        data.error_log.push(ErrorLog { message, timestamp: Utc::now() });
+    }
+
+    pub async fn update_uptime(&self, uptime: f64) {
+        let mut data = self.data.write().await;
+        data.uptime = uptime;
+    }
+
+    // Writes the in-memory statistics since start in the Prometheus text format. Unlike
+    // `snapshot`, everything is cumulative, as Prometheus expects of counters and histograms.
+    pub fn write_metrics(&self, writer: &mut MetricsWriter) {
+        let mut responses: Vec<_> = self.requests.responses().into_iter().collect();
+        responses.sort();
+        writer.family("api_http_requests_total", "counter", "HTTP requests served, by method, route and status");
+        for ((method, route, status), count) in &responses {
+            let status = status.to_string();
+            writer.sample(
+                "api_http_requests_total",
+                &[("method", method), ("route", route), ("status", &status)],
+                *count as f64,
+            );
+        }
+
+        let mut histograms: Vec<_> = self.requests.latency_histograms().into_iter().collect();
+        histograms.sort_by(|a, b| a.0.cmp(&b.0));
+        writer.family("api_http_request_duration_seconds", "histogram", "Response times, by method and route");
+        for ((method, route), histogram) in &histograms {
+            writer.histogram("api_http_request_duration_seconds", &[("method", method), ("route", route)], histogram);
+        }
+
+        self.metrics.write_metrics(writer);
+
+        writer.family("api_uptime_seconds", "gauge", "Time since the server started");
+        writer.sample("api_uptime_seconds", &[], self.started_at.elapsed().as_secs_f64());
+    }
+
+    // Counts a finished request; `route` is its `route_of` and `duration` its response time
+    // in milliseconds. Non-standard methods are counted as `OTHER`.
+    pub async fn log_request(&self, method: &str, route: &str, status: u16, duration: f64, impersonated_by: Option<i64>) {
+        let request = RequestLog {
+            method: method_label(method).to_string(),
+            endpoint: route.to_string(),
+            status,
+            timestamp: Utc::now(),
+            impersonated_by,
+        };
+        self.requests.record(request, duration);
+    }
+}
+
+async fn save_statistics_to_db(pool: &Pool, data: &StatisticsData) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = pool.get().await?;
+    db::insert_statistics(&client, data).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_statistics_new() {
+        let stats = Statistics::new();
+        assert!(stats.metrics().snapshot().is_empty());
+
+        let data = stats.data.read().await;
+        assert_eq!(data.total_requests, 0);
+        assert_eq!(data.uptime, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_includes_registered_metrics() {
+        let stats = Statistics::new();
+        let counter = stats.metrics().counter("register_requests", "Registration attempts");
+        counter.inc();
+        counter.inc();
+        stats.metrics().gauge("queue_length", "Jobs waiting").set(3.0);
+
+        let data = stats.snapshot().await;
+        assert_eq!(data.metrics["register_requests"], MetricSnapshot::Counter { value: 2.0 });
+        assert_eq!(data.metrics["queue_length"], MetricSnapshot::Gauge { value: 3.0 });
+
+        let json = serde_json::to_value(&data.metrics).unwrap();
+        assert_eq!(json["register_requests"], serde_json::json!({"kind": "counter", "value": 2.0}));
+    }
+
+    #[tokio::test]
+    async fn test_update_uptime() {
+        let stats = Statistics::new();
+        let new_uptime = 99.95;
+        stats.update_uptime(new_uptime).await;
+
+        let data = stats.data.read().await;
+        assert_eq!(data.uptime, new_uptime);
+    }
+
+    #[tokio::test]
+    async fn test_log_request() {
+        let stats = Statistics::new();
+
+        let initial_total_requests = stats.snapshot().await.total_requests;
+        assert_eq!(initial_total_requests, 0);
+
+        stats.log_request("GET", "/test", 200, 10.0, None).await;
+        stats.log_request("GET", "/test", 404, 20.0, None).await;
+        stats.log_request("POST", "/other", 500, 60.0, Some(1)).await;
+
+        let data = stats.snapshot().await;
+        assert_eq!(data.total_requests, initial_total_requests + 3);
+        assert_eq!(data.avg_response_time, 30.0);
+        assert!((data.error_rate - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(data.traffic_distribution["/test"], 2);
+        assert_eq!(data.traffic_distribution["/other"], 1);
+        assert_eq!(data.last_requests.len(), 3);
+        assert_eq!(data.last_requests[2].status, 500);
+        assert_eq!(data.last_requests[2].impersonated_by, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_recent_requests_are_bounded() {
+        let stats = Statistics::new();
+        for i in 0..RECENT_REQUESTS_CAPACITY + 5 {
+            stats.log_request("GET", &format!("/{}", i), 200, 1.0, None).await;
+        }
+
+        let data = stats.snapshot().await;
+        assert_eq!(data.total_requests, (RECENT_REQUESTS_CAPACITY + 5) as i64);
+        assert_eq!(data.last_requests.len(), RECENT_REQUESTS_CAPACITY);
+        assert_eq!(data.last_requests[0].endpoint, "/5");
+    }
+
+    #[tokio::test]
+    async fn test_saved_traffic_is_not_saved_again() {
+        let stats = Statistics::new();
+        stats.log_request("GET", "/a", 200, 1.0, None).await;
+        stats.log_request("GET", "/b", 200, 1.0, None).await;
+        let histograms = stats.requests.latency_histograms();
+        let saved = stats.snapshot_with(&histograms).await;
+
+        // Logged while the save was running
+        stats.log_request("GET", "/a", 200, 1.0, None).await;
+        stats.requests.mark_saved(&saved.traffic_distribution, saved.last_requests.len(), histograms);
+
+        let data = stats.snapshot().await;
+        assert_eq!(data.total_requests, 3);
+        assert_eq!(data.traffic_distribution, HashMap::from([("/a".to_string(), 1)]));
+        assert_eq!(data.last_requests.len(), 1);
+        assert_eq!(data.latency.len(), 1);
+        assert_eq!(data.latency[0].summary.count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_latency_percentiles_per_route_and_method() {
+        let stats = Statistics::new();
+        for _ in 0..99 {
+            stats.log_request("GET", "/slow", 200, 10.0, None).await;
+        }
+        stats.log_request("GET", "/slow", 200, 2000.0, None).await;
+        stats.log_request("POST", "/slow", 201, 1.0, None).await;
+
+        let data = stats.snapshot().await;
+        let routes: Vec<_> = data.latency.iter().map(|l| (l.method.as_str(), l.route.as_str())).collect();
+        assert_eq!(routes, [("GET", "/slow"), ("POST", "/slow")]);
+
+        let get = &data.latency[0].summary;
+        assert_eq!(get.count, 100);
+        assert!(get.p50 >= 10.0 && get.p50 < 12.0);
+        assert!(get.p99 < 12.0);
+        assert_eq!(get.max, 2000.0);
+    }
+
+    #[tokio::test]
+    async fn test_non_standard_methods_are_counted_as_other() {
+        let stats = Statistics::new();
+        stats.log_request("GET", "/a", 200, 1.0, None).await;
+        stats.log_request("FOO", "/a", 405, 1.0, None).await;
+        stats.log_request("BAR", "/a", 405, 1.0, None).await;
+        stats.log_request("get", "/a", 405, 1.0, None).await;
+
+        let data = stats.snapshot().await;
+        let methods: Vec<_> = data.latency.iter().map(|l| (l.method.as_str(), l.summary.count)).collect();
+        assert_eq!(methods, [("GET", 1), (OTHER_METHOD, 3)]);
+        assert_eq!(stats.requests.responses().len(), 2);
+        assert_eq!(data.last_requests[1].method, OTHER_METHOD);
+    }
+
+    #[tokio::test]
+    async fn test_saved_latency_maximum_is_per_interval() {
+        let stats = Statistics::new();
+        stats.log_request("GET", "/a", 200, 2000.0, None).await;
+        let histograms = stats.requests.latency_histograms();
+        let saved = stats.snapshot_with(&histograms).await;
+        stats.requests.mark_saved(&saved.traffic_distribution, saved.last_requests.len(), histograms);
+        assert_eq!(saved.latency[0].summary.max, 2000.0);
+
+        stats.log_request("GET", "/a", 200, 4.0, None).await;
+        let data = stats.snapshot().await;
+        assert_eq!(data.latency[0].summary.count, 1);
+        assert_eq!(data.latency[0].summary.max, 4.0);
+        assert!(data.latency[0].summary.p99 <= 4.0);
+    }
+
+    #[tokio::test]
+    async fn test_write_metrics_is_cumulative() {
+        let stats = Statistics::new();
+        stats.log_request("GET", "/a", 200, 1.0, None).await;
+        stats.log_request("GET", "/a", 404, 1.0, None).await;
+        stats.metrics().counter("register_requests", "Registration attempts").inc();
+        let histograms = stats.requests.latency_histograms();
+        let saved = stats.snapshot_with(&histograms).await;
+        stats.requests.mark_saved(&saved.traffic_distribution, saved.last_requests.len(), histograms);
+        stats.log_request("GET", "/a", 200, 1.0, None).await;
+
+        let mut writer = MetricsWriter::new();
+        stats.write_metrics(&mut writer);
+        let output = writer.finish();
+        assert!(output.contains("api_http_requests_total{method=\"GET\",route=\"/a\",status=\"200\"} 2\n"));
+        assert!(output.contains("api_http_requests_total{method=\"GET\",route=\"/a\",status=\"404\"} 1\n"));
+        assert!(output.contains("api_http_request_duration_seconds_count{method=\"GET\",route=\"/a\"} 3\n"));
+        assert!(output.contains("api_register_requests_total 1\n"));
+        assert!(output.contains("# TYPE api_uptime_seconds gauge\n"));
+    }
+
+    #[actix_web::test]
+    async fn test_route_of_uses_the_route_template() {
+        use actix_web::{test, web, App, HttpResponse};
+
+        let app = test::init_service(
+            App::new().service(
+                web::scope("/api").route("/user/{user_id}", web::get().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/api/user/42").to_request()).await;
+        assert_eq!(route_of(res.request()), "/api/user/{user_id}");
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/wp-login.php").to_request()).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(route_of(res.request()), UNMATCHED_ROUTE);
+    }
+
+    #[tokio::test]
+    async fn test_log_error() {
+        let stats = Statistics::new();
+        stats.log_error("Account 1 locked".to_string()).await;
+
+        let data = stats.data.read().await;
+        assert_eq!(data.error_log.len(), 1);
+        assert_eq!(data.error_log[0].message, "Account 1 locked");
+    }
+
+    // Unit tests for `get_statistics` and `save` would require mocking database interactions.
+    // These are better covered by integration tests or tests with a real test database.
+    // For example, a test for `save_statistics_to_db` would look like this if we had db mocking:
+    //
+    // use crate::tests::helpers::test_helpers; // Assuming setup_test_db is available
+    // use crate::db; // For mockable db functions
+    //
+    // #[tokio::test]
+    // async fn test_save_statistics_to_db_mocked() {
+    //     // Mock setup for db::insert_statistics
+    // }
+}
\ No newline at end of file
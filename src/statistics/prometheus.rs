@@ -0,0 +1,180 @@
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::str::FromStr;
+use ipnet::IpNet;
+use super::histogram::{bucket_bound, Histogram};
+
+// Content type of the Prometheus text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// Only every fourth histogram bucket is exported, one per doubling of the response time,
+// which keeps the number of series per route reasonable
+const EXPORTED_BUCKET_STEP: usize = 4;
+
+// Builds a response in the Prometheus text exposition format
+#[derive(Default)]
+pub struct MetricsWriter {
+    output: String,
+}
+
+impl MetricsWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Starts a metric family; `kind` is "counter", "gauge" or "histogram"
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        let _ = writeln!(self.output, "# HELP {} {}", name, help);
+        let _ = writeln!(self.output, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.output.push_str(name);
+        self.write_labels(labels, None);
+        let _ = writeln!(self.output, " {}", format_value(value));
+    }
+
+    // Writes the `_bucket`, `_sum` and `_count` samples of a histogram of response times
+    // in milliseconds, converted to seconds
+    pub fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let counts = histogram.counts();
+        let mut cumulative = 0;
+        for (index, count) in counts.iter().enumerate() {
+            cumulative += count;
+            let last = index == counts.len() - 1;
+            if index % EXPORTED_BUCKET_STEP != 0 && !last {
+                continue;
+            }
+            let le = if last { "+Inf".to_string() } else { format_value(round_bound(bucket_bound(index) / 1000.0)) };
+            let _ = write!(self.output, "{}_bucket", name);
+            self.write_labels(labels, Some(&le));
+            let _ = writeln!(self.output, " {}", cumulative);
+        }
+        self.sample(&format!("{}_sum", name), labels, histogram.sum() / 1000.0);
+        self.sample(&format!("{}_count", name), labels, histogram.count() as f64);
+    }
+
+    pub fn finish(self) -> String {
+        self.output
+    }
+
+    fn write_labels(&mut self, labels: &[(&str, &str)], le: Option<&str>) {
+        if labels.is_empty() && le.is_none() {
+            return;
+        }
+        let pairs: Vec<String> = labels
+            .iter()
+            .copied()
+            .chain(le.map(|le| ("le", le)))
+            .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+            .collect();
+        let _ = write!(self.output, "{{{}}}", pairs.join(","));
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+// Bucket bounds are computed with floating point powers; rounding them keeps the `le` labels
+// short, e.g. 0.0001 instead of 0.00010000000000000002
+fn round_bound(value: f64) -> f64 {
+    format!("{:.9e}", value).parse().unwrap_or(value)
+}
+
+// Networks allowed to scrape `/metrics` without authentication
+#[derive(Debug, Clone)]
+pub struct ScrapeAllowlist {
+    networks: Vec<IpNet>,
+}
+
+impl ScrapeAllowlist {
+    // Accepts networks in CIDR notation, or single addresses
+    pub fn new(networks: &[String]) -> Result<Self, String> {
+        let networks = networks
+            .iter()
+            .map(|network| {
+                IpNet::from_str(network)
+                    .or_else(|_| IpAddr::from_str(network).map(IpNet::from))
+                    .map_err(|_| format!("Invalid network in metrics.allowed_networks: {}", network))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(ScrapeAllowlist { networks })
+    }
+
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+        let addr = addr.to_canonical();
+        self.networks.iter().any(|network| network.contains(&addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples_and_label_escaping() {
+        let mut writer = MetricsWriter::new();
+        writer.family("api_requests_total", "counter", "Requests served");
+        writer.sample("api_requests_total", &[("route", "/a\"b"), ("status", "200")], 3.0);
+        writer.sample("api_uptime_seconds", &[], 1.5);
+
+        assert_eq!(
+            writer.finish(),
+            "# HELP api_requests_total Requests served\n\
+             # TYPE api_requests_total counter\n\
+             api_requests_total{route=\"/a\\\"b\",status=\"200\"} 3\n\
+             api_uptime_seconds 1.5\n"
+        );
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative_seconds() {
+        let mut histogram = Histogram::new();
+        histogram.record(0.01);
+        histogram.record(3.0);
+        histogram.record(1_000_000.0);
+
+        let mut writer = MetricsWriter::new();
+        writer.histogram("api_duration_seconds", &[("route", "/a")], &histogram);
+        let output = writer.finish();
+        let buckets: Vec<&str> = output.lines().filter(|line| line.contains("_bucket")).collect();
+
+        assert_eq!(buckets[0], "api_duration_seconds_bucket{route=\"/a\",le=\"0.00005\"} 1");
+        assert!(buckets.contains(&"api_duration_seconds_bucket{route=\"/a\",le=\"0.0016\"} 1"));
+        assert!(buckets.contains(&"api_duration_seconds_bucket{route=\"/a\",le=\"0.0032\"} 2"));
+        assert_eq!(*buckets.last().unwrap(), "api_duration_seconds_bucket{route=\"/a\",le=\"+Inf\"} 3");
+        assert!(output.contains("api_duration_seconds_count{route=\"/a\"} 3\n"));
+    }
+
+    #[test]
+    fn test_allowlist_matches_networks_and_addresses() {
+        let allowlist = ScrapeAllowlist::new(&[
+            "10.0.0.0/8".to_string(),
+            "192.168.1.5".to_string(),
+            "::1/128".to_string(),
+        ])
+        .unwrap();
+
+        assert!(allowlist.allows("10.20.30.40".parse().unwrap()));
+        assert!(allowlist.allows("192.168.1.5".parse().unwrap()));
+        assert!(allowlist.allows("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(allowlist.allows("::1".parse().unwrap()));
+        assert!(!allowlist.allows("192.168.1.6".parse().unwrap()));
+        assert!(!allowlist.allows("11.0.0.1".parse().unwrap()));
+
+        assert!(ScrapeAllowlist::new(&["10.0.0.0/33".to_string()]).is_err());
+    }
+}
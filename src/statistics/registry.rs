@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use serde::Serialize;
+use super::histogram::{Histogram, LatencySummary};
+use super::prometheus::MetricsWriter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "counter" => Some(MetricKind::Counter),
+            "gauge" => Some(MetricKind::Gauge),
+            "histogram" => Some(MetricKind::Histogram),
+            _ => None,
+        }
+    }
+}
+
+// A count that only goes up. Clones share the same value.
+#[derive(Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, count: u64) {
+        self.0.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// A value that can go up and down. Clones share the same value.
+#[derive(Clone, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+// Durations in milliseconds, e.g. of a call to another service. Clones share the same histogram.
+#[derive(Clone, Default)]
+pub struct HistogramMetric(Arc<Mutex<Histogram>>);
+
+impl HistogramMetric {
+    pub fn record(&self, value_ms: f64) {
+        self.0.lock().unwrap().record(value_ms);
+    }
+
+    pub fn get(&self) -> Histogram {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[derive(Clone)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(HistogramMetric),
+}
+
+impl Metric {
+    fn kind(&self) -> MetricKind {
+        match self {
+            Metric::Counter(_) => MetricKind::Counter,
+            Metric::Gauge(_) => MetricKind::Gauge,
+            Metric::Histogram(_) => MetricKind::Histogram,
+        }
+    }
+}
+
+struct RegisteredMetric {
+    help: String,
+    metric: Metric,
+}
+
+// Value of a metric at one point in time, as returned by `/api/statistics`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum MetricSnapshot {
+    Counter { value: f64 },
+    Gauge { value: f64 },
+    Histogram {
+        // Sum of all recorded values, in milliseconds
+        sum: f64,
+        #[serde(flatten)]
+        summary: LatencySummary,
+    },
+}
+
+impl MetricSnapshot {
+    pub fn kind(&self) -> MetricKind {
+        match self {
+            MetricSnapshot::Counter { .. } => MetricKind::Counter,
+            MetricSnapshot::Gauge { .. } => MetricKind::Gauge,
+            MetricSnapshot::Histogram { .. } => MetricKind::Histogram,
+        }
+    }
+
+    // The rows of `api_metric_samples` for this snapshot, as (statistic, value) pairs
+    pub fn samples(&self) -> Vec<(&'static str, f64)> {
+        match self {
+            MetricSnapshot::Counter { value } | MetricSnapshot::Gauge { value } => vec![("value", *value)],
+            MetricSnapshot::Histogram { sum, summary } => vec![
+                ("count", summary.count as f64),
+                ("sum", *sum),
+                ("p50", summary.p50),
+                ("p95", summary.p95),
+                ("p99", summary.p99),
+                ("max", summary.max),
+            ],
+        }
+    }
+
+    // Rebuilds a snapshot from its rows in `api_metric_samples`; missing statistics are zero
+    pub fn from_samples(kind: MetricKind, samples: &BTreeMap<String, f64>) -> Self {
+        let get = |statistic: &str| samples.get(statistic).copied().unwrap_or(0.0);
+        match kind {
+            MetricKind::Counter => MetricSnapshot::Counter { value: get("value") },
+            MetricKind::Gauge => MetricSnapshot::Gauge { value: get("value") },
+            MetricKind::Histogram => MetricSnapshot::Histogram {
+                sum: get("sum"),
+                summary: LatencySummary {
+                    count: get("count") as u64,
+                    p50: get("p50"),
+                    p95: get("p95"),
+                    p99: get("p99"),
+                    max: get("max"),
+                },
+            },
+        }
+    }
+}
+
+// Named counters, gauges and histograms that parts of the application register at startup.
+// Registering returns a handle that updates the metric without going through the registry.
+// Every registered metric is saved with the statistics and exported at `/metrics`.
+#[derive(Default)]
+pub struct MetricRegistry {
+    metrics: RwLock<BTreeMap<String, RegisteredMetric>>,
+}
+
+impl MetricRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Names are lowercase snake case, e.g. `register_requests`. Registering a name again
+    // returns the existing metric.
+    pub fn counter(&self, name: &str, help: &str) -> Counter {
+        match self.register(name, help, || Metric::Counter(Counter::default())) {
+            Metric::Counter(counter) => counter,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn gauge(&self, name: &str, help: &str) -> Gauge {
+        match self.register(name, help, || Metric::Gauge(Gauge::default())) {
+            Metric::Gauge(gauge) => gauge,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn histogram(&self, name: &str, help: &str) -> HistogramMetric {
+        match self.register(name, help, || Metric::Histogram(HistogramMetric::default())) {
+            Metric::Histogram(histogram) => histogram,
+            _ => unreachable!(),
+        }
+    }
+
+    // Registration happens at startup, so a bad name or a name registered with two kinds
+    // is a programming error and panics
+    fn register(&self, name: &str, help: &str, new: impl FnOnce() -> Metric) -> Metric {
+        assert!(is_valid_name(name), "invalid metric name {:?}", name);
+        let metric = new();
+        let mut metrics = self.metrics.write().unwrap();
+        let registered = metrics.entry(name.to_string()).or_insert_with(|| RegisteredMetric {
+            help: help.to_string(),
+            metric: metric.clone(),
+        });
+        assert_eq!(
+            registered.metric.kind(),
+            metric.kind(),
+            "metric {:?} is already registered as a {}",
+            name,
+            registered.metric.kind().as_str()
+        );
+        registered.metric.clone()
+    }
+
+    // Current value of every registered metric, by name
+    pub fn snapshot(&self) -> BTreeMap<String, MetricSnapshot> {
+        let metrics = self.metrics.read().unwrap();
+        metrics
+            .iter()
+            .map(|(name, registered)| {
+                let snapshot = match &registered.metric {
+                    Metric::Counter(counter) => MetricSnapshot::Counter { value: counter.get() as f64 },
+                    Metric::Gauge(gauge) => MetricSnapshot::Gauge { value: gauge.get() },
+                    Metric::Histogram(histogram) => {
+                        let histogram = histogram.get();
+                        MetricSnapshot::Histogram { sum: histogram.sum(), summary: histogram.summary() }
+                    }
+                };
+                (name.clone(), snapshot)
+            })
+            .collect()
+    }
+
+    // Exports the metrics as `api_<name>_total` for counters, `api_<name>` for gauges and
+    // `api_<name>_seconds` for histograms
+    pub fn write_metrics(&self, writer: &mut MetricsWriter) {
+        let metrics = self.metrics.read().unwrap();
+        for (name, registered) in metrics.iter() {
+            match &registered.metric {
+                Metric::Counter(counter) => {
+                    let name = format!("api_{}_total", name);
+                    writer.family(&name, "counter", &registered.help);
+                    writer.sample(&name, &[], counter.get() as f64);
+                }
+                Metric::Gauge(gauge) => {
+                    let name = format!("api_{}", name);
+                    writer.family(&name, "gauge", &registered.help);
+                    writer.sample(&name, &[], gauge.get());
+                }
+                Metric::Histogram(histogram) => {
+                    let name = format!("api_{}_seconds", name);
+                    writer.family(&name, "histogram", &registered.help);
+                    writer.histogram(&name, &[], &histogram.get());
+                }
+            }
+        }
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registering_twice_shares_the_metric() {
+        let registry = MetricRegistry::new();
+        let first = registry.counter("register_requests", "Registration attempts");
+        let second = registry.counter("register_requests", "Registration attempts");
+        first.inc();
+        second.add(2);
+        assert_eq!(first.get(), 3);
+        assert_eq!(registry.snapshot()["register_requests"], MetricSnapshot::Counter { value: 3.0 });
+    }
+
+    #[test]
+    #[should_panic(expected = "already registered as a counter")]
+    fn test_kind_conflict_panics() {
+        let registry = MetricRegistry::new();
+        registry.counter("jobs", "Jobs run");
+        registry.gauge("jobs", "Jobs running");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid metric name")]
+    fn test_invalid_name_panics() {
+        MetricRegistry::new().counter("Register-Requests", "Registration attempts");
+    }
+
+    #[test]
+    fn test_snapshot_round_trips_through_samples() {
+        let registry = MetricRegistry::new();
+        registry.gauge("queue_length", "Jobs waiting").set(4.0);
+        let duration = registry.histogram("mail_send", "Time to hand an email to the relay");
+        duration.record(10.0);
+        duration.record(30.0);
+
+        for (name, snapshot) in registry.snapshot() {
+            let samples = snapshot.samples().into_iter().map(|(s, v)| (s.to_string(), v)).collect();
+            assert_eq!(MetricSnapshot::from_samples(snapshot.kind(), &samples), snapshot, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_write_metrics_names_by_kind() {
+        let registry = MetricRegistry::new();
+        registry.counter("register_requests", "Registration attempts").inc();
+        registry.gauge("queue_length", "Jobs waiting").set(2.5);
+        registry.histogram("mail_send", "Time to hand an email to the relay").record(1.0);
+
+        let mut writer = MetricsWriter::new();
+        registry.write_metrics(&mut writer);
+        let output = writer.finish();
+        assert!(output.contains("api_register_requests_total 1\n"));
+        assert!(output.contains("api_queue_length 2.5\n"));
+        assert!(output.contains("api_mail_send_seconds_count 1\n"));
+    }
+}
@@ -2,11 +2,18 @@
 // It's intended to be used by integration tests.
 
 // Note: `crate::` here refers to `my_actix_api` (the library crate).
-use crate::config::AppConfig;
+use crate::auth::{oidc, JwtKeys};
+use crate::config::{AppConfig, AuthConfig, JwtAlgorithm};
 use crate::db; // Use db module from the library
+use actix_web::{web, App, HttpResponse, HttpServer};
 use deadpool_postgres::{Client, Pool}; // Import Pool directly from deadpool_postgres
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::fs;
+use std::net::TcpListener;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 /// Sets up a test database by running migrations.
 ///
@@ -48,14 +55,28 @@ pub async fn setup_test_db() -> Result<Pool, Box<dyn std::error::Error + Send +
     let client = pool.get().await // Removed mut
         .map_err(|e| format!("Failed to get client from test DB pool: {}", e))?;
 
-    // Read and execute migration SQL
+    // Start from an empty schema so tables referencing `users` don't block the
+    // DROP statements in the first migration
+    client.batch_execute("DROP SCHEMA public CASCADE; CREATE SCHEMA public;").await
+        .map_err(|e| format!("Failed to reset test DB schema: {}", e))?;
+
+    // Read and execute every migration in order
     // Path relative to workspace root (where Cargo.toml is)
-    let migration_file_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations/20240911144657_recreate_tables.sql");
-    let sql = fs::read_to_string(&migration_file_path)
-        .map_err(|e| format!("Failed to read migration file {:?}: {}", migration_file_path, e))?;
+    let migrations_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    let mut migration_files: Vec<_> = fs::read_dir(&migrations_dir)
+        .map_err(|e| format!("Failed to read migrations directory {:?}: {}", migrations_dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sql"))
+        .collect();
+    migration_files.sort();
+
+    for migration_file_path in migration_files {
+        let sql = fs::read_to_string(&migration_file_path)
+            .map_err(|e| format!("Failed to read migration file {:?}: {}", migration_file_path, e))?;
 
-    client.batch_execute(&sql).await
-        .map_err(|e| format!("Failed to execute migrations: {}", e))?;
+        client.batch_execute(&sql).await
+            .map_err(|e| format!("Failed to execute migration {:?}: {}", migration_file_path, e))?;
+    }
 
     Ok(pool)
 }
@@ -66,3 +87,156 @@ pub async fn clear_tables(_client: &mut Client) -> Result<(), Box<dyn std::error
     // Example: client.batch_execute("TRUNCATE users, api_statistics RESTART IDENTITY CASCADE;").await?;
     unimplemented!("Table clearing not implemented yet. Re-run migrations via setup_test_db.");
 }
+
+// Codes handed out by `MockOidcIssuer::authorize`, waiting to be exchanged at the token endpoint
+struct MockAuthorization {
+    id_token: String,
+    code_challenge: String,
+}
+
+#[derive(Deserialize)]
+struct MockTokenRequest {
+    code: String,
+    code_verifier: String,
+    client_id: String,
+}
+
+/// A local OpenID Connect provider for tests: serves a discovery document, a JWKS and a
+/// token endpoint on a random port, and signs ID tokens with the RS256 test fixture key.
+pub struct MockOidcIssuer {
+    pub issuer: String,
+    pub client_id: String,
+    keys: Arc<JwtKeys>,
+    authorizations: Arc<Mutex<HashMap<String, MockAuthorization>>>,
+}
+
+impl MockOidcIssuer {
+    pub async fn start(client_id: &str) -> Self {
+        let fixture = |name: &str| Some(format!("{}/tests/fixtures/jwt/{}", env!("CARGO_MANIFEST_DIR"), name));
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind mock issuer port");
+        let issuer = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+
+        let keys = Arc::new(
+            JwtKeys::from_config(&AuthConfig {
+                algorithm: JwtAlgorithm::Rs256,
+                secret: None,
+                secret_file: None,
+                private_key_file: fixture("rs256_private.pem"),
+                public_key_file: fixture("rs256_public.pem"),
+                kid: "mock-issuer".to_string(),
+                verification_keys: Vec::new(),
+                verification_keys_dir: None,
+                issuer: issuer.clone(),
+                audience: client_id.to_string(),
+                token_ttl_seconds: 300,
+                refresh_token_ttl_seconds: 0,
+                mfa_pending_ttl_seconds: 0,
+                impersonation_ttl_seconds: 0,
+                session_cache_seconds: 0,
+                totp_issuer: String::new(),
+            })
+            .expect("Failed to load mock issuer keys"),
+        );
+        let authorizations: Arc<Mutex<HashMap<String, MockAuthorization>>> = Arc::default();
+
+        let metadata = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        });
+        let server_keys = Arc::clone(&keys);
+        let server_authorizations = Arc::clone(&authorizations);
+        let server_client_id = client_id.to_string();
+        let server = HttpServer::new(move || {
+            let metadata = metadata.clone();
+            let jwks = server_keys.jwks();
+            let authorizations = Arc::clone(&server_authorizations);
+            let client_id = server_client_id.clone();
+            App::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(move || {
+                        let metadata = metadata.clone();
+                        async move { HttpResponse::Ok().json(metadata) }
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    web::get().to(move || {
+                        let jwks = jwks.clone();
+                        async move { HttpResponse::Ok().json(jwks) }
+                    }),
+                )
+                .route(
+                    "/token",
+                    web::post().to(move |form: web::Form<MockTokenRequest>| {
+                        let authorization = authorizations.lock().unwrap().remove(&form.code);
+                        let valid = authorization.as_ref().is_some_and(|authorization| {
+                            form.client_id == client_id
+                                && oidc::code_challenge(&form.code_verifier) == authorization.code_challenge
+                        });
+                        async move {
+                            match authorization {
+                                Some(authorization) if valid => HttpResponse::Ok().json(json!({
+                                    "access_token": "mock-access-token",
+                                    "token_type": "Bearer",
+                                    "id_token": authorization.id_token,
+                                })),
+                                _ => HttpResponse::BadRequest().json(json!({"error": "invalid_grant"})),
+                            }
+                        }
+                    }),
+                )
+        })
+        .workers(1)
+        .listen(listener)
+        .expect("Failed to start mock issuer")
+        .run();
+        actix_web::rt::spawn(server);
+
+        MockOidcIssuer {
+            issuer,
+            client_id: client_id.to_string(),
+            keys,
+            authorizations,
+        }
+    }
+
+    /// Plays the user signing in at the provider for an authorization URL built by the API.
+    /// The ID token gets the given claims on top of `iss`, `aud`, `iat`, `exp` and the
+    /// request's `nonce`. Returns the authorization code and the `state` to send back.
+    pub fn authorize(&self, authorization_url: &str, claims: Value) -> (String, String) {
+        let url = reqwest::Url::parse(authorization_url).expect("Invalid authorization URL");
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .unwrap_or_default()
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let mut id_token_claims = json!({
+            "iss": self.issuer,
+            "aud": self.client_id,
+            "iat": now,
+            "exp": now + 300,
+            "nonce": param("nonce"),
+        });
+        if let (Some(defaults), Value::Object(claims)) = (id_token_claims.as_object_mut(), claims) {
+            defaults.extend(claims);
+        }
+        let id_token = jsonwebtoken::encode(&self.keys.header(), &id_token_claims, self.keys.encoding_key())
+            .expect("Failed to sign mock ID token");
+
+        let code = uuid::Uuid::new_v4().to_string();
+        self.authorizations.lock().unwrap().insert(
+            code.clone(),
+            MockAuthorization {
+                id_token,
+                code_challenge: param("code_challenge"),
+            },
+        );
+        (code, param("state"))
+    }
+}
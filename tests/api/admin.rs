@@ -0,0 +1,95 @@
+use crate::common::{quick_logins, spawn_app_with, TestApp, TestUser};
+use reqwest::Method;
+use serde_json::{json, Value};
+
+async fn set_permissions(app: &TestApp, token: &str, user: &TestUser, permissions: Value) -> u16 {
+    app.request(Method::PUT, &format!("/api/admin/users/{}/permissions", user.id), token)
+        .json(&permissions)
+        .send()
+        .await
+        .expect("Failed to execute permissions request")
+        .status()
+        .as_u16()
+}
+
+// A user manager with `users:read` and `users:write` but no admin role, and their token
+async fn user_manager(app: &TestApp) -> (TestUser, String) {
+    let manager = app.create_user("usermanager").await;
+    app.set_permissions(&manager, json!({"permissions": ["users:read", "users:write"]})).await;
+    let (token, _) = app.login_tokens(&manager).await;
+    (manager, token)
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn permissions_can_be_granted_within_the_admins_own() {
+    let app = spawn_app_with(quick_logins).await;
+    let (_, token) = user_manager(&app).await;
+    let user = app.create_user("promoteduser").await;
+
+    let status = set_permissions(&app, &token, &user, json!({"permissions": ["users:read"]})).await;
+    assert_eq!(status, 200);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn admins_cannot_change_their_own_permissions() {
+    let app = spawn_app_with(quick_logins).await;
+    let (manager, token) = user_manager(&app).await;
+
+    let status = set_permissions(&app, &token, &manager, json!({"roles": ["admin"]})).await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn permissions_the_admin_lacks_cannot_be_granted() {
+    let app = spawn_app_with(quick_logins).await;
+    let (_, token) = user_manager(&app).await;
+    let user = app.create_user("escalateduser").await;
+
+    let status = set_permissions(&app, &token, &user, json!({"roles": ["admin"]})).await;
+    assert_eq!(status, 403);
+    let status = set_permissions(&app, &token, &user, json!({"permissions": ["audit:read"]})).await;
+    assert_eq!(status, 403);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn users_with_more_permissions_cannot_be_changed() {
+    let app = spawn_app_with(quick_logins).await;
+    let (_, token) = user_manager(&app).await;
+    let admin = app.create_user("protectedadmin").await;
+    app.make_admin(&admin).await;
+
+    let status = set_permissions(&app, &token, &admin, json!({"roles": ["user"]})).await;
+    assert_eq!(status, 403);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn api_keys_cannot_change_permissions() {
+    let app = spawn_app_with(quick_logins).await;
+    let (_, token) = user_manager(&app).await;
+    let user = app.create_user("apikeytarget").await;
+
+    let response = app
+        .request(Method::POST, "/api/user/me/api-keys", &token)
+        .json(&json!({"name": "automation", "scopes": ["users:read", "users:write"]}))
+        .send()
+        .await
+        .expect("Failed to create API key");
+    assert_eq!(response.status().as_u16(), 201);
+    let body: Value = response.json().await.unwrap();
+    let key = body["key"].as_str().expect("No API key returned");
+
+    let response = app
+        .client
+        .put(app.url(&format!("/api/admin/users/{}/permissions", user.id)))
+        .header("Authorization", format!("ApiKey {}", key))
+        .json(&json!({"permissions": ["users:read"]}))
+        .send()
+        .await
+        .expect("Failed to execute permissions request");
+    assert_eq!(response.status().as_u16(), 403);
+}
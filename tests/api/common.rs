@@ -1,39 +1,32 @@
-use my_actix_api::config::AppConfig;
-use my_actix_api::main_routes; // Assuming your main router setup is in a function called `main_routes` in lib.rs or main.rs
-use my_actix_api::logger; // Assuming logger setup is accessible
-use my_actix_api::db; // For Pool
-use my_actix_api::middleware::rate_limiter::RateLimiter; // Import RateLimiter
-
-use actix_web::{test, web, App, HttpServer};
-use deadpool_postgres::Pool;
-use std::sync::Once;
-use std::net::TcpListener;
+use my_actix_api::auth::lockout::LoginThrottle;
+use my_actix_api::auth::oidc::OidcProviders;
+use my_actix_api::auth::sessions::SessionCache;
+use my_actix_api::auth::{Credential, JwtKeys, PasswordHashers, PasswordPolicy};
+use my_actix_api::audit::AuditLogger;
+use my_actix_api::clock::{Clock, FakeClock};
+use my_actix_api::mail::{InMemoryMailer, Mailer};
+use my_actix_api::middleware::rate_limiter::MailRateLimiter;
+use my_actix_api::middleware::request_id::AssignRequestId;
+use my_actix_api::routes::UserMetrics;
+use my_actix_api::statistics::prometheus::ScrapeAllowlist;
+use my_actix_api::statistics::route_of;
+use my_actix_api::test_utils::setup_test_db;
+use my_actix_api::{configure_app_routes, init_database_schema, AppConfig, Pool, RateLimiter, Statistics};
 
-// Ensure this path is correct based on where setup_test_db is.
-// If helpers is in src/tests/mod.rs and helpers.rs, this won't work directly
-// as src/tests is not a module visible to integration tests in `tests/`.
-// `setup_test_db` needs to be part of the `my_actix_api` crate's public API (e.g., in a `my_actix_api::tests_utils` module)
-// or we replicate/call it carefully.
-// For now, let's assume `my_actix_api::tests::helpers::setup_test_db` is made available for integration tests.
-// This typically requires making `src/tests/mod.rs` and `src/tests/helpers.rs` part of the library build
-// when the `test` feature or profile is active, or moving test utils to a `my_actix_api::test_utils` module.
-
-// A simpler way for now if `setup_test_db` cannot be directly called:
-// The `setup_test_db` is in `my_actix_api::src::tests::helpers`.
-// We need to expose it or a similar function from `my_actix_api` lib.
-// Let's assume we will add a `pub mod test_utils;` to `my_actix_api::lib.rs` (or main.rs if it's a binary)
-// and move the content of `src/tests/helpers.rs` there.
-
-use my_actix_api::test_utils::setup_test_db; // Use the new test_utils module
-use my_actix_api::{AppConfig, Pool, RateLimiter, Statistics, configure_app_routes}; // Use items from lib.rs
-use actix_web::{test, web, App, HttpServer}; // HttpServer is needed to run the server
-use std::sync::{Arc, Once};
+use actix_web::dev::Service;
+use actix_web::{web, App, HttpMessage, HttpServer};
+use chrono::Utc;
+use futures::FutureExt;
+use serde_json::{json, Value};
 use std::net::TcpListener;
-use chrono::Utc; // For app_start_time for background tasks
-use my_actix_api::start_background_tasks; // Import the background task starter
+use std::sync::{Arc, Once};
+use tokio::sync::{Mutex, MutexGuard};
 
 static INIT: Once = Once::new();
 
+// Every app starts from a freshly migrated database, so tests that need one take turns
+static DATABASE: Mutex<()> = Mutex::const_new(());
+
 fn initialize_test_logging() {
     INIT.call_once(|| {
         if std::env::var("RUST_LOG").is_err() {
@@ -41,90 +34,231 @@ fn initialize_test_logging() {
         }
         // Attempt to initialize env_logger, but don't panic if it fails (e.g., if already initialized)
         env_logger::try_init().ok();
-        println!("Test logging initialized (env_logger).");
     });
 }
 
 pub struct TestApp {
     pub address: String,
     pub db_pool: Pool,
-    // pub http_client: reqwest::Client, // Can be added if needed for all tests
+    pub statistics: Arc<Statistics>,
+    pub mailer: Arc<InMemoryMailer>,
+    pub clock: Arc<FakeClock>,
+    pub client: reqwest::Client,
+    _database: MutexGuard<'static, ()>,
 }
 
-/// Spawns the application on a random available port and sets up a test database.
+// An account created by `TestApp::create_user`
+pub struct TestUser {
+    pub id: i64,
+    pub username: String,
+    pub email: String,
+    pub password: String,
+}
+
+/// Spawns the application on a random available port with a freshly migrated test database.
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Like `spawn_app`, with the configuration adjusted by `configure` first, e.g. to make
+/// lockouts quick to reach.
+pub async fn spawn_app_with(configure: impl FnOnce(&mut AppConfig)) -> TestApp {
     initialize_test_logging();
-    std::env::set_var("RUN_ENV", "test");
+    let database = DATABASE.lock().await;
 
     // Setup test database using the utility from the library crate
     let db_pool = setup_test_db().await.expect("Failed to set up test DB. Ensure DB server is running and accessible.");
+    init_database_schema(&db_pool).await.expect("Failed to create the statistics tables");
+
+    let mut app_config = AppConfig::new().expect("Failed to load test configuration.");
+    configure(&mut app_config);
+
+    let jwt_keys = web::Data::new(JwtKeys::from_config(&app_config.auth).expect("Failed to load test JWT keys."));
+
+    // Emails are kept in memory so tests can read verification links back
+    let mailer = Arc::new(InMemoryMailer::default());
+    let mailer_data: web::Data<dyn Mailer> = web::Data::from(mailer.clone() as Arc<dyn Mailer>);
+    let mail_rate_limiter = web::Data::new(MailRateLimiter::per_hour(app_config.mail.resend_per_hour));
 
-    // Load configuration
-    let app_config = AppConfig::new().expect("Failed to load test configuration.");
+    // Tests move time explicitly, e.g. to produce TOTP codes
+    let clock = Arc::new(FakeClock::new(Utc::now()));
+    let clock_data: web::Data<dyn Clock> = web::Data::from(clock.clone() as Arc<dyn Clock>);
 
-    // Create Statistics manager instance
-    let statistics_manager = Arc::new(Statistics::new());
+    let login_throttle = web::Data::new(LoginThrottle::new(app_config.lockout.clone()));
+    let password_hashers = web::Data::new(
+        PasswordHashers::from_config(&app_config.password_hashing).expect("Invalid password hashing settings"),
+    );
+    let password_policy = web::Data::new(
+        PasswordPolicy::from_config(&app_config.password_policy).expect("Failed to load the breached password list"),
+    );
+    let audit_logger = web::Data::new(AuditLogger::new(db_pool.clone()));
+    // Providers from the test config; tests can point one at a `test_utils::MockOidcIssuer`
+    let oidc_providers = web::Data::new(OidcProviders::from_config(&app_config.oidc));
+    let session_cache = web::Data::new(SessionCache::new(
+        app_config.auth.session_cache_seconds,
+        app_config.auth.token_ttl_seconds,
+    ));
+    let scrape_allowlist = web::Data::new(
+        ScrapeAllowlist::new(&app_config.metrics.allowed_networks).expect("Invalid metrics settings"),
+    );
+    let rate_limiter = RateLimiter::new(app_config.rate_limit.requests_per_second, app_config.rate_limit.burst_size);
 
-    // It's important that the test database schema is also initialized
-    // if `setup_test_db` only runs migrations but doesn't handle `create_statistics_tables`
-    // However, `setup_test_db` *should* run all migrations, which create all tables.
-    // The `init_database_schema` in lib.rs also calls `create_statistics_tables`,
-    // which might be redundant if migrations already do this.
-    // For tests, `setup_test_db` running the main migration SQL should be sufficient.
+    // Statistics are only saved when a test calls `statistics.save`
+    let statistics = Arc::new(Statistics::new());
+    let user_metrics = web::Data::new(UserMetrics::register(statistics.metrics()));
 
     let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind random port");
     let port = listener.local_addr().unwrap().port();
     let address = format!("http://127.0.0.1:{}", port);
 
-    // Clone Arcs and other data needed for the server factory
     let server_db_pool = db_pool.clone();
-    let server_statistics = Arc::clone(&statistics_manager);
-    let server_app_config = app_config.clone();
-
-    // Start background tasks for the test server instance
-    // Note: For isolated tests, you might not want these running, or want control over them.
-    // For now, we mirror main.rs behavior.
-    let app_start_time = Utc::now();
-    start_background_tasks(Arc::clone(&statistics_manager), db_pool.clone(), app_start_time);
-
-
+    let server_statistics = Arc::clone(&statistics);
     let server = HttpServer::new(move || {
-        let rate_limiter_config = server_app_config.rate_limit.clone();
-        let rate_limiter = RateLimiter::new(rate_limiter_config.requests_per_second, rate_limiter_config.burst_size);
+        let stats = Arc::clone(&server_statistics);
 
         App::new()
-            .wrap(actix_web::middleware::Logger::default()) // Actix default logger
+            .wrap(actix_web::middleware::Logger::default())
             .wrap(actix_cors::Cors::permissive())
-            .wrap(rate_limiter)
-            // Custom logging middleware for statistics - simplified for spawn_app context
-            // The full one from main.rs can be used if preferred.
+            .wrap(rate_limiter.clone())
+            // Counts requests like the middleware in main.rs
             .wrap_fn(move |req, srv| {
-                let stats = Arc::clone(&server_statistics);
-                let start = Utc::now();
-                let fut = srv.call(req);
-                async move {
-                    let res = fut.await?;
-                    let duration = (Utc::now() - start).num_milliseconds() as f64;
-                    stats.log_request(
-                        res.request().method().as_str().to_string(),
-                        res.request().path().to_string(),
-                        res.status().as_u16(),
-                        duration,
-                    ).await; // log_request is async
-                    Ok(res)
-                }
+                let stats = Arc::clone(&stats);
+                let method = req.method().to_string();
+                let start = std::time::Instant::now();
+                srv.call(req).map(move |res| {
+                    if let Ok(res) = &res {
+                        let duration = start.elapsed().as_secs_f64() * 1000.0;
+                        let status = res.status().as_u16();
+                        let route = route_of(res.request());
+                        let impersonated_by = res.request().extensions().get::<Credential>().and_then(Credential::impersonator);
+                        tokio::spawn(async move {
+                            stats.log_request(&method, &route, status, duration, impersonated_by).await;
+                        });
+                    }
+                    res
+                })
             })
+            .wrap(AssignRequestId)
             .app_data(web::Data::new(server_db_pool.clone()))
             .app_data(web::Data::new(Arc::clone(&server_statistics)))
-            .app_data(web::Data::new(server_app_config.clone()))
-            .configure(configure_app_routes) // Use the centralized route configurator
+            .app_data(web::Data::new(app_config.clone()))
+            .app_data(jwt_keys.clone())
+            .app_data(mailer_data.clone())
+            .app_data(mail_rate_limiter.clone())
+            .app_data(clock_data.clone())
+            .app_data(login_throttle.clone())
+            .app_data(password_hashers.clone())
+            .app_data(password_policy.clone())
+            .app_data(audit_logger.clone())
+            .app_data(oidc_providers.clone())
+            .app_data(session_cache.clone())
+            .app_data(web::Data::new(rate_limiter.clone()))
+            .app_data(scrape_allowlist.clone())
+            .app_data(user_metrics.clone())
+            .configure(configure_app_routes)
     })
-    .listen(listener) // Listen on the TcpListener
+    .workers(1)
+    .listen(listener)
     .expect("Failed to bind server to listener")
     .run();
 
     // Run the server in a separate Tokio task
     tokio::spawn(server);
 
-    TestApp { address, db_pool }
+    TestApp {
+        address,
+        db_pool,
+        statistics,
+        mailer,
+        clock,
+        client: reqwest::Client::new(),
+        _database: database,
+    }
+}
+
+// Turns off the rate limiter and makes the login backoff short, for tests that need many
+// logins in a row
+pub fn quick_logins(config: &mut AppConfig) {
+    config.rate_limit.requests_per_second = 10_000;
+    config.rate_limit.burst_size = 10_000;
+    config.lockout.backoff_base_ms = 1;
+    config.lockout.backoff_max_ms = 10;
+}
+
+impl TestApp {
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.address, path)
+    }
+
+    pub async fn post_json(&self, path: &str, body: &Value) -> reqwest::Response {
+        self.client.post(self.url(path)).json(body).send().await.expect("Failed to execute request.")
+    }
+
+    // Registers an account and opens the verification link emailed to it, so it can log in
+    pub async fn create_user(&self, name: &str) -> TestUser {
+        let user = TestUser {
+            id: 0,
+            username: name.to_string(),
+            email: format!("{}@example.com", name),
+            password: "Correct-Horse-Battery-42".to_string(),
+        };
+        let response = self
+            .post_json(
+                "/api/register",
+                &json!({"username": user.username, "email": user.email, "password": user.password}),
+            )
+            .await;
+        let status = response.status().as_u16();
+        assert_eq!(status, 200, "Registration of {} failed: {}", name, response.text().await.unwrap_or_default());
+        let body: Value = response.json().await.expect("Failed to parse registration response");
+        let id = body["user"]["id"].as_i64().expect("Registration response has no user id");
+
+        let email = self.mailer.last_to(&user.email).expect("No verification email was sent");
+        let link = email
+            .body
+            .split_whitespace()
+            .find(|word| word.contains("/api/verify-email?token="))
+            .expect("Verification email has no link");
+        let path = &link[link.find("/api/").unwrap()..];
+        let response = self.client.get(self.url(path)).send().await.expect("Failed to open verification link");
+        assert!(response.status().is_success(), "Verification of {} failed: {}", name, response.status());
+
+        TestUser { id, ..user }
+    }
+
+    // Replaces the roles and permissions of the account, e.g. `{"roles": ["admin"]}`
+    pub async fn set_permissions(&self, user: &TestUser, permissions: Value) {
+        let client = self.db_pool.get().await.expect("Failed to get a database connection");
+        client
+            .execute("UPDATE users SET permissions = $2 WHERE id = $1", &[&user.id, &permissions])
+            .await
+            .expect("Failed to set permissions");
+    }
+
+    pub async fn make_admin(&self, user: &TestUser) {
+        self.set_permissions(user, json!({"roles": ["admin"]})).await;
+    }
+
+    pub async fn login(&self, login: &str, password: &str) -> reqwest::Response {
+        self.post_json("/api/login", &json!({"login": login, "password": password})).await
+    }
+
+    // Logs in and returns the access token and the refresh token
+    pub async fn login_tokens(&self, user: &TestUser) -> (String, String) {
+        let response = self.login(&user.username, &user.password).await;
+        assert_eq!(response.status().as_u16(), 200, "Login of {} failed", user.username);
+        let body: Value = response.json().await.expect("Failed to parse login response");
+        (
+            body["token"].as_str().expect("Login response has no token").to_string(),
+            body["refresh_token"].as_str().expect("Login response has no refresh token").to_string(),
+        )
+    }
+
+    pub fn get(&self, path: &str, token: &str) -> reqwest::RequestBuilder {
+        self.client.get(self.url(path)).bearer_auth(token)
+    }
+
+    pub fn request(&self, method: reqwest::Method, path: &str, token: &str) -> reqwest::RequestBuilder {
+        self.client.request(method, self.url(path)).bearer_auth(token)
+    }
 }
@@ -1,22 +1,21 @@
 use crate::common::spawn_app; // Use the spawn_app helper from common.rs
 
-// Note: We need an HTTP client. `reqwest` is good for external-style testing.
-// Add `reqwest = { version = "0.11", features = ["json"] }` to [dev-dependencies] in Cargo.toml
-
 #[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
 async fn health_check_works() {
     // Arrange
     let app = spawn_app().await;
-    let client = reqwest::Client::new();
 
     // Act
-    let response = client
-        .get(&format!("{}/health", &app.address))
+    let response = app
+        .client
+        .get(app.url("/api/health"))
         .send()
         .await
         .expect("Failed to execute request.");
 
     // Assert
     assert!(response.status().is_success());
-    assert_eq!(response.content_length(), Some(0)); // Assuming health check returns empty body
+    let body: serde_json::Value = response.json().await.expect("Failed to parse health check response");
+    assert_eq!(body["status"], "OK");
 }
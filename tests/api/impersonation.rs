@@ -0,0 +1,84 @@
+use crate::common::{quick_logins, spawn_app_with, TestApp, TestUser};
+use reqwest::Method;
+use serde_json::{json, Value};
+
+async fn impersonate(app: &TestApp, admin_token: &str, user: &TestUser, reason: &str) -> reqwest::Response {
+    app.request(Method::POST, &format!("/api/admin/users/{}/impersonate", user.id), admin_token)
+        .json(&json!({"reason": reason}))
+        .send()
+        .await
+        .expect("Failed to execute impersonation request")
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn impersonation_token_cannot_manage_the_account() {
+    let app = spawn_app_with(quick_logins).await;
+    let admin = app.create_user("impersonatingadmin").await;
+    app.make_admin(&admin).await;
+    let user = app.create_user("impersonateduser").await;
+    let (admin_token, _) = app.login_tokens(&admin).await;
+
+    let response = impersonate(&app, &admin_token, &user, "Reproduce bug report #42").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["user_id"], user.id);
+    assert!(body.get("refresh_token").is_none(), "Impersonation must not be refreshable");
+    let token = body["token"].as_str().unwrap().to_string();
+
+    // Reading as the user works
+    let response = app.get(&format!("/api/user/{}", user.id), &token).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    // Account management doesn't
+    let forbidden = [
+        (Method::PATCH, "/api/user/me", json!({"email": "attacker@example.com"})),
+        (Method::PUT, "/api/user/me/password", json!({"current_password": user.password, "new_password": "Another-Secret-42"})),
+        (Method::DELETE, "/api/user/me", json!({})),
+        (Method::GET, "/api/user/me/sessions", json!({})),
+        (Method::DELETE, "/api/user/me/sessions", json!({})),
+        (Method::POST, "/api/user/me/api-keys", json!({"name": "backdoor"})),
+        (Method::POST, "/api/user/me/mfa/totp", json!({})),
+    ];
+    for (method, path, body) in forbidden {
+        let response = app.request(method.clone(), path, &token).json(&body).send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 403, "{} {} should be refused", method, path);
+    }
+
+    // The user's password still works, and the impersonation doesn't show among their sessions
+    let (user_token, _) = app.login_tokens(&user).await;
+    let response = app.get("/api/user/me/sessions", &user_token).send().await.unwrap();
+    let sessions: Vec<Value> = response.json().await.unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0]["current"], true);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn impersonation_is_limited_to_the_admins_own_permissions() {
+    let app = spawn_app_with(quick_logins).await;
+    let support = app.create_user("supportagent").await;
+    app.set_permissions(&support, json!({"permissions": ["users:impersonate"]})).await;
+    let admin = app.create_user("fulladmin").await;
+    app.make_admin(&admin).await;
+    let user = app.create_user("regularuser").await;
+    let (support_token, _) = app.login_tokens(&support).await;
+
+    // Acting as an admin would give the support agent more permissions than they have
+    let response = impersonate(&app, &support_token, &admin, "Check admin settings").await;
+    assert_eq!(response.status().as_u16(), 403);
+
+    // A reason is required, and nobody impersonates themselves
+    let response = impersonate(&app, &support_token, &user, "  ").await;
+    assert_eq!(response.status().as_u16(), 400);
+    let response = impersonate(&app, &support_token, &support, "Testing").await;
+    assert_eq!(response.status().as_u16(), 400);
+
+    // Impersonation tokens can't start another impersonation
+    let response = impersonate(&app, &support_token, &user, "Reproduce bug report #43").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    let token = body["token"].as_str().unwrap();
+    let response = impersonate(&app, token, &support, "Chain").await;
+    assert_eq!(response.status().as_u16(), 403);
+}
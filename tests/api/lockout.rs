@@ -0,0 +1,165 @@
+use crate::common::{quick_logins, spawn_app_with, TestApp};
+use serde_json::json;
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn account_locks_after_repeated_wrong_passwords() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("lockoutuser").await;
+
+    // `lockout.max_failed_attempts` is 5 in the test configuration
+    for attempt in 1..=5 {
+        let response = app.login(&user.username, "Wrong-Password-42").await;
+        assert_eq!(response.status().as_u16(), 401, "Attempt {} should fail", attempt);
+    }
+
+    // The right password doesn't get in while the account is locked, and the answer is the
+    // same as for a wrong password, so it doesn't tell the password was right
+    let response = app.login(&user.username, &user.password).await;
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(response.text().await.unwrap(), "Unauthorized");
+
+    // Other users can't see the lock, but admins can
+    let locked_until = |token: String| {
+        let request = app.get(&format!("/api/user/{}", user.id), &token);
+        async move {
+            let body: serde_json::Value = request.send().await.unwrap().json().await.unwrap();
+            body["locked_until"].clone()
+        }
+    };
+    let other = app.create_user("lockoutbystander").await;
+    let (other_token, _) = app.login_tokens(&other).await;
+    assert!(locked_until(other_token).await.is_null());
+    let admin = app.create_user("lockoutadmin").await;
+    app.make_admin(&admin).await;
+    let (token, _) = app.login_tokens(&admin).await;
+    assert!(locked_until(token.clone()).await.is_string());
+
+    // An admin can lift the lock early
+    let response = app
+        .request(reqwest::Method::POST, &format!("/api/admin/users/{}/unlock", user.id), &token)
+        .send()
+        .await
+        .expect("Failed to unlock user");
+    assert!(response.status().is_success(), "Unlock failed: {}", response.status());
+
+    let response = app.login(&user.username, &user.password).await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn unknown_accounts_are_answered_like_wrong_passwords() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("knownuser").await;
+
+    let wrong_password = app.login(&user.username, "Wrong-Password-42").await;
+    let unknown_account = app.login("nobody_here", "Wrong-Password-42").await;
+    assert_eq!(wrong_password.status(), unknown_account.status());
+    assert_eq!(wrong_password.text().await.unwrap(), unknown_account.text().await.unwrap());
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn client_ip_locks_after_failures_across_accounts() {
+    let app = spawn_app_with(|config| {
+        quick_logins(config);
+        config.lockout.ip_max_failed_attempts = 3;
+    })
+    .await;
+    let user = app.create_user("iplockoutuser").await;
+
+    // One failure each for several login names, none of which gets locked by itself
+    for login in ["first_guess", "second_guess", "third_guess"] {
+        let response = app.login(login, "Wrong-Password-42").await;
+        assert_eq!(response.status().as_u16(), 401);
+    }
+
+    // The IP is locked now, even for the right password of an unlocked account
+    let response = app.login(&user.username, &user.password).await;
+    assert_eq!(response.status().as_u16(), 429);
+
+    // Second factors are refused from the locked IP as well
+    let response = app.post_json("/api/login/mfa", &json!({"mfa_token": "invalid", "code": "000000"})).await;
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+// Opens the reset link emailed by `/api/password/forgot` and returns its token
+async fn password_reset_token(app: &TestApp, email: &str) -> String {
+    let response = app.post_json("/api/password/forgot", &json!({"email": email})).await;
+    assert_eq!(response.status().as_u16(), 202);
+    // The email is sent after the response
+    for _ in 0..50 {
+        if let Some(email) = app.mailer.last_to(email).filter(|email| email.subject == "Reset your password") {
+            if let Some(token) = email.body.split_whitespace().find_map(|word| word.split("token=").nth(1)) {
+                return token.to_string();
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("No password reset email was sent to {}", email);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn password_reset_lifts_the_lockout() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("resetlockoutuser").await;
+    for _ in 0..5 {
+        let response = app.login(&user.username, "Wrong-Password-42").await;
+        assert_eq!(response.status().as_u16(), 401);
+    }
+
+    // Made-up tokens are refused
+    let response = app
+        .post_json("/api/password/reset", &json!({"token": "not-a-token", "new_password": "Another-Secret-42"}))
+        .await;
+    assert_eq!(response.status().as_u16(), 400);
+
+    // The new password is checked against the account like at registration
+    let token = password_reset_token(&app, &user.email).await;
+    let response = app
+        .post_json("/api/password/reset", &json!({"token": token, "new_password": "Resetlockoutuser-42"}))
+        .await;
+    assert_eq!(response.status().as_u16(), 400);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body.to_string().contains("contains_personal_info"), "Unexpected error: {}", body);
+    // ... and a refused password leaves the token usable
+
+    let response = app
+        .post_json("/api/password/reset", &json!({"token": token, "new_password": "Another-Secret-42"}))
+        .await;
+    assert_eq!(response.status().as_u16(), 204);
+
+    let response = app.login(&user.username, "Another-Secret-42").await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn wrong_current_passwords_lock_the_account() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("changelockoutuser").await;
+    let (token, _) = app.login_tokens(&user).await;
+
+    // A stolen access token gives no more guesses than the login form
+    for _ in 0..5 {
+        let response = app
+            .request(reqwest::Method::PUT, "/api/user/me/password", &token)
+            .json(&json!({"current_password": "Wrong-Password-42", "new_password": "Another-Secret-42"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 401);
+    }
+
+    let response = app
+        .request(reqwest::Method::PUT, "/api/user/me/password", &token)
+        .json(&json!({"current_password": user.password, "new_password": "Another-Secret-42"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let response = app.login(&user.username, &user.password).await;
+    assert_eq!(response.status().as_u16(), 401);
+}
@@ -0,0 +1,15 @@
+// Request-level tests: each one starts the application on a random port in front of a freshly
+// migrated PostgreSQL database (see `test_utils::setup_test_db` for the connection settings)
+// and talks to it over HTTP. They are ignored by default; run them with
+// `cargo test --test api -- --ignored`.
+
+pub mod admin;
+pub mod common; // For shared test setup logic like spawn_app
+pub mod health_check;
+pub mod impersonation;
+pub mod lockout;
+pub mod mfa;
+pub mod rate_limit;
+pub mod sessions;
+pub mod statistics;
+pub mod user;
@@ -0,0 +1,120 @@
+use crate::common::{quick_logins, spawn_app_with, TestApp, TestUser};
+use chrono::Duration;
+use my_actix_api::auth::totp;
+use my_actix_api::clock::Clock;
+use serde_json::{json, Value};
+
+// Enables TOTP for the account and returns its secret
+async fn enable_totp(app: &TestApp, user: &TestUser) -> Vec<u8> {
+    let (token, _) = app.login_tokens(user).await;
+    let response = app
+        .request(reqwest::Method::POST, "/api/user/me/mfa/totp", &token)
+        .send()
+        .await
+        .expect("Failed to start TOTP setup");
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    let secret = totp::decode_base32(body["secret"].as_str().unwrap()).expect("Invalid TOTP secret");
+
+    let response = app
+        .request(reqwest::Method::POST, "/api/user/me/mfa/totp/confirm", &token)
+        .json(&json!({"code": current_code(app, &secret)}))
+        .send()
+        .await
+        .expect("Failed to confirm TOTP setup");
+    assert_eq!(response.status().as_u16(), 200);
+
+    // Each code is only accepted once, so the next login needs the next one
+    app.clock.advance(Duration::seconds(30));
+    secret
+}
+
+fn current_code(app: &TestApp, secret: &[u8]) -> String {
+    totp::code_at_step(secret, totp::time_step(app.clock.now()), 6)
+}
+
+// A code from long ago, which is never accepted
+fn wrong_code(app: &TestApp, secret: &[u8]) -> String {
+    totp::code_at_step(secret, totp::time_step(app.clock.now()) - 100, 6)
+}
+
+// Passes the password step and returns the `mfa_token` for the second one
+async fn start_mfa_login(app: &TestApp, user: &TestUser) -> String {
+    let response = app.login(&user.username, &user.password).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["mfa_required"], true);
+    body["mfa_token"].as_str().expect("No mfa_token").to_string()
+}
+
+async fn second_factor(app: &TestApp, mfa_token: &str, code: &str) -> reqwest::Response {
+    app.post_json("/api/login/mfa", &json!({"mfa_token": mfa_token, "code": code})).await
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn mfa_challenge_allows_only_a_few_attempts() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("mfauser").await;
+    let secret = enable_totp(&app, &user).await;
+
+    // `lockout.mfa_attempts_per_challenge` is 3 in the test configuration
+    let mfa_token = start_mfa_login(&app, &user).await;
+    for attempt in 1..=3 {
+        let response = second_factor(&app, &mfa_token, &wrong_code(&app, &secret)).await;
+        assert_eq!(response.status().as_u16(), 401, "Attempt {} should fail", attempt);
+    }
+
+    // The challenge is used up; not even the right code gets through with it
+    let response = second_factor(&app, &mfa_token, &current_code(&app, &secret)).await;
+    assert_eq!(response.status().as_u16(), 401);
+
+    // A new password step gives a new challenge
+    let mfa_token = start_mfa_login(&app, &user).await;
+    let response = second_factor(&app, &mfa_token, &current_code(&app, &secret)).await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert!(body["token"].as_str().is_some());
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn wrong_second_factors_lock_the_account() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("mfalockoutuser").await;
+    let secret = enable_totp(&app, &user).await;
+
+    // Wrong codes count as failed logins, so spreading them over new challenges doesn't help:
+    // `lockout.max_failed_attempts` is 5
+    let mut failures = 0;
+    while failures < 5 {
+        let mfa_token = start_mfa_login(&app, &user).await;
+        for _ in 0..2 {
+            let response = second_factor(&app, &mfa_token, &wrong_code(&app, &secret)).await;
+            assert_eq!(response.status().as_u16(), 401);
+            failures += 1;
+        }
+    }
+
+    // Locked: the password step answers like a wrong password
+    let response = app.login(&user.username, &user.password).await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn lockout_during_the_second_step_stops_it() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("mfalatelockuser").await;
+    let secret = enable_totp(&app, &user).await;
+
+    // A challenge taken before the account gets locked
+    let early_token = start_mfa_login(&app, &user).await;
+    for _ in 0..5 {
+        let response = app.login(&user.username, "Wrong-Password-42").await;
+        assert_eq!(response.status().as_u16(), 401);
+    }
+
+    let response = second_factor(&app, &early_token, &current_code(&app, &secret)).await;
+    assert_eq!(response.status().as_u16(), 401);
+}
@@ -1,44 +0,0 @@
-// This file will declare the modules for different API endpoint tests.
-
-// We'll need common setup, like spinning up the application
-// and database helper access. Let's define a helper module here or ensure
-// the one from `src/tests/helpers.rs` is accessible.
-// For integration tests, `crate::` refers to the main lib/binary crate, not this test crate.
-// So, we need to refer to the main crate by its name.
-// Let's assume the crate name is `my_rust_app` (I should verify this from Cargo.toml).
-
-pub mod common; // For shared test setup logic like spawn_app
-pub mod health_check;
-pub mod user;
-pub mod rate_limit;
-pub mod statistics;
-
-// Helper function to spawn the app for testing.
-// This might be better in a `common.rs` module.
-// For now, just a placeholder thought. It needs access to the AppConfig, main router, etc.
-// from the main crate.
-
-/*
-use my_rust_app::AppConfig; // Replace my_rust_app with actual crate name
-use my_rust_app::setup_routes; // Assuming router setup is in a function
-use actix_web::{App, web, test::TestServer};
-use std::sync::Once;
-
-static INIT: Once = Once::new();
-
-pub fn initialize() {
-    INIT.call_once(|| {
-        // Any global setup for tests, like initializing a logger
-        // my_rust_app::logger::init_logger().expect("Failed to init logger for tests");
-        std::env::set_var("RUST_LOG", "actix_web=debug,my_rust_app=debug"); // Adjust log levels
-        std::env::set_var("RUN_ENV", "test"); // Ensure test config is loaded
-    });
-}
-
-async fn spawn_app() -> ??? { // Return type would be something like TestServer or address
-    initialize();
-    // ... setup test db ...
-    // ... load config ...
-    // ... start server ...
-}
-*/
@@ -1,11 +1,12 @@
-use crate::common::spawn_app;
+use crate::common::{spawn_app, spawn_app_with};
+use serde_json::{json, Value};
 use std::time::Duration;
 use tokio::time::sleep;
 
 #[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
 async fn rate_limit_blocks_excessive_requests() {
     let app = spawn_app().await; // spawn_app configures RateLimiter based on AppConfig
-    let client = reqwest::Client::new();
 
     // Need to know the rate limit settings from AppConfig.
     // Let's assume example.config.toml has:
@@ -27,7 +28,7 @@ async fn rate_limit_blocks_excessive_requests() {
     let configured_burst_size = 3;
     let requests_to_send = configured_burst_size + 2; // Send a few more than burst size
 
-    let target_url = format!("{}/health", &app.address); // Use a simple, fast endpoint
+    let target_url = app.url("/api/health"); // Use a simple, fast endpoint
     let mut received_429 = false;
 
     println!("Sending {} requests to {} with configured burst_size = {}", requests_to_send, target_url, configured_burst_size);
@@ -74,3 +75,51 @@ async fn rate_limit_blocks_excessive_requests() {
             "Request after exhausting refilled burst should be rate limited again.");
     }
 }
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn api_key_requests_are_limited_per_key() {
+    // A burst of 6 and one more request per second; creating the user and the key takes 4
+    let app = spawn_app_with(|config| {
+        config.rate_limit.requests_per_second = 1;
+        config.rate_limit.burst_size = 6;
+    })
+    .await;
+    let user = app.create_user("ratelimitedkeyuser").await;
+    let (token, _) = app.login_tokens(&user).await;
+    let response = app
+        .request(reqwest::Method::POST, "/api/user/me/api-keys", &token)
+        .json(&json!({"name": "busy client"}))
+        .send()
+        .await
+        .expect("Failed to create API key");
+    assert_eq!(response.status().as_u16(), 201);
+    let body: Value = response.json().await.unwrap();
+    let key = body["key"].as_str().expect("No API key returned").to_string();
+
+    let with_key = |key: &str| {
+        app.client
+            .get(app.url(&format!("/api/user/{}", user.id)))
+            .header("Authorization", format!("ApiKey {}", key))
+            .send()
+    };
+
+    // The key has a full burst of its own, however much the IP has used
+    for i in 0..6 {
+        let response = with_key(&key).await.expect("Request failed");
+        assert_eq!(response.status().as_u16(), 200, "Request {} with the key should pass", i + 1);
+    }
+    let response = with_key(&key).await.expect("Request failed");
+    assert_eq!(response.status().as_u16(), 429);
+
+    // ... and using it up leaves the client IP its own requests
+    let response = app.client.get(app.url("/api/health")).send().await.expect("Request failed");
+    assert!(response.status().is_success(), "Anonymous request should pass: {}", response.status());
+
+    // Made-up keys take from the IP's bucket, not from a bucket of their own
+    let mut statuses = Vec::new();
+    for _ in 0..6 {
+        statuses.push(with_key("ak_abcd2345_notasecret").await.expect("Request failed").status().as_u16());
+    }
+    assert_eq!(statuses.last(), Some(&429), "Unknown keys should run out of requests: {:?}", statuses);
+}
@@ -0,0 +1,109 @@
+use crate::common::{quick_logins, spawn_app_with, TestApp, TestUser};
+use reqwest::Method;
+use serde_json::{json, Value};
+
+async fn status_with(app: &TestApp, token: &str, user: &TestUser) -> u16 {
+    app.get(&format!("/api/user/{}", user.id), token).send().await.unwrap().status().as_u16()
+}
+
+async fn refresh(app: &TestApp, refresh_token: &str) -> u16 {
+    app.post_json("/api/token/refresh", &json!({"refresh_token": refresh_token})).await.status().as_u16()
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn revoked_session_stops_its_tokens() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("sessionuser").await;
+    let (laptop_token, _) = app.login_tokens(&user).await;
+    let (phone_token, phone_refresh) = app.login_tokens(&user).await;
+
+    let response = app.get("/api/user/me/sessions", &laptop_token).send().await.unwrap();
+    let sessions: Vec<Value> = response.json().await.unwrap();
+    assert_eq!(sessions.len(), 2);
+    let phone_session = sessions.iter().find(|session| session["current"] == false).expect("No other session");
+
+    let response = app
+        .request(Method::DELETE, &format!("/api/user/me/sessions/{}", phone_session["id"].as_str().unwrap()), &laptop_token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 204);
+
+    // The revoked session's access and refresh tokens stop working at once; the other stays
+    assert_eq!(status_with(&app, &phone_token, &user).await, 401);
+    assert_eq!(refresh(&app, &phone_refresh).await, 401);
+    assert_eq!(status_with(&app, &laptop_token, &user).await, 200);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn logging_out_everywhere_revokes_every_session() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("logouteverywhereuser").await;
+    let (first_token, first_refresh) = app.login_tokens(&user).await;
+    let (second_token, _) = app.login_tokens(&user).await;
+
+    let response = app.request(Method::DELETE, "/api/user/me/sessions", &second_token).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 204);
+
+    assert_eq!(status_with(&app, &first_token, &user).await, 401);
+    assert_eq!(status_with(&app, &second_token, &user).await, 401);
+    assert_eq!(refresh(&app, &first_refresh).await, 401);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn logout_revokes_the_session() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("logoutuser").await;
+    let (token, refresh_token) = app.login_tokens(&user).await;
+
+    let response = app.post_json("/api/logout", &json!({"refresh_token": refresh_token})).await;
+    assert_eq!(response.status().as_u16(), 204);
+
+    assert_eq!(status_with(&app, &token, &user).await, 401);
+    assert_eq!(refresh(&app, &refresh_token).await, 401);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn password_change_revokes_other_sessions() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("passwordchangeuser").await;
+    let (other_token, other_refresh) = app.login_tokens(&user).await;
+    let (token, _) = app.login_tokens(&user).await;
+
+    let response = app
+        .request(Method::PUT, "/api/user/me/password", &token)
+        .json(&json!({"current_password": user.password, "new_password": "Another-Secret-42"}))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success(), "Password change failed: {}", response.status());
+
+    assert_eq!(status_with(&app, &other_token, &user).await, 401);
+    assert_eq!(refresh(&app, &other_refresh).await, 401);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn suspension_revokes_every_session() {
+    let app = spawn_app_with(quick_logins).await;
+    let admin = app.create_user("suspendingadmin").await;
+    app.make_admin(&admin).await;
+    let user = app.create_user("suspendeduser").await;
+    let (admin_token, _) = app.login_tokens(&admin).await;
+    let (token, refresh_token) = app.login_tokens(&user).await;
+
+    let response = app
+        .request(Method::PUT, &format!("/api/admin/users/{}/status", user.id), &admin_token)
+        .json(&json!({"status": "suspended", "reason": "Abuse report"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(status_with(&app, &token, &user).await, 401);
+    assert_ne!(refresh(&app, &refresh_token).await, 200);
+}
@@ -1,155 +1,37 @@
-use crate::common::spawn_app;
-use my_actix_api::statistics::StatisticsData; // For deserializing the response
-use my_actix_api::routes::user::RegisterUserPayload; // To generate some activity
+use crate::common::{quick_logins, spawn_app_with};
+use serde_json::Value;
 
 #[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
 async fn get_statistics_returns_data() {
-    let app = spawn_app().await;
-    let client = reqwest::Client::new();
+    let app = spawn_app_with(quick_logins).await;
 
-    // Optional: Perform some actions to generate statistics
-    // For example, register a user or hit some endpoints a few times.
-    // This makes the statistics more interesting than all zeros.
-    let register_payload = RegisterUserPayload {
-        email: format!("stats_user_{}@example.com", chrono::Utc::now().timestamp_micros()),
-        username: format!("stats_user_{}", chrono::Utc::now().timestamp_micros()),
-        password: "PasswordForStats!".to_string(),
-    };
-    let reg_response = client
-        .post(&format!("{}/users/register", &app.address))
-        .json(&register_payload)
-        .send()
-        .await
-        .expect("User registration for stats failed.");
-    assert!(reg_response.status().is_success(), "User registration call for stats failed with status: {}", reg_response.status());
-
-    // Hit health check a couple of times
+    // Registering the admin and hitting the health check gives the statistics something to count
+    let admin = app.create_user("statsadmin").await;
+    app.make_admin(&admin).await;
+    let (token, _) = app.login_tokens(&admin).await;
     for _ in 0..3 {
-        client.get(&format!("{}/health", &app.address)).send().await.expect("Health check call failed.");
+        app.client.get(app.url("/api/health")).send().await.expect("Health check call failed.");
     }
 
-    // Allow some time for logs to be processed by the async log_request if it involves spawning.
-    // The current log_request in spawn_app is async.
+    // `/api/statistics` reports the last save; the background save only runs every five
+    // minutes, so save right away. Requests are counted after their response is sent.
     tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    app.statistics.save(&app.db_pool).await.expect("Failed to save statistics");
 
-
-    // Act: Get statistics
-    let response = client
-        .get(&format!("{}/statistics", &app.address))
-        .send()
-        .await
-        .expect("Failed to execute request to /statistics.");
-
-    // Assert status
+    let response = app.get("/api/statistics", &token).send().await.expect("Failed to execute request to /statistics.");
     assert!(response.status().is_success(), "Expected success status from /statistics. Got: {}", response.status());
 
-    // Assert content type (optional, but good practice)
-    // assert_eq!(response.headers().get(reqwest::header::CONTENT_TYPE).unwrap(), "application/json");
-
-    // Deserialize and check response structure
-    let stats_data: StatisticsData = response.json().await.expect("Failed to parse statistics response.");
-
-    // Basic checks on the data structure.
-    // Values might be zero or non-zero depending on activity and timing.
-    // The key is that the structure is correct and deserializable.
-    assert!(stats_data.total_requests >= 1, "Expected some total_requests after activity."); // registration + health checks
-
-    // Check if specific counters from our activity are present
-    // Note: The Statistics struct in lib.rs gets data from DB for `get_statistics` handler.
-    // The in-memory stats (like from `log_request`) are saved to DB periodically.
-    // So, what we see here depends on whether a save has occurred OR if get_statistics also consults in-memory.
-    // The current `Statistics::get_statistics` in `src/statistics.rs` *only* fetches from DB.
-    // The `Statistics::log_request` updates in-memory `data.total_requests`.
-    // The `Statistics::save` method saves the in-memory `data` (including total_requests) to the DB.
-    // Background tasks in `spawn_app` (and `main.rs`) save stats every 5 mins.
-    // This test runs much faster than 5 mins.
-    // THEREFORE, the `/statistics` endpoint which calls `get_latest_statistics` from DB
-    // might not reflect the very latest `log_request` calls immediately unless a save has happened.
-    // The `setup_test_db` clears and re-migrates tables.
-    // The `init_database_schema` in `lib.rs` also calls `create_statistics_tables`.
-
-    // Let's reconsider. The statistics endpoint `routes::statistics::get_statistics_handler`
-    // calls `stats.get_statistics(client)`.
-    // `Statistics::get_statistics` calls `db::get_latest_statistics`.
-    // This means it reads the *last saved* statistics from the DB.
-    // The `log_request` calls update an in-memory `Statistics.data` which is then periodically saved.
-    // For this test to be robust about specific counts like `register_requests`, we would need to:
-    // 1. Ensure a statistics save cycle completes OR
-    // 2. Modify the endpoint to return a mix of DB-persisted and current in-memory stats OR
-    // 3. Add a test-only endpoint to trigger a save.
-
-    // For now, let's check for a plausible `total_requests` based on DB state which should be at least 0.
-    // If `setup_test_db` runs migrations, `api_statistics` is initially empty.
-    // The `Statistics::save` is called by a background task.
-    // If no save has happened, `get_latest_statistics` might return an error or no data.
-    // `db::get_latest_statistics` does `ORDER BY timestamp DESC LIMIT 1`. If table is empty, it errors.
-    // This needs to be handled in the route or `get_latest_statistics`.
-    // Let's assume `get_latest_statistics` returns a default/empty `StatisticsData` if DB is empty or has an error.
-    // The current `get_latest_statistics` will error if the table is empty (from query_one).
-    // The handler `get_statistics_handler` should map this error.
-
-    // Given this, the `total_requests >= 1` assertion might be too strong if no save has occurred.
-    // A safer bet for now is to just check if the structure is valid and some default values.
-    // Or, we can ensure at least one save cycle. The background task saves every 5 mins by default in main.
-    // In `tests/api/common.rs` `start_background_tasks` uses 5 mins.
-    // This is too long for a test.
-
-    // Simplification for now: test that the endpoint returns *something* parsable as StatisticsData.
-    // Detailed count verification requires addressing the save cycle timing.
-    assert!(stats_data.timestamp <= Utc::now()); // Timestamp should be valid.
-    // If the DB was empty, total_requests would be from a default StatisticsData if the error is handled,
-    // or the request would fail.
-    // The current `get_latest_statistics` will error if no rows.
-    // The route `get_statistics_handler` currently:
-    // pub async fn get_statistics_handler(pool: web::Data<Pool>, stats: web::Data<Arc<Statistics>>) -> Result<HttpResponse, AppError> {
-    //    let client = pool.get().await.map_err(|e| AppError::DatabaseError(e.to_string()))?;
-    //    let statistics_data = stats.get_statistics(&client).await.map_err(|e| AppError::InternalServerError)?;
-    //    Ok(HttpResponse::Ok().json(statistics_data))
-    // }
-    // If `stats.get_statistics` (which calls `db::get_latest_statistics`) errors because table is empty,
-    // it will become `AppError::InternalServerError`. So the request would return 500.
-    // This means the `assert!(response.status().is_success())` would fail if the table is empty.
-
-    // Conclusion: For `/statistics` to return 200 OK, there must be at least one row in `api_statistics`.
-    // The `spawn_app` calls `start_background_tasks`. One of these tasks is `stats_clone.save(&pool_for_stats)`.
-    // This save happens every 5 minutes.
-    // However, `Statistics::new()` initializes `StatisticsData` with current values (mostly zeros).
-    // The first save will save these initial (mostly zero) values.
-    // So, a row *should* exist if the save task runs at least once.
-    // The interval is 5 min. The test is too fast.
-
-    // Let's force a save for testing purposes or make interval very short for tests.
-    // The easiest for now: after activity, manually call save on the stats object via app.
-    // But `app` doesn't expose `Statistics` directly.
-    // Alternative: The `StatisticsData` struct has `last_saved: Option<DateTime<Utc>>`.
-    // If the table is empty, `get_latest_statistics` would error.
-    // If a save occurred, `total_requests` would be what was saved.
-
-    // For the test to pass reliably for now, we need `api_statistics` table to have at least one entry
-    // *before* the `/statistics` endpoint is called.
-    // The `Statistics::new()` creates data. The background task `stats_clone.save()` *will* save it.
-    // The interval is 5m. Let's shorten this interval in `start_background_tasks` when in test mode.
-    // This is a change to `my_actix_api::start_background_tasks` in `lib.rs`.
-    // Or, the `Statistics::save` should be called directly in the test if possible.
-
-    // Given the current setup, the test might be flaky depending on when the first save occurs.
-    // A robust test would ensure data exists.
-    // For now, the assertion `total_requests >= 1` relies on the logging middleware having updated
-    // the in-memory stats, and that initial save from `start_background_tasks` having completed
-    // with these non-zero values. This is unlikely given the 5 min interval.
-    // The first save will likely save the initial zeroed-out statistics.
-    // So, `total_requests` might be 0 from the DB.
-
-    // Let's assume the first save from `start_background_tasks` saves the initial state.
-    // `StatisticsData` is initialized with `total_requests: 0`.
-    // So, reading from DB will give `total_requests: 0` initially.
-    // The activity (`register`, `health` calls) updates in-memory stats via `log_request`.
-    // These in-memory stats are NOT what `/statistics` endpoint currently returns.
-    // This means `assert!(stats_data.total_requests >= 1)` will likely fail.
-    // It should be `assert_eq!(stats_data.total_requests, 0)` if only the initial save happened.
-    // Or, if we wait long enough for a second save (after activity), then it would be non-zero.
-
-    // Test will be more reliable if we check for initial state from DB.
-    assert_eq!(stats_data.total_requests, 0, "Expected total_requests to be 0 from initial DB save.");
-    assert_eq!(stats_data.register_requests, 0); // And other specific counters.
+    let stats: Value = response.json().await.expect("Failed to parse statistics response.");
+    // Registration, verification, login and the health checks
+    assert!(stats["total_requests"].as_i64().unwrap() >= 6, "Unexpected total: {}", stats["total_requests"]);
+    assert_eq!(stats["traffic_distribution"]["/api/health"], 3);
+    assert_eq!(stats["metrics"]["register_requests"]["value"], 1.0);
+    assert_eq!(stats["metrics"]["register_success"]["value"], 1.0);
+
+    // Without the permission the statistics stay hidden
+    let user = app.create_user("statsuser").await;
+    let (token, _) = app.login_tokens(&user).await;
+    let response = app.get("/api/statistics", &token).send().await.expect("Failed to execute request to /statistics.");
+    assert_eq!(response.status().as_u16(), 403);
 }
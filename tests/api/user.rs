@@ -1,138 +1,133 @@
-use crate::common::spawn_app;
-use my_actix_api::auth::User; // Assuming User struct is needed for response deserialization
-use my_actix_api::routes::user::{RegisterUserPayload, LoginUserPayload}; // Payloads for requests
-use serde_json::json;
+use crate::common::{quick_logins, spawn_app, spawn_app_with};
+use reqwest::Method;
+use serde_json::{json, Value};
 
 #[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
 async fn register_user_success() {
     let app = spawn_app().await;
-    let client = reqwest::Client::new();
-
-    let unique_email = format!("testuser_{}@example.com", chrono::Utc::now().timestamp_micros());
-    let payload = RegisterUserPayload {
-        email: unique_email.clone(),
-        username: format!("testuser_{}", chrono::Utc::now().timestamp_micros()),
-        password: "Password123!".to_string(),
-    };
-
-    let response = client
-        .post(&format!("{}/users/register", &app.address))
-        .json(&payload)
-        .send()
-        .await
-        .expect("Failed to execute request.");
 
-    assert_eq!(response.status().as_u16(), 201, "Expected 201 Created status");
-
-    let user_response: User = response.json().await.expect("Failed to parse user response");
-    assert_eq!(user_response.email, unique_email);
-    assert_eq!(user_response.username, payload.username);
-    // Further checks on the user object can be added if needed
+    let payload = json!({
+        "email": "testuser@example.com",
+        "username": "testuser",
+        "password": "Correct-Horse-42",
+    });
+    let response = app.post_json("/api/register", &payload).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    let body: Value = response.json().await.expect("Failed to parse user response");
+    assert_eq!(body["user"]["email"], "testuser@example.com");
+    assert_eq!(body["user"]["username"], "testuser");
+    assert_eq!(body["user"]["status"], "pending_verification");
+    assert!(app.mailer.last_to("testuser@example.com").is_some(), "Expected a verification email");
 }
 
 #[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
 async fn register_user_duplicate_email() {
     let app = spawn_app().await;
-    let client = reqwest::Client::new();
-
-    let unique_email = format!("duplicate_{}@example.com", chrono::Utc::now().timestamp_micros());
-    let payload1 = RegisterUserPayload {
-        email: unique_email.clone(),
-        username: "user1_dup_email".to_string(),
-        password: "Password123!".to_string(),
-    };
-    let payload2 = RegisterUserPayload {
-        email: unique_email.clone(), // Same email
-        username: "user2_dup_email".to_string(),
-        password: "Password456!".to_string(),
-    };
+
+    let first = json!({"email": "duplicate@example.com", "username": "firstdupemail", "password": "Correct-Horse-42"});
+    let second = json!({"email": "duplicate@example.com", "username": "seconddupemail", "password": "Battery-Staple-42"});
 
     // First registration should succeed
-    let response1 = client
-        .post(&format!("{}/users/register", &app.address))
-        .json(&payload1)
-        .send()
-        .await
-        .expect("Failed to execute request for first user.");
-    assert_eq!(response1.status().as_u16(), 201, "First registration should succeed");
+    let response = app.post_json("/api/register", &first).await;
+    assert_eq!(response.status().as_u16(), 200, "First registration should succeed");
 
     // Second registration with the same email should fail
-    let response2 = client
-        .post(&format!("{}/users/register", &app.address))
-        .json(&payload2)
-        .send()
-        .await
-        .expect("Failed to execute request for second user.");
-
-    assert_eq!(response2.status().as_u16(), 400, "Expected 400 Bad Request for duplicate email");
-    let error_response: serde_json::Value = response2.json().await.expect("Failed to parse error response");
-    assert!(error_response["message"].as_str().unwrap().contains("User with this email or username already exists"));
+    let response = app.post_json("/api/register", &second).await;
+    assert_eq!(response.status().as_u16(), 400, "Expected 400 Bad Request for duplicate email");
+    let message = response.text().await.expect("Failed to read error response");
+    assert!(message.contains("User with this email or username already exists"), "Unexpected error: {}", message);
 }
 
-
 #[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
 async fn register_user_invalid_payload() {
     let app = spawn_app().await;
-    let client = reqwest::Client::new();
-
-    // Example: missing email
-    let payload = json!({
-        "username": "invalid_payload_user",
-        "password": "Password123!"
-    });
-
-    let response = client
-        .post(&format!("{}/users/register", &app.address))
-        .json(&payload)
-        .send()
-        .await
-        .expect("Failed to execute request.");
 
+    // Missing email
+    let response = app
+        .post_json("/api/register", &json!({"username": "invalidpayloaduser", "password": "Correct-Horse-42"}))
+        .await;
     assert_eq!(response.status().as_u16(), 400, "Expected 400 Bad Request for invalid payload");
-    // Further checks on the error message can be added if needed
-    let error_body: serde_json::Value = response.json().await.expect("Failed to parse error response");
-    assert!(error_body["message"].to_string().to_lowercase().contains("deserialize") || // Serde error
-            error_body["message"].to_string().to_lowercase().contains("invalid") || // Validation error
-            error_body["message"].to_string().to_lowercase().contains("missing field"));
+    let message = response.text().await.expect("Failed to read error response");
+    assert!(message.contains("missing field"), "Unexpected error: {}", message);
 }
 
-// TODO: Add tests for login (success, failure - wrong password, user not found)
-// TODO: Add tests for get user by ID (success, not found, authorization if any)
-// These would require a user to be registered first, and potentially a token from login.
-
-/*
 #[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
 async fn login_user_success() {
-    let app = spawn_app().await;
-    let client = reqwest::Client::new();
-
-    let unique_email = format!("login_test_{}@example.com", chrono::Utc::now().timestamp_micros());
-    let password = "LoginPassword123!";
-    let register_payload = RegisterUserPayload {
-        email: unique_email.clone(),
-        username: format!("login_user_{}", chrono::Utc::now().timestamp_micros()),
-        password: password.to_string(),
-    };
-
-    // Register user first
-    let reg_response = client
-        .post(&format!("{}/users/register", &app.address))
-        .json(&register_payload)
-        .send().await.expect("Register failed");
-    assert_eq!(reg_response.status().as_u16(), 201);
-
-    let login_payload = LoginUserPayload {
-        email: unique_email,
-        password: password.to_string(),
-    };
-
-    let login_response = client
-        .post(&format!("{}/users/login", &app.address))
-        .json(&login_payload)
-        .send().await.expect("Login failed");
-
-    assert_eq!(login_response.status().as_u16(), 200, "Login should succeed");
-    let response_body: serde_json::Value = login_response.json().await.expect("Failed to parse login response");
-    assert!(response_body["token"].as_str().is_some(), "Token should be present in login response");
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("loginuser").await;
+
+    // Either the username or the email address works
+    for login in [&user.username, &user.email] {
+        let response = app.login(login, &user.password).await;
+        assert_eq!(response.status().as_u16(), 200, "Login with {} should succeed", login);
+        let body: Value = response.json().await.expect("Failed to parse login response");
+        assert!(body["token"].as_str().is_some(), "Token should be present in login response");
+    }
+
+    let response = app.login(&user.username, "Wrong-Password-42").await;
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL test database"]
+async fn deleting_the_account_drops_pending_email_changes() {
+    let app = spawn_app_with(quick_logins).await;
+    let user = app.create_user("deleteduser").await;
+    let (token, _) = app.login_tokens(&user).await;
+
+    let response = app
+        .request(Method::PATCH, "/api/user/me", &token)
+        .json(&json!({"email": "newaddress@example.com"}))
+        .send()
+        .await
+        .expect("Failed to change email");
+    assert!(response.status().is_success(), "Email change failed: {}", response.status());
+    let email = app.mailer.last_to("newaddress@example.com").expect("No confirmation email was sent");
+    let link = email
+        .body
+        .split_whitespace()
+        .find(|word| word.contains("/api/"))
+        .expect("Confirmation email has no link");
+
+    let response = app.request(Method::DELETE, "/api/user/me", &token).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 204);
+
+    // The link sent before the deletion no longer does anything
+    let response = app.client.get(app.url(&link[link.find("/api/").unwrap()..])).send().await.unwrap();
+    assert!(response.status().is_client_error(), "Link should be refused: {}", response.status());
+
+    let client = app.db_pool.get().await.unwrap();
+    let row = client
+        .query_one(
+            "SELECT pending_email, (SELECT COUNT(*) FROM one_time_tokens WHERE user_id = $1) AS tokens
+             FROM users WHERE id = $1",
+            &[&user.id],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, Option<String>>("pending_email"), None);
+    assert_eq!(row.get::<_, i64>("tokens"), 0);
+
+    // The deletion is audited with the user as actor and target; entries are written in the background
+    let mut audited = 0;
+    for _ in 0..50 {
+        audited = client
+            .query_one(
+                "SELECT COUNT(*) FROM audit_log WHERE action = 'account_delete' AND actor_id = $1 AND target_id = $1",
+                &[&user.id],
+            )
+            .await
+            .unwrap()
+            .get::<_, i64>(0);
+        if audited > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(audited, 1);
 }
-*/